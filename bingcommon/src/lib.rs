@@ -0,0 +1,9 @@
+//! Pieces shared by bingdaily and bingpapr, kept apart from bingdaily's library so bingpapr
+//! doesn't pull in its HTTP client and image decoders.
+
+pub mod state;
+
+/// Well-known D-Bus name bingdaily's service is reachable at unless configured otherwise.
+pub const DEFAULT_DBUS_NAME: &str = "net.boothwhack.BingDaily1";
+/// Object path bingdaily's service is exported at unless configured otherwise.
+pub const DEFAULT_DBUS_PATH: &str = "/net/boothwhack/BingDaily1";
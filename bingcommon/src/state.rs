@@ -0,0 +1,204 @@
+//! Persisted state shared by features that need to remember something across restarts. State is
+//! stored as versioned JSON and written atomically, so a crash never leaves a corrupt file behind.
+
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use log::{debug, warn};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::fs::{create_dir_all, File};
+use tokio::io::AsyncWriteExt;
+
+/// State which can be persisted with [persist_state] and loaded with [load_state].
+pub trait VersionedState: Serialize + DeserializeOwned + Default {
+    /// Current schema version, to be incremented on every incompatible change.
+    const VERSION: u32;
+
+    /// Migrates state persisted with an older schema version. Returning `None` discards it.
+    fn migrate(_version: u32, _state: serde_json::Value) -> Option<Self> {
+        None
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum StateError {
+    #[error("Failed to access state file {0:?}: {1}")]
+    IoError(PathBuf, #[source] io::Error),
+    #[error("Failed to serialize state: {0}")]
+    SerializeError(#[from] serde_json::Error),
+}
+
+#[derive(Serialize, Deserialize)]
+struct Envelope<T> {
+    version: u32,
+    state: T,
+}
+
+/// Uniquely named hidden file next to `path` to write its new contents into, so concurrent
+/// writers never share one.
+pub fn temp_path(path: &Path) -> PathBuf {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+
+    let mut name = OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(format!(".{}-{}.part", std::process::id(), count));
+    path.with_file_name(name)
+}
+
+/// Renames the temporary file over `path`. Temporary files are created next to their target so
+/// this stays on one filesystem, but should the two still end up on different filesystems, e.g.
+/// through a bind mount, the file is copied and synced instead.
+pub async fn move_into_place(temp_path: &Path, path: &Path) -> io::Result<()> {
+    match tokio::fs::rename(temp_path, path).await {
+        Err(err) if err.kind() == io::ErrorKind::CrossesDevices => {
+            debug!("Cannot rename across filesystems, copying {} instead", temp_path.display());
+            tokio::fs::copy(temp_path, path).await?;
+            File::open(path).await?.sync_all().await?;
+            tokio::fs::remove_file(temp_path).await
+        }
+        result => result,
+    }
+}
+
+/// Writes `contents` to a temporary file next to `path` and moves it over `path` once synced, so
+/// a crash leaves either the previous or the new contents behind, never a partial file. Missing
+/// parent directories are created.
+pub async fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent).await?;
+    }
+    let temp_path = temp_path(path);
+    let result = async {
+        let mut file = File::create(&temp_path).await?;
+        file.write_all(contents).await?;
+        file.sync_all().await?;
+        move_into_place(&temp_path, path).await
+    }.await;
+    if result.is_err() {
+        let _ = tokio::fs::remove_file(&temp_path).await;
+    }
+    result
+}
+
+/// Writes the state to `path` with [write_atomically].
+pub async fn persist_state<T: VersionedState>(path: &Path, state: &T) -> Result<(), StateError> {
+    let envelope = Envelope { version: T::VERSION, state };
+    let json = serde_json::to_vec_pretty(&envelope)?;
+    write_atomically(path, &json).await
+        .map_err(|err| StateError::IoError(path.to_owned(), err))
+}
+
+/// Loads state from `path`, migrating it from an older schema version if necessary. Missing,
+/// corrupt or unmigratable state results in the default state.
+pub async fn load_state<T: VersionedState>(path: &Path) -> Result<T, StateError> {
+    let json = match tokio::fs::read(path).await {
+        Ok(json) => json,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(T::default()),
+        Err(err) => return Err(StateError::IoError(path.to_owned(), err)),
+    };
+
+    let envelope = match serde_json::from_slice::<Envelope<serde_json::Value>>(&json) {
+        Ok(envelope) => envelope,
+        Err(err) => {
+            warn!("Discarding corrupt state {}: {}", path.display(), err);
+            return Ok(T::default());
+        }
+    };
+
+    let state = if envelope.version == T::VERSION {
+        serde_json::from_value(envelope.state).ok()
+    } else {
+        T::migrate(envelope.version, envelope.state)
+    };
+    Ok(state.unwrap_or_else(|| {
+        warn!("Discarding state {} with unsupported version {}", path.display(), envelope.version);
+        T::default()
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Default, Deserialize, PartialEq, Serialize)]
+    struct Counter {
+        count: u32,
+    }
+
+    impl VersionedState for Counter {
+        const VERSION: u32 = 2;
+
+        fn migrate(version: u32, state: serde_json::Value) -> Option<Self> {
+            match version {
+                1 => Some(Counter { count: state.as_u64()? as u32 }),
+                _ => None,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn round_trips() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("nested").join("counter.json");
+        persist_state(&path, &Counter { count: 3 }).await.unwrap();
+        assert_eq!(load_state::<Counter>(&path).await.unwrap(), Counter { count: 3 });
+    }
+
+    #[tokio::test]
+    async fn crash_before_rename_keeps_previous_state() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("counter.json");
+        persist_state(&path, &Counter { count: 1 }).await.unwrap();
+
+        // a crash after writing the temporary file but before renaming it into place
+        tokio::fs::write(temp_path(&path), b"{\"version\":2,\"sta").await.unwrap();
+
+        assert_eq!(load_state::<Counter>(&path).await.unwrap(), Counter { count: 1 });
+        persist_state(&path, &Counter { count: 2 }).await.unwrap();
+        assert_eq!(load_state::<Counter>(&path).await.unwrap(), Counter { count: 2 });
+    }
+
+    #[tokio::test]
+    async fn migrates_older_versions() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("counter.json");
+        tokio::fs::write(&path, b"{\"version\":1,\"state\":7}").await.unwrap();
+        assert_eq!(load_state::<Counter>(&path).await.unwrap(), Counter { count: 7 });
+    }
+
+    #[tokio::test]
+    async fn discards_corrupt_and_unknown_state() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("counter.json");
+        tokio::fs::write(&path, b"not json").await.unwrap();
+        assert_eq!(load_state::<Counter>(&path).await.unwrap(), Counter::default());
+        tokio::fs::write(&path, b"{\"version\":9,\"state\":{}}").await.unwrap();
+        assert_eq!(load_state::<Counter>(&path).await.unwrap(), Counter::default());
+    }
+
+    #[tokio::test]
+    async fn missing_state_is_default() {
+        let directory = tempfile::tempdir().unwrap();
+        assert_eq!(load_state::<Counter>(&directory.path().join("counter.json")).await.unwrap(), Counter::default());
+    }
+
+    #[tokio::test]
+    async fn write_atomically_replaces_contents() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("config.toml");
+        write_atomically(&path, b"market = \"en-US\"").await.unwrap();
+        write_atomically(&path, b"market = \"ja-JP\"").await.unwrap();
+        assert_eq!(tokio::fs::read(&path).await.unwrap(), b"market = \"ja-JP\"");
+        // no temporary files are left behind
+        let mut entries = tokio::fs::read_dir(directory.path()).await.unwrap();
+        let mut names = Vec::new();
+        while let Some(entry) = entries.next_entry().await.unwrap() {
+            names.push(entry.file_name());
+        }
+        assert_eq!(names, vec![OsString::from("config.toml")]);
+    }
+}
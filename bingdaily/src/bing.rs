@@ -1,21 +1,66 @@
-use std::fmt::{Debug, Formatter};
+use std::ffi::OsString;
+use std::fmt::{Debug, Display, Formatter};
 use std::io;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
 use chrono::{DateTime, NaiveDate, NaiveTime, Utc};
-use log::debug;
-use serde::Deserialize;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
-use tokio::fs::{create_dir_all, File};
-use tokio::io::AsyncWriteExt;
+use reqwest::header::{CONTENT_RANGE, CONTENT_TYPE, RANGE};
+use reqwest::StatusCode;
+use tokio::fs::{create_dir_all, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio_stream::StreamExt;
+use url::Url;
+use crate::state::{move_into_place, write_atomically};
 
-#[derive(Default)]
+#[derive(Clone, Default, Deserialize, Eq, Hash, PartialEq)]
+#[serde(try_from = "String")]
 pub enum Market {
+    ChineseChina,
     DanishDenmark,
+    EnglishAU,
+    EnglishCA,
     EnglishGB,
+    EnglishIN,
     #[default]
     EnglishUS,
+    FrenchFrance,
+    GermanGermany,
+    JapaneseJapan,
+    PortugueseBrazil,
+    /// Any other `xx-YY` market code, for markets Bing supports that are not listed.
+    Other(String),
+}
+
+impl Market {
+    /// Every known market.
+    pub const ALL: &'static [Market] = &[
+        Market::ChineseChina,
+        Market::DanishDenmark,
+        Market::EnglishAU,
+        Market::EnglishCA,
+        Market::EnglishGB,
+        Market::EnglishIN,
+        Market::EnglishUS,
+        Market::FrenchFrance,
+        Market::GermanGermany,
+        Market::JapaneseJapan,
+        Market::PortugueseBrazil,
+    ];
+}
+
+/// Whether the code has the `xx-YY` shape of a market code.
+fn is_market_code(code: &str) -> bool {
+    let code = code.as_bytes();
+    code.len() == 5
+        && code[..2].iter().all(u8::is_ascii_lowercase)
+        && code[2] == b'-'
+        && code[3..].iter().all(u8::is_ascii_uppercase)
 }
 
 impl Debug for Market {
@@ -24,13 +69,22 @@ impl Debug for Market {
     }
 }
 
-impl ToString for Market {
-    fn to_string(&self) -> String {
-        match self {
-            Market::DanishDenmark => "da-DK".to_owned(),
-            Market::EnglishGB => "en-GB".to_owned(),
-            Market::EnglishUS => "en-US".to_owned(),
-        }
+impl Display for Market {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Market::ChineseChina => "zh-CN",
+            Market::DanishDenmark => "da-DK",
+            Market::EnglishAU => "en-AU",
+            Market::EnglishCA => "en-CA",
+            Market::EnglishGB => "en-GB",
+            Market::EnglishIN => "en-IN",
+            Market::EnglishUS => "en-US",
+            Market::FrenchFrance => "fr-FR",
+            Market::GermanGermany => "de-DE",
+            Market::JapaneseJapan => "ja-JP",
+            Market::PortugueseBrazil => "pt-BR",
+            Market::Other(code) => code,
+        })
     }
 }
 
@@ -38,28 +92,101 @@ impl ToString for Market {
 #[error("Unknown market: {0}")]
 pub struct UnknownMarket(String);
 
+impl TryFrom<String> for Market {
+    type Error = UnknownMarket;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
 impl FromStr for Market {
     type Err = UnknownMarket;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
+            "zh-CN" => Ok(Market::ChineseChina),
             "da-DK" => Ok(Market::DanishDenmark),
+            "en-AU" => Ok(Market::EnglishAU),
+            "en-CA" => Ok(Market::EnglishCA),
             "en-GB" => Ok(Market::EnglishGB),
+            "en-IN" => Ok(Market::EnglishIN),
             "en-US" => Ok(Market::EnglishUS),
+            "fr-FR" => Ok(Market::FrenchFrance),
+            "de-DE" => Ok(Market::GermanGermany),
+            "ja-JP" => Ok(Market::JapaneseJapan),
+            "pt-BR" => Ok(Market::PortugueseBrazil),
+            _ if is_market_code(s) => Ok(Market::Other(s.to_owned())),
             _ => Err(UnknownMarket(s.to_owned())),
         }
     }
 }
 
-const BING_IMAGE_API_BASE_URL: &str = "https://www.bing.com/HPImageArchive.aspx";
+/// Image resolutions offered by Bing.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+pub enum Resolution {
+    #[default]
+    UHD,
+    #[serde(rename = "1920x1080")]
+    R1920x1080,
+    #[serde(rename = "1366x768")]
+    R1366x768,
+    #[serde(rename = "1280x720")]
+    R1280x720,
+}
+
+impl Resolution {
+    /// Every resolution except [Resolution::UHD], from smallest to largest.
+    const SIZED: [Resolution; 3] = [Resolution::R1280x720, Resolution::R1366x768, Resolution::R1920x1080];
+
+    /// Suffix appended to the image's `urlbase` to request this resolution.
+    pub fn suffix(&self) -> &'static str {
+        match self {
+            Resolution::UHD => "UHD",
+            Resolution::R1920x1080 => "1920x1080",
+            Resolution::R1366x768 => "1366x768",
+            Resolution::R1280x720 => "1280x720",
+        }
+    }
+
+    /// Dimensions of the resolution, or `None` for UHD whose dimensions vary per image.
+    pub fn dimensions(&self) -> Option<(u32, u32)> {
+        match self {
+            Resolution::UHD => None,
+            Resolution::R1920x1080 => Some((1920, 1080)),
+            Resolution::R1366x768 => Some((1366, 768)),
+            Resolution::R1280x720 => Some((1280, 720)),
+        }
+    }
+
+    /// Selects the smallest resolution covering a display of the given size, falling back to
+    /// UHD for displays larger than every sized resolution.
+    pub fn for_display(width: u32, height: u32) -> Resolution {
+        Self::SIZED.into_iter()
+            .find(|resolution| {
+                let (res_width, res_height) = resolution.dimensions().unwrap();
+                res_width >= width && res_height >= height
+            })
+            .unwrap_or(Resolution::UHD)
+    }
+}
+
+/// Path of the image archive endpoint, relative to the base URL.
+const BING_IMAGE_API_PATH: &str = "HPImageArchive.aspx";
 const BING_BASE_URL: &str = "https://www.bing.com";
+/// Connections kept alive per host, enough for downloading Bing's whole archive at once.
+const MAX_IDLE_CONNECTIONS: usize = 8;
+/// Time allowed for establishing a connection to Bing.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Default time allowed for a request to complete, including reading the response body.
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
 
 #[derive(Deserialize)]
 struct BingAPIResponse {
     images: Vec<BingImage>,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
 pub struct BingImage {
     #[serde(rename = "startdate")]
     start_date: String,
@@ -71,31 +198,294 @@ pub struct BingImage {
     #[serde(rename = "urlbase")]
     url_base: String,
     title: String,
+    #[serde(default)]
+    copyright: String,
+    /// Link to more information about the picture's subject.
+    #[serde(rename = "copyrightlink", default)]
+    copyright_link: String,
+    /// Whether Bing intends the image as a wallpaper, unlike e.g. quiz graphics.
+    #[serde(default = "default_wp")]
+    wp: bool,
+    /// Hash of the image's content, identical for an image appearing in several markets.
+    #[serde(default)]
+    hsh: String,
+    /// Market the image was requested for, filled in after fetching.
+    #[serde(skip)]
+    market: Market,
+    /// Base URL the image's URLs are relative to, filled in after fetching.
+    #[serde(skip, default = "default_base_url")]
+    base_url: String,
 }
 
+fn default_wp() -> bool {
+    true
+}
+
+fn default_base_url() -> String {
+    BING_BASE_URL.to_owned()
+}
+
+/// Number of days Bing keeps images available for through its API.
+pub const MAX_HISTORY_DAYS: u32 = 8;
+
+/// Query parameters of an archive request for `count` images of the market, starting `idx` days
+/// ago. Without `mkt`, Bing picks the market from the client's location.
+fn archive_query(market: &Market, idx: u32, count: u32) -> [(&'static str, String); 4] {
+    [
+        ("format", "js".to_owned()),
+        ("idx", idx.to_string()),
+        ("n", count.to_string()),
+        ("mkt", market.to_string()),
+    ]
+}
+
+/// Longest file name given to downloaded images, leaving room for the suffix of temporary
+/// download files within the 255 byte limit of common filesystems.
+pub const MAX_FILE_NAME_BYTES: usize = 200;
+
 pub const BING_DATE_FORMAT: &str = "%Y%m%d";
 pub const TIME_FORMAT: &str = "%H%M";
 
-pub fn parse_bing_date(s: &str) -> Result<DateTime<Utc>, chrono::ParseError> {
-    let (date, time) = NaiveDate::parse_and_remainder(s, &BING_DATE_FORMAT)?;
-    let time = NaiveTime::parse_from_str(time, &TIME_FORMAT)
-        .unwrap_or_else(|_| NaiveTime::from_hms_opt(7, 0, 0).unwrap());
+#[derive(Debug, Error)]
+pub enum BingDateError {
+    #[error("Bing date '{0}' is too short, expected 8 or 12 digits")]
+    TooShort(String),
+    #[error("Bing date '{0}' is too long, expected 8 or 12 digits")]
+    TooLong(String),
+    #[error("Bing date '{0}' has an incomplete time, expected 8 or 12 digits")]
+    IncompleteTime(String),
+    #[error(transparent)]
+    ParseError(#[from] chrono::ParseError),
+}
+
+/// Parses either a `YYYYMMDD` date, which is assumed to start at 7am, or a `YYYYMMDDHHMM` date
+/// and time.
+pub fn parse_bing_date(s: &str) -> Result<DateTime<Utc>, BingDateError> {
+    match s.len() {
+        0..=7 => return Err(BingDateError::TooShort(s.to_owned())),
+        9..=11 => return Err(BingDateError::IncompleteTime(s.to_owned())),
+        13.. => return Err(BingDateError::TooLong(s.to_owned())),
+        _ => {}
+    }
+
+    let (date, time) = NaiveDate::parse_and_remainder(s, BING_DATE_FORMAT)?;
+    let time = if time.is_empty() {
+        NaiveTime::from_hms_opt(7, 0, 0).unwrap()
+    } else {
+        NaiveTime::parse_from_str(time, TIME_FORMAT)?
+    };
 
     Ok(date.and_time(time).and_utc())
 }
 
+/// Resolves a URL returned by the Bing API against `base_url`, usually [BING_BASE_URL]. Plain
+/// paths as well as protocol-relative and absolute URLs are accepted. The result uses https
+/// unless the base URL itself uses http, e.g. a local server.
+pub fn resolve_bing_url(base_url: &str, url: &str) -> Result<Url, url::ParseError> {
+    let base_url = Url::parse(base_url)?;
+    let mut url = base_url.join(url)?;
+    if url.scheme() == "http" && base_url.scheme() == "https" {
+        // changing between special schemes always succeeds
+        let _ = url.set_scheme("https");
+    }
+    Ok(url)
+}
+
+/// Replaces path separators, characters reserved on common filesystems and control characters
+/// with `_`, so the title can't create nested directories or fail to be written.
+fn sanitize_file_name(title: &str) -> String {
+    title.chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect::<String>()
+        .trim_matches([' ', '.'])
+        .to_owned()
+}
+
+/// Strips a trailing `_UHD` or `_WIDTHxHEIGHT` resolution token, along with a `.jpg` extension
+/// following it, from an image URL base.
+fn strip_resolution(url_base: &str) -> &str {
+    let base = url_base.strip_suffix(".jpg").unwrap_or(url_base);
+    let Some((stripped, token)) = base.rsplit_once('_') else {
+        return url_base;
+    };
+    let is_resolution = token == "UHD" || token.split_once('x').is_some_and(|(width, height)| {
+        !width.is_empty() && !height.is_empty()
+            && width.bytes().all(|byte| byte.is_ascii_digit())
+            && height.bytes().all(|byte| byte.is_ascii_digit())
+    });
+    if is_resolution {
+        stripped
+    } else {
+        url_base
+    }
+}
+
 impl BingImage {
-    pub fn get_image_url(&self) -> String {
-        format!("{}{}_UHD.jpg", BING_BASE_URL, self.url_base)
+    pub fn get_image_url(&self) -> Result<Url, url::ParseError> {
+        self.get_image_url_with_resolution(Resolution::UHD)
+    }
+
+    /// URL of the image in the given resolution. A resolution already part of the `urlbase` Bing
+    /// returned is replaced rather than suffixed again.
+    pub fn get_image_url_with_resolution(&self, resolution: Resolution) -> Result<Url, url::ParseError> {
+        resolve_bing_url(&self.base_url, &format!("{}_{}.jpg", strip_resolution(&self.url_base), resolution.suffix()))
+    }
+
+    /// URL of the image exactly as returned by Bing, which exists even when the resolution
+    /// requested through [Self::get_image_url_with_resolution] doesn't.
+    pub fn get_fallback_image_url(&self) -> Result<Url, url::ParseError> {
+        resolve_bing_url(&self.base_url, &self.url)
     }
 
+    /// File name of the downloaded image, `YYYYMMDD-market-title.jpg`. Characters of the title
+    /// that are invalid in file names are replaced, and it is truncated to keep the name within
+    /// [MAX_FILE_NAME_BYTES]. Downloads are always JPEG images, so the extension is fixed.
     pub fn get_image_file_name(&self) -> String {
-        format!("{}-{}.jpg", self.start_date, self.title)
+        const EXTENSION: &str = ".jpg";
+        let prefix = format!("{}-{}-", self.start_date, self.market);
+        let title = sanitize_file_name(&self.title);
+        let mut end = MAX_FILE_NAME_BYTES.saturating_sub(prefix.len() + EXTENSION.len()).min(title.len());
+        while !title.is_char_boundary(end) {
+            end -= 1;
+        }
+        format!("{}{}{}", prefix, title[..end].trim_end(), EXTENSION)
+    }
+
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+
+    pub fn copyright(&self) -> &str {
+        &self.copyright
+    }
+
+    pub fn copyright_link(&self) -> &str {
+        &self.copyright_link
+    }
+
+    pub fn is_wallpaper(&self) -> bool {
+        self.wp
+    }
+
+    pub fn hash(&self) -> &str {
+        &self.hsh
+    }
+
+    /// Resolution of the image referenced by the `url` Bing returned, if it contains one.
+    pub fn implied_resolution(&self) -> Option<(u32, u32)> {
+        self.url
+            .split(['_', '.', '&', '='])
+            .find_map(|token| {
+                let (width, height) = token.split_once('x')?;
+                Some((width.parse().ok()?, height.parse().ok()?))
+            })
     }
 
-    pub fn get_end_date(&self) -> Result<DateTime<Utc>, chrono::ParseError> {
+    pub fn market(&self) -> &Market {
+        &self.market
+    }
+
+    pub fn get_start_date(&self) -> Result<DateTime<Utc>, BingDateError> {
+        parse_bing_date(&self.start_date)
+    }
+
+    pub fn get_end_date(&self) -> Result<DateTime<Utc>, BingDateError> {
         parse_bing_date(&self.end_date)
     }
+
+    /// Parses the end date, estimating it as a day after the precise start date should the end
+    /// date be malformed. Fails with the end date's error if neither can be parsed.
+    pub fn get_end_date_or_estimate(&self) -> Result<DateTime<Utc>, BingDateError> {
+        self.get_end_date().or_else(|err| match parse_bing_date(&self.full_start_date) {
+            Ok(start_date) => {
+                debug!("Failed to parse end date: {}, estimating it from the start date", err);
+                Ok(start_date + chrono::Duration::hours(24))
+            }
+            Err(_) => Err(err),
+        })
+    }
+
+    /// Describes the image as an XMP packet using Dublin Core fields, for photo management tools
+    /// to pick up from a sidecar file.
+    pub fn to_xmp(&self) -> String {
+        let date = self.get_start_date()
+            .map(|date| date.format("%Y-%m-%d").to_string())
+            .unwrap_or_default();
+        let source = self.get_image_url().map(|url| url.to_string()).unwrap_or_default();
+        format!(
+            concat!(
+                "<?xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>\n",
+                "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\">\n",
+                " <rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">\n",
+                "  <rdf:Description rdf:about=\"\" xmlns:dc=\"http://purl.org/dc/elements/1.1/\">\n",
+                "   <dc:title><rdf:Alt><rdf:li xml:lang=\"x-default\">{}</rdf:li></rdf:Alt></dc:title>\n",
+                "   <dc:rights><rdf:Alt><rdf:li xml:lang=\"x-default\">{}</rdf:li></rdf:Alt></dc:rights>\n",
+                "   <dc:date><rdf:Seq><rdf:li>{}</rdf:li></rdf:Seq></dc:date>\n",
+                "   <dc:source>{}</dc:source>\n",
+                "  </rdf:Description>\n",
+                " </rdf:RDF>\n",
+                "</x:xmpmeta>\n",
+                "<?xpacket end=\"w\"?>\n",
+            ),
+            escape_xml(&self.title),
+            escape_xml(&self.copyright),
+            date,
+            escape_xml(&source),
+        )
+    }
+}
+
+fn escape_xml(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            // control characters other than whitespace are not allowed in XML
+            c if c.is_control() && !c.is_whitespace() => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Location of the XMP sidecar of the picture at `path`.
+pub fn xmp_sidecar_path(path: &Path) -> PathBuf {
+    path.with_extension("xmp")
+}
+
+/// Location of the metadata sidecar of the picture at `path`, holding the image's details as
+/// returned by Bing.
+pub fn metadata_sidecar_path(path: &Path) -> PathBuf {
+    path.with_extension("json")
+}
+
+/// Reads the image's details from the metadata sidecar of the picture at `path`. Returns `None`
+/// if the sidecar is missing or corrupt.
+pub async fn read_metadata_sidecar(path: &Path) -> Option<BingImage> {
+    let sidecar_path = metadata_sidecar_path(path);
+    let contents = match tokio::fs::read(&sidecar_path).await {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return None,
+        Err(err) => {
+            warn!("Failed to read metadata sidecar {}: {}", sidecar_path.display(), err);
+            return None;
+        }
+    };
+    match serde_json::from_slice(&contents) {
+        Ok(image) => Some(image),
+        Err(err) => {
+            warn!("Ignoring corrupt metadata sidecar {}: {}", sidecar_path.display(), err);
+            None
+        }
+    }
 }
 
 #[derive(Debug, Error)]
@@ -104,64 +494,1153 @@ pub enum ImageOfTheDayError {
     RequestError(#[from] reqwest::Error),
     #[error("Bing API did not return any images")]
     NoImagesFound,
+    #[error("Bing only keeps {MAX_HISTORY_DAYS} days of images, cannot fetch {count} images starting {start_idx} days ago")]
+    InvalidRange { start_idx: u32, count: u32 },
 }
 
 #[derive(Debug, Error)]
 pub enum DownloadImageError {
     #[error(transparent)]
     RequestError(#[from] reqwest::Error),
+    #[error("Invalid image URL: {0}")]
+    InvalidUrl(#[from] url::ParseError),
+    #[error("Image not found at {0}")]
+    NotFound(Url),
     #[error("Failed to write image to {0:?}: {1}")]
     IoError(PathBuf, #[source] io::Error),
+    #[error("Response from {0} is not a valid image: {1}")]
+    InvalidImage(Url, String),
 }
 
+/// Called with the number of bytes downloaded so far and the total size of the download, which is
+/// `None` if the server didn't report it.
+pub type ProgressCallback = dyn Fn(u64, Option<u64>) + Send + Sync;
+
 pub struct Bing {
     client: reqwest::Client,
+    download_attempts: u32,
+    timeout: Duration,
+    /// URL of the image archive endpoint.
+    api_url: String,
+    /// URL the image URLs returned by the archive are relative to.
+    base_url: String,
+    /// Incremented by the size of every downloaded chunk.
+    download_counter: Option<Arc<AtomicU64>>,
 }
 
 impl Bing {
-    pub fn new() -> Bing {
-        Bing {
-            client: reqwest::Client::new(),
+    /// Creates a client whose connections, negotiated as HTTP/2 where possible, are kept alive and
+    /// shared by all requests, so consecutive downloads multiplex over the same connection. Fails
+    /// if the HTTP client can't be built, e.g. when the TLS backend fails to initialize.
+    pub fn new() -> Result<Bing, reqwest::Error> {
+        Bing::from_client_builder(reqwest::Client::builder())
+    }
+
+    /// Creates a client from `builder`, e.g. one configured with a proxy, on top of the connection
+    /// settings described in [Bing::new]. Fails if the builder's settings are invalid.
+    pub fn from_client_builder(builder: reqwest::ClientBuilder) -> Result<Bing, reqwest::Error> {
+        let client = builder
+            .http2_adaptive_window(true)
+            .pool_max_idle_per_host(MAX_IDLE_CONNECTIONS)
+            .connect_timeout(CONNECT_TIMEOUT)
+            .build()?;
+        Ok(Bing {
+            client,
+            download_attempts: DEFAULT_DOWNLOAD_ATTEMPTS,
+            timeout: DEFAULT_REQUEST_TIMEOUT,
+            api_url: format!("{}/{}", BING_BASE_URL, BING_IMAGE_API_PATH),
+            base_url: BING_BASE_URL.to_owned(),
+            download_counter: None,
+        })
+    }
+
+    /// Queries the image archive at `base_url` instead of [BING_BASE_URL], e.g. a local server
+    /// serving canned responses. Images referenced by relative URLs are downloaded from the same
+    /// server, keeping its scheme.
+    pub fn with_base_url(mut self, base_url: &str) -> Bing {
+        let base_url = base_url.trim_end_matches('/');
+        self.api_url = format!("{}/{}", base_url, BING_IMAGE_API_PATH);
+        self.base_url = base_url.to_owned();
+        self
+    }
+
+    /// Sets the time allowed for each request to complete, so a hung connection fails rather than
+    /// blocking polling indefinitely. Timed out downloads are resumed by the next attempt.
+    pub fn with_timeout(mut self, timeout: Duration) -> Bing {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Adds the size of every chunk downloaded, including those of failed downloads, to `counter`.
+    pub(crate) fn with_download_counter(mut self, counter: Arc<AtomicU64>) -> Bing {
+        self.download_counter = Some(counter);
+        self
+    }
+
+    /// Sets the number of attempts made at downloading an image before giving up, backing off
+    /// exponentially between attempts.
+    pub fn with_download_attempts(mut self, attempts: u32) -> Bing {
+        self.download_attempts = attempts.max(1);
+        self
+    }
+
+    pub async fn image_of_the_day(&self, market: &Market) -> Result<BingImage, ImageOfTheDayError> {
+        let mut images = self.images(market, 0, 1).await?;
+        Ok(images.remove(0))
+    }
+
+    /// Fetches `count` images starting `start_idx` days ago in a single request, validating that
+    /// the range lies within the days Bing keeps images available for.
+    pub async fn images_range(&self, market: &Market, start_idx: u32, count: u32) -> Result<Vec<BingImage>, ImageOfTheDayError> {
+        if count == 0 || start_idx.saturating_add(count) > MAX_HISTORY_DAYS {
+            return Err(ImageOfTheDayError::InvalidRange { start_idx, count });
         }
+        self.images(market, start_idx, count).await
     }
 
-    pub async fn image_of_the_day(&self) -> Result<BingImage, ImageOfTheDayError> {
-        let mut response = self
+    /// Fetches `count` images, starting `idx` days ago and ordered from newest to oldest. Never
+    /// returns an empty list.
+    pub async fn images(&self, market: &Market, idx: u32, count: u32) -> Result<Vec<BingImage>, ImageOfTheDayError> {
+        let response = self
             .client
-            .get(BING_IMAGE_API_BASE_URL)
-            .query(&[
-                ("format", "js"),
-                ("idx", "0"),
-                ("n", "1"),
-            ])
+            .get(&self.api_url)
+            .timeout(self.timeout)
+            .query(&archive_query(market, idx, count))
             .send()
             .await?
             .json::<BingAPIResponse>()
             .await?;
 
-        let mut images = response.images.drain(..);
-        images.next().ok_or(ImageOfTheDayError::NoImagesFound)
+        let mut images = response.images;
+        if images.is_empty() {
+            return Err(ImageOfTheDayError::NoImagesFound);
+        }
+        for image in &mut images {
+            image.market = market.clone();
+            image.base_url = self.base_url.clone();
+        }
+        Ok(images)
+    }
+
+    /// Downloads the image into `path`. An already existing file is reused unless `force` is set,
+    /// in which case it is downloaded again and overwritten.
+    pub async fn download_image(&self, image: &BingImage, path: &Path, force: bool, progress: Option<&ProgressCallback>) -> Result<(), DownloadImageError> {
+        self.download_image_with_resolution(image, Resolution::UHD, path, force, progress).await
     }
 
-    pub async fn download_image(&self, image: &BingImage, path: &Path) -> Result<(), DownloadImageError> {
-        let url = image.get_image_url();
+    pub async fn download_image_with_resolution(
+        &self,
+        image: &BingImage,
+        resolution: Resolution,
+        path: &Path,
+        force: bool,
+        progress: Option<&ProgressCallback>,
+    ) -> Result<(), DownloadImageError> {
+        if !force {
+            if let Ok(true) = tokio::fs::try_exists(path).await {
+                debug!("Picture already downloaded");
+                return Ok(());
+            }
+        }
+
+        let url = image.get_image_url_with_resolution(resolution)?;
 
         debug!("Downloading image from {} into {}", url, path.display());
 
-        let response = self.client.get(&url).send().await?;
+        // succeeds for existing directories, including ones created by a concurrent download
         if let Some(parent) = path.parent() {
-            if let Ok(false) = tokio::fs::try_exists(parent).await {
-                create_dir_all(parent).await
-                    .map_err(|err| DownloadImageError::IoError(path.to_path_buf(), err))?;
+            create_dir_all(parent).await
+                .map_err(|err| DownloadImageError::IoError(parent.to_path_buf(), err))?;
+        }
+
+        // download into a partial file and move it into place once complete, so an interrupted
+        // download never leaves a partially written picture behind. Downloads of the same picture
+        // share the partial file to resume each other and take turns through an advisory lock
+        let partial_path = partial_path(path);
+        let _lock = lock_partial_download(&partial_path).await
+            .map_err(|err| DownloadImageError::IoError(partial_path.clone(), err))?;
+        if !force {
+            if let Ok(true) = tokio::fs::try_exists(path).await {
+                debug!("Picture downloaded concurrently");
+                return Ok(());
+            }
+        }
+        let mut result = self.download_with_retries(&url, &partial_path, progress).await;
+        if let Err(DownloadImageError::NotFound(_)) = result {
+            // some markets and days lack the requested resolution, fall back to the image Bing
+            // referenced directly
+            let fallback_url = image.get_fallback_image_url()?;
+            if fallback_url != url {
+                warn!("Image not available at {}, falling back to {}", url, fallback_url);
+                let _ = tokio::fs::remove_file(&partial_path).await;
+                result = self.download_with_retries(&fallback_url, &partial_path, progress).await;
+            }
+        }
+        let result = match result {
+            Ok(()) => move_into_place(&partial_path, path)
+                .await
+                .map_err(|err| DownloadImageError::IoError(path.to_owned(), err)),
+            Err(err) => Err(err),
+        };
+        // the partial file is resumed by the next attempt, unless there is nothing to resume
+        if result.is_err() && tokio::fs::metadata(&partial_path).await.is_ok_and(|metadata| metadata.len() == 0) {
+            let _ = tokio::fs::remove_file(&partial_path).await;
+        }
+        result
+    }
+
+    async fn download_with_retries(&self, url: &Url, temp_path: &Path, progress: Option<&ProgressCallback>) -> Result<(), DownloadImageError> {
+        let mut result = self.download_into(url, temp_path, progress).await;
+        let mut backoff = INITIAL_DOWNLOAD_BACKOFF;
+        for attempt in 2..=self.download_attempts {
+            match &result {
+                Err(DownloadImageError::RequestError(err)) if is_transient(err) => {
+                    warn!("Download interrupted: {}, resuming in {:?} (attempt {}/{})", err, backoff, attempt, self.download_attempts);
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 4;
+                    result = self.download_into(url, temp_path, progress).await;
+                }
+                _ => break,
+            }
+        }
+        result
+    }
+
+    /// Downloads `url` into `temp_path`, resuming from the bytes already written by an interrupted
+    /// attempt. Servers ignoring the requested range are downloaded from the start instead.
+    async fn download_into(&self, url: &Url, temp_path: &Path, progress: Option<&ProgressCallback>) -> Result<(), DownloadImageError> {
+        let written = tokio::fs::metadata(temp_path).await.map(|metadata| metadata.len()).unwrap_or(0);
+
+        let mut request = self.client.get(url.clone()).timeout(self.timeout);
+        if written > 0 {
+            request = request.header(RANGE, format!("bytes={}-", written));
+        }
+        let response = request.send().await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Err(DownloadImageError::NotFound(url.clone()));
+        }
+        if written > 0 && response.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+            // the partial file doesn't belong to the image served now, e.g. because it changed
+            // since, so start over without a range
+            debug!("Server rejected resuming after {} bytes, restarting download", written);
+            let _ = tokio::fs::remove_file(temp_path).await;
+            return Box::pin(self.download_into(url, temp_path, progress)).await;
+        }
+        let response = response.error_for_status()?;
+        if let Some(content_type) = response.headers().get(CONTENT_TYPE) {
+            let content_type = content_type.to_str().unwrap_or_default();
+            if !content_type.starts_with("image/") {
+                let _ = tokio::fs::remove_file(temp_path).await;
+                return Err(DownloadImageError::InvalidImage(url.clone(), format!("content type {}", content_type)));
             }
         }
-        let mut file = File::create(&path)
-            .await
-            .map_err(|err| DownloadImageError::IoError(path.to_owned(), err))?;
-        let mut bytes = response.bytes_stream();
-        while let Some(Ok(item)) = bytes.next().await {
-            file.write_all(&item).await.map_err(|err| DownloadImageError::IoError(path.to_owned(), err))?;
+
+        let resumed = written > 0
+            && response.status() == StatusCode::PARTIAL_CONTENT
+            && response.headers().get(CONTENT_RANGE)
+                .and_then(|range| range.to_str().ok())
+                .is_some_and(|range| range.starts_with(&format!("bytes {}-", written)));
+        if resumed {
+            debug!("Resuming download after {} bytes", written);
+        } else if written > 0 {
+            debug!("Server ignored range request, restarting download");
+        }
+        let offset = if resumed { written } else { 0 };
+        let total = response.content_length().map(|length| offset + length);
+        write_response(response, temp_path, resumed, offset, total, progress, self.download_counter.as_deref()).await?;
+
+        if !has_jpeg_signature(temp_path).await {
+            let _ = tokio::fs::remove_file(temp_path).await;
+            return Err(DownloadImageError::InvalidImage(url.clone(), "missing JPEG signature".to_owned()));
         }
         Ok(())
     }
+
+    /// Writes the image's metadata into an XMP sidecar next to the picture at `path`.
+    pub async fn write_xmp_sidecar(&self, image: &BingImage, path: &Path) -> Result<(), DownloadImageError> {
+        let sidecar_path = xmp_sidecar_path(path);
+        write_atomically(&sidecar_path, image.to_xmp().as_bytes()).await
+            .map_err(|err| DownloadImageError::IoError(sidecar_path, err))
+    }
+
+    /// Writes the image's details into a metadata sidecar next to the picture at `path`, for
+    /// restoring them on startup without querying Bing.
+    pub async fn write_metadata_sidecar(&self, image: &BingImage, path: &Path) -> Result<(), DownloadImageError> {
+        let sidecar_path = metadata_sidecar_path(path);
+        let contents = serde_json::to_vec(image).map_err(io::Error::from);
+        let result = match contents {
+            Ok(contents) => write_atomically(&sidecar_path, &contents).await,
+            Err(err) => Err(err),
+        };
+        result.map_err(|err| DownloadImageError::IoError(sidecar_path, err))
+    }
+}
+
+/// Default number of attempts made at downloading an image, resuming interrupted downloads where
+/// possible.
+pub const DEFAULT_DOWNLOAD_ATTEMPTS: u32 = 3;
+
+/// Delay before the second download attempt, quadrupled for every further attempt.
+const INITIAL_DOWNLOAD_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Whether the error is likely to go away by itself, unlike e.g. the server rejecting the request.
+fn is_transient(err: &reqwest::Error) -> bool {
+    match err.status() {
+        Some(status) => status.is_server_error(),
+        None => err.is_connect() || err.is_timeout() || err.is_body() || err.is_request(),
+    }
+}
+
+/// Hidden file next to `path` that the picture is downloaded into, named after it so a later
+/// attempt resumes where a failed one left off.
+fn partial_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(PARTIAL_DOWNLOAD_SUFFIX);
+    path.with_file_name(name)
+}
+
+/// Suffix of partial downloads, see [partial_path].
+pub(crate) const PARTIAL_DOWNLOAD_SUFFIX: &str = ".part";
+
+/// Opens the partial file, creating it if missing, and waits for an exclusive lock on it, which is
+/// released when the returned file is dropped.
+async fn lock_partial_download(path: &Path) -> io::Result<std::fs::File> {
+    let path = path.to_owned();
+    tokio::task::spawn_blocking(move || {
+        let file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+        file.lock()?;
+        Ok(file)
+    }).await.map_err(io::Error::other)?
+}
+
+/// Whether the file starts with the `FF D8 FF` signature of JPEG images.
+async fn has_jpeg_signature(path: &Path) -> bool {
+    let mut signature = [0u8; 3];
+    match tokio::fs::File::open(path).await {
+        Ok(mut file) => file.read_exact(&mut signature).await.is_ok() && signature == [0xFF, 0xD8, 0xFF],
+        Err(_) => false,
+    }
+}
+
+/// Writes the response's body into the file at `path`, reporting the bytes written so far,
+/// starting at `offset`, after each chunk and adding each chunk's size to `counter`.
+async fn write_response(
+    response: reqwest::Response,
+    path: &Path,
+    append: bool,
+    offset: u64,
+    total: Option<u64>,
+    progress: Option<&ProgressCallback>,
+    counter: Option<&AtomicU64>,
+) -> Result<(), DownloadImageError> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(append)
+        .truncate(!append)
+        .open(path)
+        .await
+        .map_err(|err| DownloadImageError::IoError(path.to_owned(), err))?;
+    let mut bytes = response.bytes_stream();
+    let mut downloaded = offset;
+    while let Some(item) = bytes.next().await {
+        let item = item?;
+        file.write_all(&item).await.map_err(|err| DownloadImageError::IoError(path.to_owned(), err))?;
+        downloaded += item.len() as u64;
+        if let Some(counter) = counter {
+            counter.fetch_add(item.len() as u64, Ordering::Relaxed);
+        }
+        if let Some(progress) = progress {
+            progress(downloaded, total);
+        }
+    }
+    file.sync_all().await.map_err(|err| DownloadImageError::IoError(path.to_owned(), err))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::collections::VecDeque;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::net::{TcpListener, TcpStream};
+    use super::*;
+
+    #[test]
+    fn resolves_bing_urls() {
+        for (url, expected) in [
+            ("/th?id=OHR.Lighthouse_UHD.jpg", "https://www.bing.com/th?id=OHR.Lighthouse_UHD.jpg"),
+            ("th?id=OHR.Lighthouse_UHD.jpg", "https://www.bing.com/th?id=OHR.Lighthouse_UHD.jpg"),
+            ("//www.bing.com/th?id=OHR.Lighthouse_UHD.jpg", "https://www.bing.com/th?id=OHR.Lighthouse_UHD.jpg"),
+            ("//s.cn.bing.net/th?id=OHR.Lighthouse_UHD.jpg", "https://s.cn.bing.net/th?id=OHR.Lighthouse_UHD.jpg"),
+            ("http://www.bing.com/th?id=OHR.Lighthouse_UHD.jpg", "https://www.bing.com/th?id=OHR.Lighthouse_UHD.jpg"),
+            ("https://www.bing.com/th?id=OHR.Lighthouse_UHD.jpg", "https://www.bing.com/th?id=OHR.Lighthouse_UHD.jpg"),
+            ("/az/hprichbg/rb/Lighthouse_EN-US1234_1920x1080.jpg", "https://www.bing.com/az/hprichbg/rb/Lighthouse_EN-US1234_1920x1080.jpg"),
+        ] {
+            assert_eq!(resolve_bing_url(BING_BASE_URL, url).unwrap().as_str(), expected, "{}", url);
+        }
+        assert!(resolve_bing_url(BING_BASE_URL, "http://[invalid").is_err());
+        assert!(resolve_bing_url("not a url", "/th?id=OHR.Lighthouse_UHD.jpg").is_err());
+    }
+
+    #[test]
+    fn keeps_the_scheme_of_http_base_urls() {
+        for (url, expected) in [
+            ("/th?id=OHR.Lighthouse_UHD.jpg", "http://127.0.0.1:8080/th?id=OHR.Lighthouse_UHD.jpg"),
+            ("//127.0.0.1:8080/th?id=OHR.Lighthouse_UHD.jpg", "http://127.0.0.1:8080/th?id=OHR.Lighthouse_UHD.jpg"),
+            ("http://www.bing.com/th?id=OHR.Lighthouse_UHD.jpg", "http://www.bing.com/th?id=OHR.Lighthouse_UHD.jpg"),
+            ("https://www.bing.com/th?id=OHR.Lighthouse_UHD.jpg", "https://www.bing.com/th?id=OHR.Lighthouse_UHD.jpg"),
+        ] {
+            assert_eq!(resolve_bing_url("http://127.0.0.1:8080", url).unwrap().as_str(), expected, "{}", url);
+        }
+    }
+
+    #[test]
+    fn parses_bing_dates() {
+        for (date, expected) in [
+            ("20230815", "2023-08-15T07:00:00Z"),
+            ("202308151630", "2023-08-15T16:30:00Z"),
+            ("202308150000", "2023-08-15T00:00:00Z"),
+            ("20240229", "2024-02-29T07:00:00Z"),
+        ] {
+            assert_eq!(parse_bing_date(date).unwrap(), expected.parse::<DateTime<Utc>>().unwrap(), "{}", date);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_bing_dates() {
+        for date in ["", "2023081"] {
+            assert!(matches!(parse_bing_date(date), Err(BingDateError::TooShort(_))), "{}", date);
+        }
+        for date in ["202308151", "2023081516", "20230815163"] {
+            assert!(matches!(parse_bing_date(date), Err(BingDateError::IncompleteTime(_))), "{}", date);
+        }
+        for date in ["2023081516300", "20230815163000"] {
+            assert!(matches!(parse_bing_date(date), Err(BingDateError::TooLong(_))), "{}", date);
+        }
+        for date in ["20231315", "20230230", "202308152500", "202308151260", "2023o8151630"] {
+            assert!(matches!(parse_bing_date(date), Err(BingDateError::ParseError(_))), "{}", date);
+        }
+    }
+
+    #[test]
+    fn selects_resolutions_for_displays() {
+        for (width, height, expected) in [
+            (0, 0, Resolution::R1280x720),
+            (800, 600, Resolution::R1280x720),
+            (1280, 720, Resolution::R1280x720),
+            (1281, 720, Resolution::R1366x768),
+            (1280, 721, Resolution::R1366x768),
+            (1366, 768, Resolution::R1366x768),
+            (1280, 800, Resolution::R1920x1080),
+            (1440, 900, Resolution::R1920x1080),
+            (1920, 1080, Resolution::R1920x1080),
+            (1080, 1920, Resolution::UHD),
+            (1920, 1200, Resolution::UHD),
+            (2560, 1440, Resolution::UHD),
+            (3840, 2160, Resolution::UHD),
+            (7680, 4320, Resolution::UHD),
+        ] {
+            assert_eq!(Resolution::for_display(width, height), expected, "{}x{}", width, height);
+        }
+    }
+
+    /// Canned HTTP/1.1 response, optionally closing the connection after it is written.
+    pub(crate) struct Response {
+        bytes: Vec<u8>,
+        close: bool,
+    }
+
+    impl Response {
+        pub(crate) fn new(status: &str, headers: &[(&str, &str)], body: &[u8]) -> Response {
+            let mut head = format!("HTTP/1.1 {}\r\ncontent-length: {}\r\n", status, body.len());
+            for (name, value) in headers {
+                head.push_str(&format!("{}: {}\r\n", name, value));
+            }
+            head.push_str("\r\n");
+            let mut bytes = head.into_bytes();
+            bytes.extend_from_slice(body);
+            Response { bytes, close: false }
+        }
+
+        pub(crate) fn json(body: &str) -> Response {
+            Response::new("200 OK", &[("content-type", "application/json")], body.as_bytes())
+        }
+
+        pub(crate) fn jpeg(body: &[u8]) -> Response {
+            Response::new("200 OK", &[("content-type", "image/jpeg")], body)
+        }
+
+        /// Cuts the response off after the first `length` bytes of the body, as if the connection
+        /// dropped.
+        fn truncated(mut self, length: usize) -> Response {
+            let head = self.bytes.windows(4).position(|window| window == b"\r\n\r\n").unwrap() + 4;
+            self.bytes.truncate(head + length);
+            self.close = true;
+            self
+        }
+    }
+
+    /// Local server answering requests with canned responses in order, recording the head of
+    /// every request and the number of connections accepted.
+    pub(crate) struct MockServer {
+        address: SocketAddr,
+        requests: Arc<Mutex<Vec<String>>>,
+        connections: Arc<AtomicUsize>,
+    }
+
+    impl MockServer {
+        pub(crate) async fn start(responses: Vec<Response>) -> MockServer {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+            let requests = Arc::new(Mutex::new(Vec::new()));
+            let connections = Arc::new(AtomicUsize::new(0));
+            let responses = Arc::new(Mutex::new(VecDeque::from(responses)));
+            tokio::spawn({
+                let requests = requests.clone();
+                let connections = connections.clone();
+                async move {
+                    while let Ok((stream, _)) = listener.accept().await {
+                        connections.fetch_add(1, Ordering::SeqCst);
+                        tokio::spawn(serve_connection(stream, requests.clone(), responses.clone()));
+                    }
+                }
+            });
+            MockServer { address, requests, connections }
+        }
+
+        pub(crate) fn base_url(&self) -> String {
+            format!("http://{}", self.address)
+        }
+
+        fn url(&self, path: &str) -> Url {
+            Url::parse(&self.base_url()).unwrap().join(path).unwrap()
+        }
+
+        pub(crate) fn requests(&self) -> Vec<String> {
+            self.requests.lock().unwrap().clone()
+        }
+
+        fn connections(&self) -> usize {
+            self.connections.load(Ordering::SeqCst)
+        }
+    }
+
+    async fn serve_connection(mut stream: TcpStream, requests: Arc<Mutex<Vec<String>>>, responses: Arc<Mutex<VecDeque<Response>>>) {
+        let mut buffer = Vec::new();
+        let mut chunk = [0u8; 1024];
+        loop {
+            // requests are bodiless GETs, so the head is the whole request
+            let end = loop {
+                if let Some(position) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+                    break position + 4;
+                }
+                match stream.read(&mut chunk).await {
+                    Ok(0) | Err(_) => return,
+                    Ok(read) => buffer.extend_from_slice(&chunk[..read]),
+                }
+            };
+            requests.lock().unwrap().push(String::from_utf8_lossy(&buffer[..end]).into_owned());
+            buffer.drain(..end);
+
+            let Some(response) = responses.lock().unwrap().pop_front() else {
+                return;
+            };
+            if stream.write_all(&response.bytes).await.is_err() || response.close {
+                return;
+            }
+        }
+    }
+
+    pub(crate) const ARCHIVE_RESPONSE: &str = r#"{
+        "images": [
+            {
+                "startdate": "20231005",
+                "fullstartdate": "202310050700",
+                "enddate": "20231006",
+                "url": "/th?id=OHR.Lighthouse_EN-US1234_1920x1080.jpg&rf=LaDigue_1920x1080.jpg&pid=hp",
+                "urlbase": "/th?id=OHR.Lighthouse_EN-US1234",
+                "copyright": "Lighthouse on the coast (© Photographer)",
+                "copyrightlink": "https://www.bing.com/search?q=lighthouse",
+                "title": "Guiding light",
+                "wp": true,
+                "hsh": "0123456789abcdef"
+            }
+        ]
+    }"#;
+
+    #[tokio::test]
+    async fn parses_image_of_the_day() {
+        let server = MockServer::start(vec![Response::json(ARCHIVE_RESPONSE)]).await;
+        let bing = Bing::new().unwrap().with_base_url(&server.base_url());
+
+        let image = bing.image_of_the_day(&Market::JapaneseJapan).await.unwrap();
+
+        assert_eq!(image.title(), "Guiding light");
+        assert_eq!(image.copyright(), "Lighthouse on the coast (© Photographer)");
+        assert_eq!(image.copyright_link(), "https://www.bing.com/search?q=lighthouse");
+        assert_eq!(image.hash(), "0123456789abcdef");
+        assert!(image.is_wallpaper());
+        assert_eq!(image.market(), &Market::JapaneseJapan);
+        assert_eq!(image.implied_resolution(), Some((1920, 1080)));
+        assert_eq!(image.get_start_date().unwrap().to_rfc3339(), "2023-10-05T07:00:00+00:00");
+        assert_eq!(image.get_end_date().unwrap().to_rfc3339(), "2023-10-06T07:00:00+00:00");
+        assert_eq!(image.get_image_url().unwrap(), server.url("/th?id=OHR.Lighthouse_EN-US1234_UHD.jpg"));
+        assert_eq!(
+            image.get_fallback_image_url().unwrap(),
+            server.url("/th?id=OHR.Lighthouse_EN-US1234_1920x1080.jpg&rf=LaDigue_1920x1080.jpg&pid=hp"),
+        );
+        assert_eq!(image.get_image_file_name(), "20231005-ja-JP-Guiding light.jpg");
+    }
+
+    #[tokio::test]
+    async fn queries_the_archive() {
+        let server = MockServer::start(vec![Response::json(ARCHIVE_RESPONSE)]).await;
+        let bing = Bing::new().unwrap().with_base_url(&format!("{}/", server.base_url()));
+
+        bing.images_range(&Market::EnglishGB, 2, 3).await.unwrap();
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 1);
+        let request_line = requests[0].lines().next().unwrap();
+        assert!(request_line.starts_with("GET /HPImageArchive.aspx?"), "{}", request_line);
+        for parameter in ["format=js", "idx=2", "n=3", "mkt=en-GB"] {
+            assert!(request_line.contains(parameter), "{} lacks {}", request_line, parameter);
+        }
+    }
+
+    const TWO_DAY_ARCHIVE_RESPONSE: &str = r#"{"images":[
+        {"startdate":"20231006","fullstartdate":"202310060700","enddate":"20231007","url":"/b_1920x1080.jpg","urlbase":"/b","title":"B"},
+        {"startdate":"20231005","fullstartdate":"202310050700","enddate":"20231006","url":"/a_1920x1080.jpg","urlbase":"/a","title":"A"}
+    ]}"#;
+
+    #[tokio::test]
+    async fn returns_every_archived_image() {
+        let server = MockServer::start(vec![Response::json(TWO_DAY_ARCHIVE_RESPONSE)]).await;
+        let bing = Bing::new().unwrap().with_base_url(&server.base_url());
+
+        let images = bing.images(&Market::EnglishGB, 0, 2).await.unwrap();
+
+        assert_eq!(images.iter().map(BingImage::title).collect::<Vec<_>>(), ["B", "A"]);
+        assert!(images.iter().all(|image| image.market() == &Market::EnglishGB));
+        assert_eq!(images[1].get_image_url().unwrap(), server.url("/a_UHD.jpg"));
+    }
+
+    #[tokio::test]
+    async fn image_of_the_day_is_the_newest_image() {
+        let server = MockServer::start(vec![Response::json(TWO_DAY_ARCHIVE_RESPONSE)]).await;
+        let bing = Bing::new().unwrap().with_base_url(&server.base_url());
+
+        let image = bing.image_of_the_day(&Market::EnglishUS).await.unwrap();
+
+        assert_eq!(image.title(), "B");
+        let requests = server.requests();
+        let request_line = requests[0].lines().next().unwrap();
+        for parameter in ["idx=0", "n=1"] {
+            assert!(request_line.contains(parameter), "{} lacks {}", request_line, parameter);
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_ranges_outside_the_history() {
+        let server = MockServer::start(Vec::new()).await;
+        let bing = Bing::new().unwrap().with_base_url(&server.base_url());
+
+        for (start_idx, count) in [(0, 0), (7, 0), (0, 9), (1, 8), (7, 2), (8, 1), (u32::MAX, 1), (1, u32::MAX)] {
+            let result = bing.images_range(&Market::EnglishUS, start_idx, count).await;
+            assert!(
+                matches!(result, Err(ImageOfTheDayError::InvalidRange { start_idx: s, count: c }) if s == start_idx && c == count),
+                "{} + {}", start_idx, count,
+            );
+        }
+        assert!(server.requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn accepts_ranges_within_the_history() {
+        let ranges = [(0, 1), (0, 8), (7, 1), (3, 5)];
+        let server = MockServer::start(ranges.iter().map(|_| Response::json(ARCHIVE_RESPONSE)).collect()).await;
+        let bing = Bing::new().unwrap().with_base_url(&server.base_url());
+
+        for (start_idx, count) in ranges {
+            bing.images_range(&Market::EnglishUS, start_idx, count).await.unwrap();
+        }
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), ranges.len());
+        for (request, (start_idx, count)) in requests.iter().zip(ranges) {
+            let request_line = request.lines().next().unwrap();
+            for parameter in [format!("idx={}", start_idx), format!("n={}", count)] {
+                assert!(request_line.contains(&parameter), "{} lacks {}", request_line, parameter);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn defaults_missing_optional_fields() {
+        let server = MockServer::start(vec![Response::json(
+            r#"{"images":[{"startdate":"20231005","fullstartdate":"202310050700","enddate":"20231006","url":"/a_1366x768.jpg","urlbase":"/a","title":"A"}]}"#,
+        )]).await;
+        let bing = Bing::new().unwrap().with_base_url(&server.base_url());
+
+        let image = bing.image_of_the_day(&Market::EnglishUS).await.unwrap();
+
+        assert_eq!(image.copyright(), "");
+        assert_eq!(image.copyright_link(), "");
+        assert_eq!(image.hash(), "");
+        assert!(image.is_wallpaper());
+        assert_eq!(image.get_image_url().unwrap(), server.url("/a_UHD.jpg"));
+    }
+
+    #[tokio::test]
+    async fn fails_without_images() {
+        let server = MockServer::start(vec![Response::json(r#"{"images":[]}"#)]).await;
+        let bing = Bing::new().unwrap().with_base_url(&server.base_url());
+
+        let result = bing.image_of_the_day(&Market::EnglishUS).await;
+
+        assert!(matches!(result, Err(ImageOfTheDayError::NoImagesFound)), "{:?}", result.err());
+    }
+
+    #[tokio::test]
+    async fn fails_on_malformed_responses() {
+        let server = MockServer::start(vec![Response::json(r#"{"images":"#)]).await;
+        let bing = Bing::new().unwrap().with_base_url(&server.base_url());
+
+        let result = bing.image_of_the_day(&Market::EnglishUS).await;
+
+        assert!(matches!(result, Err(ImageOfTheDayError::RequestError(_))), "{:?}", result.err());
+    }
+
+    /// Contents of a small JPEG file, as far as the signature check is concerned.
+    pub(crate) const JPEG: &[u8] = &[0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x10, b'J', b'F', b'I', b'F', 0x00];
+
+    #[test]
+    fn reports_client_build_failures() {
+        // native-tls doesn't support requiring TLS 1.3
+        let builder = reqwest::Client::builder().min_tls_version(reqwest::tls::Version::TLS_1_3);
+
+        let error = Bing::from_client_builder(builder).err().unwrap();
+
+        assert!(error.is_builder(), "{:?}", error);
+    }
+
+    #[tokio::test]
+    async fn consecutive_requests_reuse_the_connection() {
+        let server = MockServer::start(vec![
+            Response::json(ARCHIVE_RESPONSE),
+            Response::json(ARCHIVE_RESPONSE),
+            Response::jpeg(JPEG),
+            Response::jpeg(JPEG),
+            Response::jpeg(JPEG),
+        ]).await;
+        let bing = Bing::new().unwrap().with_base_url(&server.base_url());
+        let directory = tempfile::tempdir().unwrap();
+
+        bing.image_of_the_day(&Market::EnglishUS).await.unwrap();
+        bing.images_range(&Market::JapaneseJapan, 0, 8).await.unwrap();
+        for day in 0..3 {
+            let path = directory.path().join(format!("{}.jpg", day));
+            bing.download_with_retries(&server.url(&format!("/{}.jpg", day)), &path, None).await.unwrap();
+            assert_eq!(tokio::fs::read(&path).await.unwrap(), JPEG);
+        }
+
+        assert_eq!(server.requests().len(), 5);
+        assert_eq!(server.connections(), 1);
+    }
+
+    /// A larger image, downloaded in parts by the resumption tests.
+    fn large_jpeg() -> Vec<u8> {
+        let mut image = JPEG.to_vec();
+        image.extend((0..4096).map(|i| (i % 251) as u8));
+        image
+    }
+
+    fn partial(image: &[u8], start: usize) -> Response {
+        let range = format!("bytes {}-{}/{}", start, image.len() - 1, image.len());
+        Response::new("206 Partial Content", &[("content-type", "image/jpeg"), ("content-range", &range)], &image[start..])
+    }
+
+    fn range_header(request: &str) -> Option<String> {
+        request.lines()
+            .find_map(|line| line.to_ascii_lowercase().strip_prefix("range: ").map(str::to_owned))
+    }
+
+    #[tokio::test]
+    async fn resumes_partial_downloads() {
+        let image = large_jpeg();
+        let server = MockServer::start(vec![partial(&image, 1000)]).await;
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("image.jpg.part");
+        tokio::fs::write(&path, &image[..1000]).await.unwrap();
+
+        Bing::new().unwrap().download_into(&server.url("/image.jpg"), &path, None).await.unwrap();
+
+        assert_eq!(range_header(&server.requests()[0]).as_deref(), Some("bytes=1000-"));
+        assert_eq!(tokio::fs::read(&path).await.unwrap(), image);
+    }
+
+    #[tokio::test]
+    async fn resumes_interrupted_downloads() {
+        let image = large_jpeg();
+        let server = MockServer::start(vec![Response::jpeg(&image).truncated(1500), partial(&image, 1500)]).await;
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("image.jpg.part");
+
+        let progress = Arc::new(Mutex::new(Vec::new()));
+        let callback = {
+            let progress = progress.clone();
+            move |downloaded: u64, total: Option<u64>| progress.lock().unwrap().push((downloaded, total))
+        };
+        Bing::new().unwrap().download_with_retries(&server.url("/image.jpg"), &path, Some(&callback)).await.unwrap();
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(range_header(&requests[0]), None);
+        assert_eq!(range_header(&requests[1]).as_deref(), Some("bytes=1500-"));
+        assert_eq!(tokio::fs::read(&path).await.unwrap(), image);
+        let total = image.len() as u64;
+        assert_eq!(progress.lock().unwrap().last(), Some(&(total, Some(total))));
+    }
+
+    #[tokio::test]
+    async fn restarts_when_the_range_is_ignored() {
+        let image = large_jpeg();
+        let server = MockServer::start(vec![Response::jpeg(&image)]).await;
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("image.jpg.part");
+        tokio::fs::write(&path, b"stale partial download").await.unwrap();
+
+        Bing::new().unwrap().download_into(&server.url("/image.jpg"), &path, None).await.unwrap();
+
+        assert_eq!(range_header(&server.requests()[0]).as_deref(), Some("bytes=22-"));
+        assert_eq!(tokio::fs::read(&path).await.unwrap(), image);
+    }
+
+    #[tokio::test]
+    async fn restarts_when_the_range_is_not_satisfiable() {
+        let image = large_jpeg();
+        let server = MockServer::start(vec![
+            Response::new("416 Range Not Satisfiable", &[("content-range", "bytes */4107")], b""),
+            Response::jpeg(&image),
+        ]).await;
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("image.jpg.part");
+        // longer than the image, as if left behind by a different picture
+        tokio::fs::write(&path, vec![0; 8192]).await.unwrap();
+
+        Bing::new().unwrap().with_download_attempts(1).download_with_retries(&server.url("/image.jpg"), &path, None).await.unwrap();
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(range_header(&requests[0]).as_deref(), Some("bytes=8192-"));
+        assert_eq!(range_header(&requests[1]), None);
+        assert_eq!(tokio::fs::read(&path).await.unwrap(), image);
+    }
+
+    #[test]
+    fn archive_queries_include_the_market() {
+        for (market, expected) in [(Market::DanishDenmark, "da-DK"), (Market::EnglishUS, "en-US"), (Market::JapaneseJapan, "ja-JP")] {
+            let query = archive_query(&market, 2, 3);
+            assert_eq!(query, [
+                ("format", "js".to_owned()),
+                ("idx", "2".to_owned()),
+                ("n", "3".to_owned()),
+                ("mkt", expected.to_owned()),
+            ]);
+        }
+    }
+
+    fn image(url: &str, url_base: &str, title: &str) -> BingImage {
+        BingImage {
+            start_date: "20231005".to_owned(),
+            full_start_date: "202310050700".to_owned(),
+            end_date: "20231006".to_owned(),
+            url: url.to_owned(),
+            url_base: url_base.to_owned(),
+            title: title.to_owned(),
+            copyright: String::new(),
+            copyright_link: String::new(),
+            wp: true,
+            hsh: String::new(),
+            market: Market::EnglishUS,
+            base_url: BING_BASE_URL.to_owned(),
+        }
+    }
+
+    #[test]
+    fn writes_escaped_xmp_sidecars() {
+        let mut image = image("/a_1920x1080.jpg", "/th?id=OHR.A&B", "Fish & Chips <\"Brighton\">");
+        image.copyright = "© Jane O'Brien\u{7}".to_owned();
+
+        assert_eq!(image.to_xmp(), concat!(
+            "<?xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>\n",
+            "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\">\n",
+            " <rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">\n",
+            "  <rdf:Description rdf:about=\"\" xmlns:dc=\"http://purl.org/dc/elements/1.1/\">\n",
+            "   <dc:title><rdf:Alt><rdf:li xml:lang=\"x-default\">Fish &amp; Chips &lt;&quot;Brighton&quot;&gt;</rdf:li></rdf:Alt></dc:title>\n",
+            "   <dc:rights><rdf:Alt><rdf:li xml:lang=\"x-default\">© Jane O&apos;Brien</rdf:li></rdf:Alt></dc:rights>\n",
+            "   <dc:date><rdf:Seq><rdf:li>2023-10-05</rdf:li></rdf:Seq></dc:date>\n",
+            "   <dc:source>https://www.bing.com/th?id=OHR.A&amp;B_UHD.jpg</dc:source>\n",
+            "  </rdf:Description>\n",
+            " </rdf:RDF>\n",
+            "</x:xmpmeta>\n",
+            "<?xpacket end=\"w\"?>\n",
+        ));
+    }
+
+    #[test]
+    fn xmp_sidecars_are_well_formed() {
+        let xmp = image("/a_1920x1080.jpg", "/a", "<dc:title>&</x:xmpmeta>").to_xmp();
+        // every element opened is closed again in reverse order
+        let mut open = Vec::new();
+        for tag in xmp.split('<').skip(1).map(|tag| tag.split('>').next().unwrap()) {
+            if tag.starts_with('?') {
+                continue;
+            }
+            let name = tag.trim_start_matches('/').split(' ').next().unwrap();
+            if tag.starts_with('/') {
+                assert_eq!(open.pop(), Some(name), "{}", xmp);
+            } else {
+                open.push(name);
+            }
+        }
+        assert!(open.is_empty(), "{}", xmp);
+    }
+
+    #[test]
+    fn sanitizes_file_names() {
+        for (title, expected) in [
+            ("Lighthouse", "Lighthouse"),
+            ("Guiding light", "Guiding light"),
+            ("AC/DC", "AC_DC"),
+            ("../../etc/passwd", "_.._etc_passwd"),
+            ("C:\\Windows", "C__Windows"),
+            ("What's this?", "What's this_"),
+            ("\"Quoted\" <tags> *stars* a|b", "_Quoted_ _tags_ _stars_ a_b"),
+            ("Line\nbreak\ttab\0nul", "Line_break_tab_nul"),
+            ("Leuchtturm in Westerhever", "Leuchtturm in Westerhever"),
+            ("灯台と海 🌊", "灯台と海 🌊"),
+            ("Ński/Łódź", "Ński_Łódź"),
+            ("  Padded. ", "Padded"),
+            ("...", ""),
+        ] {
+            assert_eq!(sanitize_file_name(title), expected, "{:?}", title);
+        }
+    }
+
+    #[test]
+    fn file_names_keep_the_date_prefix() {
+        for (title, expected) in [
+            ("Lighthouse", "20231005-en-US-Lighthouse.jpg"),
+            ("Sunrise/sunset: a view?", "20231005-en-US-Sunrise_sunset_ a view_.jpg"),
+            (" 灯台 ", "20231005-en-US-灯台.jpg"),
+        ] {
+            let name = image("/a_1920x1080.jpg", "/a", title).get_image_file_name();
+            assert_eq!(name, expected);
+            assert!(crate::manager::is_bing_picture_name(&name), "{}", name);
+            assert_eq!(Path::new(&name).components().count(), 1, "{}", name);
+        }
+    }
+
+    #[test]
+    fn truncates_long_file_names() {
+        const PREFIX: &str = "20231005-en-US-";
+        let ascii = "a".repeat(300);
+        let cjk = "灯".repeat(100);
+        let emoji = "🌊".repeat(100);
+        let mixed = format!("a{}", cjk);
+        let spaced = format!("{} {}", "a".repeat(180), "b".repeat(100));
+        for (title, expected) in [
+            ("Lighthouse", "Lighthouse".to_owned()),
+            (&ascii, "a".repeat(181)),
+            (&cjk, "灯".repeat(60)),
+            (&emoji, "🌊".repeat(45)),
+            (&mixed, format!("a{}", "灯".repeat(60))),
+            (&spaced, "a".repeat(180)),
+        ] {
+            let name = image("/a_1920x1080.jpg", "/a", title).get_image_file_name();
+            assert!(name.len() <= MAX_FILE_NAME_BYTES, "{} is {} bytes", name, name.len());
+            assert_eq!(name, format!("{}{}.jpg", PREFIX, expected));
+            let partial_name = partial_path(Path::new(&name)).file_name().unwrap().len();
+            assert!(partial_name <= 255, "partial file name of {} is {} bytes", name, partial_name);
+        }
+    }
+
+    #[test]
+    fn strips_resolutions() {
+        for (url_base, expected) in [
+            ("/th?id=OHR.Lighthouse_EN-US1234", "/th?id=OHR.Lighthouse_EN-US1234"),
+            ("/th?id=OHR.Lighthouse_EN-US1234_UHD", "/th?id=OHR.Lighthouse_EN-US1234"),
+            ("/th?id=OHR.Lighthouse_EN-US1234_UHD.jpg", "/th?id=OHR.Lighthouse_EN-US1234"),
+            ("/th?id=OHR.Lighthouse_EN-US1234_1920x1080", "/th?id=OHR.Lighthouse_EN-US1234"),
+            ("/th?id=OHR.Lighthouse_EN-US1234_1920x1080.jpg", "/th?id=OHR.Lighthouse_EN-US1234"),
+            ("/th?id=OHR.Lighthouse_EN-US1234_1366x768_UHD", "/th?id=OHR.Lighthouse_EN-US1234_1366x768"),
+            ("/th?id=OHR.Lighthouse_EN-US1234.jpg", "/th?id=OHR.Lighthouse_EN-US1234.jpg"),
+            ("/th?id=OHR.Lighthouse_EN-US1234_x1080", "/th?id=OHR.Lighthouse_EN-US1234_x1080"),
+            ("/th?id=OHR.Lighthouse_EN-US1234_1920x", "/th?id=OHR.Lighthouse_EN-US1234_1920x"),
+            ("/th?id=OHR.Lighthouse_EN-US1234_Fox", "/th?id=OHR.Lighthouse_EN-US1234_Fox"),
+            ("/th?id=OHR.Lighthouse_EN-US1234_uhd", "/th?id=OHR.Lighthouse_EN-US1234_uhd"),
+            ("/az/hprichbg/rb/Lighthouse", "/az/hprichbg/rb/Lighthouse"),
+        ] {
+            assert_eq!(strip_resolution(url_base), expected, "{}", url_base);
+        }
+    }
+
+    #[test]
+    fn image_urls_have_a_single_resolution() {
+        for url_base in [
+            "/th?id=OHR.Lighthouse_EN-US1234",
+            "/th?id=OHR.Lighthouse_EN-US1234_UHD",
+            "/th?id=OHR.Lighthouse_EN-US1234_1920x1080",
+            "/th?id=OHR.Lighthouse_EN-US1234_1366x768.jpg",
+        ] {
+            let image = image("/th?id=OHR.Lighthouse_EN-US1234_1920x1080.jpg", url_base, "Lighthouse");
+            for (resolution, expected) in [
+                (Resolution::UHD, "https://www.bing.com/th?id=OHR.Lighthouse_EN-US1234_UHD.jpg"),
+                (Resolution::R1920x1080, "https://www.bing.com/th?id=OHR.Lighthouse_EN-US1234_1920x1080.jpg"),
+                (Resolution::R1280x720, "https://www.bing.com/th?id=OHR.Lighthouse_EN-US1234_1280x720.jpg"),
+            ] {
+                assert_eq!(image.get_image_url_with_resolution(resolution).unwrap().as_str(), expected, "{}", url_base);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn cached_pictures_are_downloaded_again_only_when_forced() {
+        let server = MockServer::start(vec![Response::jpeg(JPEG)]).await;
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("20231005-en-US-Lighthouse.jpg");
+        tokio::fs::write(&path, b"cached").await.unwrap();
+        let mut image = image("/a_1920x1080.jpg", "/a", "Lighthouse");
+        image.base_url = server.base_url();
+        let bing = Bing::new().unwrap().with_download_attempts(1);
+
+        bing.download_image(&image, &path, false, None).await.unwrap();
+        assert_eq!(tokio::fs::read(&path).await.unwrap(), b"cached");
+        assert!(server.requests().is_empty());
+
+        bing.download_image(&image, &path, true, None).await.unwrap();
+        assert_eq!(tokio::fs::read(&path).await.unwrap(), JPEG);
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn failed_forced_downloads_keep_the_cached_picture() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("20231005-en-US-Lighthouse.jpg");
+        tokio::fs::write(&path, b"cached").await.unwrap();
+        // nothing listens on port 1, so any download attempt fails right away
+        let image = image("https://127.0.0.1:1/a_1920x1080.jpg", "https://127.0.0.1:1/a", "Lighthouse");
+        let bing = Bing::new().unwrap().with_download_attempts(1);
+
+        let result = bing.download_image(&image, &path, true, None).await;
+        assert!(matches!(result, Err(DownloadImageError::RequestError(_))), "{:?}", result);
+        assert_eq!(tokio::fs::read(&path).await.unwrap(), b"cached");
+        let mut entries = tokio::fs::read_dir(directory.path()).await.unwrap();
+        let mut names = Vec::new();
+        while let Some(entry) = entries.next_entry().await.unwrap() {
+            names.push(entry.file_name());
+        }
+        assert_eq!(names, ["20231005-en-US-Lighthouse.jpg"]);
+    }
+
+    #[tokio::test]
+    async fn failed_downloads_are_resumed_by_the_next_attempt() {
+        let picture = large_jpeg();
+        let server = MockServer::start(vec![Response::jpeg(&picture).truncated(1500), partial(&picture, 1500)]).await;
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("20231005-en-US-Lighthouse.jpg");
+        let mut image = image("/a_1920x1080.jpg", "/a", "Lighthouse");
+        image.base_url = server.base_url();
+        let counter = Arc::new(AtomicU64::new(0));
+        let bing = Bing::new().unwrap().with_download_attempts(1).with_download_counter(counter.clone());
+
+        let result = bing.download_image_with_resolution(&image, Resolution::UHD, &path, false, None).await;
+        assert!(matches!(result, Err(DownloadImageError::RequestError(_))), "{:?}", result);
+        assert!(!tokio::fs::try_exists(&path).await.unwrap());
+        assert_eq!(tokio::fs::read(partial_path(&path)).await.unwrap(), &picture[..1500]);
+
+        bing.download_image_with_resolution(&image, Resolution::UHD, &path, false, None).await.unwrap();
+
+        let requests = server.requests();
+        assert_eq!(range_header(&requests[1]).as_deref(), Some("bytes=1500-"));
+        assert_eq!(tokio::fs::read(&path).await.unwrap(), picture);
+        assert!(!tokio::fs::try_exists(partial_path(&path)).await.unwrap());
+        // every byte was downloaded once
+        assert_eq!(counter.load(Ordering::Relaxed), picture.len() as u64);
+    }
+
+    #[tokio::test]
+    async fn concurrent_downloads_of_the_same_picture_leave_one_valid_file() {
+        let picture = large_jpeg();
+        let server = MockServer::start(vec![Response::jpeg(&picture), Response::jpeg(&picture)]).await;
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("20231005-en-US-Lighthouse.jpg");
+        let mut image = image("/a_1920x1080.jpg", "/a", "Lighthouse");
+        image.base_url = server.base_url();
+        let bing = Bing::new().unwrap();
+
+        let (first, second) = tokio::join!(
+            bing.download_image(&image, &path, true, None),
+            bing.download_image(&image, &path, true, None),
+        );
+
+        first.unwrap();
+        second.unwrap();
+        assert_eq!(server.requests().len(), 2);
+        assert_eq!(tokio::fs::read(&path).await.unwrap(), picture);
+        let mut entries = tokio::fs::read_dir(directory.path()).await.unwrap();
+        let mut names = Vec::new();
+        while let Some(entry) = entries.next_entry().await.unwrap() {
+            names.push(entry.file_name());
+        }
+        assert_eq!(names, ["20231005-en-US-Lighthouse.jpg"]);
+    }
+
+    #[tokio::test]
+    async fn concurrent_downloads_create_the_missing_directory() {
+        let server = MockServer::start(vec![Response::jpeg(JPEG), Response::jpeg(JPEG), Response::jpeg(JPEG)]).await;
+        let directory = tempfile::tempdir().unwrap();
+        let pictures = directory.path().join("pictures").join("bing");
+        let bing = Bing::new().unwrap();
+        let download = |title: &str| {
+            let mut image = image(&format!("/{}_1920x1080.jpg", title), &format!("/{}", title), title);
+            image.base_url = server.base_url();
+            let path = pictures.join(format!("{}.jpg", title));
+            let bing = &bing;
+            async move { bing.download_image(&image, &path, false, None).await }
+        };
+
+        let (lighthouse, glacier, dunes) = tokio::join!(download("Lighthouse"), download("Glacier"), download("Dunes"));
+
+        lighthouse.unwrap();
+        glacier.unwrap();
+        dunes.unwrap();
+        for title in ["Lighthouse", "Glacier", "Dunes"] {
+            assert_eq!(tokio::fs::read(pictures.join(format!("{}.jpg", title))).await.unwrap(), JPEG);
+        }
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_the_plain_url_when_the_resolution_is_missing() {
+        let server = MockServer::start(vec![
+            Response::json(ARCHIVE_RESPONSE),
+            Response::new("404 Not Found", &[], b""),
+            Response::jpeg(JPEG),
+        ]).await;
+        let bing = Bing::new().unwrap().with_base_url(&server.base_url());
+        let image = bing.image_of_the_day(&Market::EnglishUS).await.unwrap();
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("20231005-en-US-Lighthouse.jpg");
+
+        bing.download_image(&image, &path, false, None).await.unwrap();
+
+        assert_eq!(tokio::fs::read(&path).await.unwrap(), JPEG);
+        let requests = server.requests();
+        assert_eq!(requests.len(), 3, "{:?}", requests);
+        assert!(requests[1].starts_with("GET /th?id=OHR.Lighthouse_EN-US1234_UHD.jpg "), "{}", requests[1]);
+        assert!(requests[2].starts_with("GET /th?id=OHR.Lighthouse_EN-US1234_1920x1080.jpg&rf=LaDigue_1920x1080.jpg&pid=hp "), "{}", requests[2]);
+    }
 }
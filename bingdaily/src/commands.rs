@@ -0,0 +1,285 @@
+//! Command line subcommands that run instead of the D-Bus service.
+
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+use log::error;
+use tokio::sync::Semaphore;
+use tokio::time::error::Elapsed;
+use tokio::time::timeout;
+use zbus::export::futures_util::future::join_all;
+use crate::bing::{Bing, BingImage, ImageOfTheDayError, Market, Resolution};
+use crate::manager::{Configuration, ConfigurationError, Manager};
+
+/// Maximum number of concurrent requests made by [markets].
+const MARKETS_CONCURRENCY: usize = 4;
+const MARKETS_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Prints the effective configuration.
+pub fn print_config(configuration: &Configuration) {
+    println!("{}", format_config(configuration));
+}
+
+fn format_config(configuration: &Configuration) -> String {
+    format!("{:#?}\nResolved pictures directory: {}", configuration, configuration.get_pictures_directory().display())
+}
+
+/// Validates the configuration file at `path`, or the default location, printing every problem
+/// found. Returns whether the configuration is valid.
+pub async fn validate_config(path: Option<&Path>) -> bool {
+    let path = path.map_or_else(Configuration::get_config_file, Path::to_path_buf);
+    let problems = match config_problems(&path).await {
+        Ok(problems) => problems,
+        Err(err) => {
+            println!("{}", err);
+            return false;
+        }
+    };
+
+    for problem in &problems {
+        println!("{}", problem);
+    }
+    if problems.is_empty() {
+        println!("{} is valid", path.display());
+    }
+    problems.is_empty()
+}
+
+/// Describes every problem with the configuration file at `path`: unknown keys, invalid values
+/// and an unwritable pictures directory. Fails only if the file can't be read or parsed at all.
+async fn config_problems(path: &Path) -> Result<Vec<String>, ConfigurationError> {
+    let (configuration, mut problems) = Configuration::parse_leniently_from(path).await?;
+    problems.extend(configuration.problems());
+    let pictures_directory = configuration.get_pictures_directory();
+    if let Err(err) = check_writable(&pictures_directory).await {
+        problems.push(format!("pictures_directory {} is not writable: {}", pictures_directory.display(), err));
+    }
+    Ok(problems)
+}
+
+/// Checks that files can be created in `directory`, or in its closest existing ancestor if it
+/// doesn't exist yet.
+async fn check_writable(directory: &Path) -> io::Result<()> {
+    let mut directory = directory;
+    while !tokio::fs::try_exists(directory).await? {
+        match directory.parent() {
+            Some(parent) => directory = parent,
+            None => return Ok(()),
+        }
+    }
+
+    let probe = directory.join(format!(".bingdaily-write-test-{}", std::process::id()));
+    tokio::fs::write(&probe, []).await?;
+    tokio::fs::remove_file(&probe).await
+}
+
+/// Prints the metadata of the market's image of the day, optionally downloading it to `path` in
+/// the given resolution.
+pub async fn show(bing: &Bing, market: &Market, resolution: Resolution, path: Option<&Path>) {
+    let image = match bing.image_of_the_day(market).await {
+        Ok(image) => image,
+        Err(err) => {
+            error!("Failed to query image of the day: {}", err);
+            return;
+        }
+    };
+    println!("{}", format_image(&image, resolution));
+
+    if let Some(path) = path {
+        match bing.download_image_with_resolution(&image, resolution, path, true, None).await {
+            Ok(()) => println!("Downloaded to {}", path.display()),
+            Err(err) => error!("Failed to download image: {}", err),
+        }
+    }
+}
+
+/// Polls the configured market's picture once, for running from a systemd timer or cron instead
+/// of as a service, and prints its path. Returns whether a picture was obtained.
+pub async fn once(manager: &Manager) -> bool {
+    let market = manager.configuration().market.clone();
+    match manager.poll_picture(&market, false).await {
+        (Some(picture), _) => {
+            println!("{}", picture.path.display());
+            true
+        }
+        (None, _) => {
+            error!("Failed to get a picture for {}", market);
+            false
+        }
+    }
+}
+
+fn format_image(image: &BingImage, resolution: Resolution) -> String {
+    let date = match image.get_start_date() {
+        Ok(date) => date.format("%Y-%m-%d").to_string(),
+        Err(err) => format!("unknown ({})", err),
+    };
+    let url = match image.get_image_url_with_resolution(resolution) {
+        Ok(url) => url.to_string(),
+        Err(err) => format!("invalid ({})", err),
+    };
+    format!(
+        "Title: {}\nCopyright: {}\nDate: {}\nMarket: {}\nURL: {}",
+        image.title(), image.copyright(), date, image.market(), url,
+    )
+}
+
+/// Prints the current image of every known market.
+pub async fn markets(bing: &Bing) {
+    let semaphore = &Semaphore::new(MARKETS_CONCURRENCY);
+    let results = join_all(Market::ALL.iter().map(|market| async move {
+        let _permit = semaphore.acquire().await.expect("semaphore closed");
+        let result = timeout(MARKETS_TIMEOUT, bing.image_of_the_day(market)).await;
+        (market, result)
+    })).await;
+
+    for (market, result) in results {
+        println!("{}", format_market(market, result));
+    }
+}
+
+fn format_market(market: &Market, result: Result<Result<BingImage, ImageOfTheDayError>, Elapsed>) -> String {
+    let image = match result {
+        Ok(Ok(image)) => image,
+        Ok(Err(err)) => return format!("{}\terror: {}", market, err),
+        Err(_) => return format!("{}\terror: timed out", market),
+    };
+    match image.get_image_url() {
+        Ok(url) => format!("{}\t{}\t{}", market, image.title(), url),
+        Err(err) => format!("{}\t{}\terror: {}", market, image.title(), err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn problems_of(contents: &str) -> Vec<String> {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("config.toml");
+        tokio::fs::write(&path, contents.replace("$DIR", &directory.path().to_string_lossy())).await.unwrap();
+        config_problems(&path).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn reports_every_problem() {
+        let problems = problems_of(concat!(
+            "market = \"english\"\n",
+            "refresh_hour = 24\n",
+            "max_cached_images = \"lots\"\n",
+            // a file where a directory is expected can't be written to, even by root
+            "pictures_directory = \"$DIR/config.toml/pictures\"\n",
+        )).await;
+
+        assert_eq!(problems.len(), 4, "{:#?}", problems);
+        assert!(problems[0].starts_with("market: "), "{}", problems[0]);
+        assert!(problems[0].contains("english"), "{}", problems[0]);
+        assert!(problems[1].starts_with("max_cached_images: "), "{}", problems[1]);
+        assert_eq!(problems[2], "refresh_hour must be between 0 and 23, got 24");
+        assert!(problems[3].contains("is not writable"), "{}", problems[3]);
+    }
+
+    #[tokio::test]
+    async fn reports_unknown_keys() {
+        let problems = problems_of(concat!(
+            "markt = \"ja-JP\"\n",
+            "pictures_directory = \"$DIR\"\n",
+            "[maintenance]\n",
+            "enabled = true\n",
+            "hours = 4\n",
+            "interval_days = 0\n",
+        )).await;
+
+        assert_eq!(problems.len(), 3, "{:#?}", problems);
+        assert!(problems[0].starts_with("maintenance.hours: unknown field `hours`"), "{}", problems[0]);
+        assert!(problems[1].starts_with("markt: unknown field `markt`"), "{}", problems[1]);
+        assert_eq!(problems[2], "maintenance.interval_days must be at least 1");
+    }
+
+    #[tokio::test]
+    async fn accepts_valid_configuration() {
+        let problems = problems_of(concat!(
+            "market = \"ja-JP\"\n",
+            "pictures_directory = \"$DIR/pictures\"\n",
+            "mode = { best = { count = 3 } }\n",
+            "[maintenance]\n",
+            "enabled = true\n",
+        )).await;
+
+        assert_eq!(problems, Vec::<String>::new());
+    }
+
+    #[test]
+    fn prints_configured_values() {
+        let configuration: Configuration = toml::from_str("market = \"ja-JP\"\npictures_directory = \"/srv/bing\"").unwrap();
+
+        let printed = format_config(&configuration);
+
+        assert!(printed.contains("market: ja-JP"), "{}", printed);
+        assert!(printed.contains("pictures_directory: Some(\n        \"/srv/bing\""), "{}", printed);
+        assert!(printed.ends_with("Resolved pictures directory: /srv/bing"), "{}", printed);
+    }
+
+    #[tokio::test]
+    async fn prints_overridden_values() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("config.toml");
+        tokio::fs::write(&path, "market = \"ja-JP\"\nrefresh_hour = 6\n").await.unwrap();
+        let mut configuration = Configuration::load_from(&path).await.unwrap();
+        // overridden at runtime the way `SetMarket` does, after the file was read
+        configuration.market = Market::EnglishGB;
+        configuration.pictures_directory = Some(directory.path().join("pictures").to_string_lossy().into_owned());
+
+        let printed = format_config(&configuration);
+
+        assert!(printed.contains("market: en-GB"), "{}", printed);
+        assert!(!printed.contains("ja-JP"), "{}", printed);
+        assert!(printed.contains("refresh_hour: Some(\n        6,"), "{}", printed);
+        let resolved = format!("Resolved pictures directory: {}", directory.path().join("pictures").display());
+        assert!(printed.ends_with(&resolved), "{}", printed);
+    }
+
+    fn image() -> BingImage {
+        serde_json::from_str(r#"{
+            "startdate": "20231005",
+            "fullstartdate": "202310050700",
+            "enddate": "20231006",
+            "url": "/th?id=OHR.Lighthouse_EN-US1234_1920x1080.jpg",
+            "urlbase": "/th?id=OHR.Lighthouse_EN-US1234",
+            "copyright": "Lighthouse on the coast (© Photographer)",
+            "title": "Guiding light"
+        }"#).unwrap()
+    }
+
+    #[test]
+    fn formats_image_metadata() {
+        assert_eq!(format_image(&image(), Resolution::R1920x1080), concat!(
+            "Title: Guiding light\n",
+            "Copyright: Lighthouse on the coast (© Photographer)\n",
+            "Date: 2023-10-05\n",
+            "Market: en-US\n",
+            "URL: https://www.bing.com/th?id=OHR.Lighthouse_EN-US1234_1920x1080.jpg",
+        ));
+    }
+
+    #[tokio::test]
+    async fn formats_market_lines() {
+        let elapsed = timeout(Duration::ZERO, std::future::pending::<()>()).await.unwrap_err();
+        for (result, expected) in [
+            (Ok(Ok(image())), "en-GB\tGuiding light\thttps://www.bing.com/th?id=OHR.Lighthouse_EN-US1234_UHD.jpg"),
+            (Ok(Err(ImageOfTheDayError::NoImagesFound)), "en-GB\terror: Bing API did not return any images"),
+            (Err(elapsed), "en-GB\terror: timed out"),
+        ] {
+            assert_eq!(format_market(&Market::EnglishGB, result), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn fails_on_invalid_toml() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("config.toml");
+        tokio::fs::write(&path, "market = ").await.unwrap();
+
+        assert!(matches!(config_problems(&path).await, Err(ConfigurationError::ParseError(..))));
+    }
+}
@@ -0,0 +1,28 @@
+//! Downloading and caching of Bing's daily pictures, for embedding in applications without the
+//! D-Bus service provided by the `bingdaily` binary.
+//!
+//! [bing::Bing] queries Bing's API and downloads images, while [manager::Manager] adds caching,
+//! the configured [manager::Configuration] and selection of the picture to show on top of it.
+//!
+//! ```no_run
+//! use bingdaily::bing::Bing;
+//! use bingdaily::manager::{Configuration, Manager};
+//!
+//! # async fn example() -> Result<(), Box<dyn std::error::Error>> {
+//! let configuration = Configuration::load().await?;
+//! let market = configuration.market.clone();
+//! let manager = Manager::new(Bing::new()?, configuration);
+//!
+//! // downloads today's picture into the pictures directory, unless it is cached already
+//! let (picture, next_poll) = manager.poll_picture(&market, false).await;
+//! if let Some(picture) = picture {
+//!     println!("{} at {}, next poll at {}", picture.title, picture.path.display(), next_poll);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod bing;
+pub mod manager;
+pub mod metrics;
+pub use bingcommon::state;
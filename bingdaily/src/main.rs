@@ -6,80 +6,237 @@
 //! property.
 //!
 //! Subscribe to the `PropertiesChanged` signal to get notified when a new picture has become
-//! available locally.
+//! available locally, or to the `PictureChanged` signal to receive its title and copyright along
+//! with it. The `DownloadProgress` signal reports the progress of downloading a new picture.
+//!
+//! Run with `--once` to download today's picture, print its path and exit, e.g. from a systemd
+//! timer instead of running as a service.
 
-pub mod bing;
-pub mod manager;
+mod commands;
+mod network;
+mod notifications;
 
+use bingdaily::{bing, manager, state};
+use std::collections::HashMap;
 use std::env;
-use std::path::PathBuf;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::str::FromStr;
 use std::sync::Arc;
-use chrono::{Duration, Utc};
-use log::{debug, error};
-use tokio::sync::Mutex;
-use zbus::{ConnectionBuilder, dbus_interface};
+use chrono::{DateTime, Duration, Utc};
+use log::{debug, error, info, warn};
+use thiserror::Error;
+use tokio::{join, select};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, watch, Mutex};
+use tokio::time::Instant;
+use zbus::{Connection, dbus_interface, fdo, InterfaceRef, SignalContext};
 use tokio_walltime::sleep_until;
-use crate::bing::Bing;
-use crate::manager::{Configuration, LocalPicture, Manager, predict_next_poll_time};
+use crate::bing::{Bing, Market};
+use crate::manager::{CatchUp, Clock, Configuration, is_past_refresh_hour, LocalPicture, Manager, PolledPicture, predict_next_poll_time};
+use crate::notifications::Notifier;
+
+/// None of the candidates for the fallback picture exist.
+#[derive(Debug, Error)]
+#[error("No fallback picture found, tried {0:?}")]
+struct FallbackPictureNotFound(Vec<PathBuf>);
 
-async fn locate_bliss() -> Option<PathBuf> {
-    let possibilities = [
-        PathBuf::from("/usr/lib/bingdaily/bliss.jpg"),
-        env::current_dir().unwrap().join("bliss.jpg"),
-        env::current_exe().unwrap().join("bliss.jpg"),
-    ];
+async fn locate_bliss(configuration: &Configuration) -> Result<PathBuf, FallbackPictureNotFound> {
+    let mut possibilities = Vec::new();
+    if let Some(fallback_picture) = configuration.fallback_picture.as_ref() {
+        possibilities.push(PathBuf::from(fallback_picture));
+    }
+    if let Some(base_dirs) = directories::BaseDirs::new() {
+        possibilities.push(base_dirs.data_dir().join("bingdaily").join("bliss.jpg"));
+    }
+    possibilities.push(PathBuf::from("/usr/lib/bingdaily/bliss.jpg"));
+    if let Ok(current_dir) = env::current_dir() {
+        possibilities.push(current_dir.join("bliss.jpg"));
+    }
+    if let Some(exe_dir) = env::current_exe().ok().as_deref().and_then(Path::parent) {
+        possibilities.push(exe_dir.join("bliss.jpg"));
+    }
 
-    for possibility in possibilities {
-        if let Ok(true) = tokio::fs::try_exists(&possibility).await {
-            return Some(possibility);
+    for possibility in &possibilities {
+        if let Ok(true) = tokio::fs::try_exists(possibility).await {
+            return Ok(possibility.clone());
         }
     }
 
-    None
+    Err(FallbackPictureNotFound(possibilities))
+}
+
+/// Minimum time between two `DownloadProgress` signals.
+const PROGRESS_INTERVAL: std::time::Duration = std::time::Duration::from_millis(250);
+
+/// Number of attempts made at connecting to the session bus on startup.
+const CONNECT_ATTEMPTS: u32 = 8;
+
+/// Connects to the session bus, backing off exponentially between attempts as the bus may not be
+/// ready yet when started early during login.
+async fn connect_session_bus() -> zbus::Result<Connection> {
+    let mut backoff = std::time::Duration::from_millis(500);
+    let mut attempt = 1;
+    loop {
+        match Connection::session().await {
+            Err(err) if attempt < CONNECT_ATTEMPTS => {
+                warn!("Failed to connect to the session bus: {}, retrying in {:?}", err, backoff);
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() {
     env_logger::builder().target(env_logger::Target::Stdout).init();
 
-    let bliss = locate_bliss().await.expect("locate fallback picture");
+    let configuration = Configuration::load().await;
+    if env::args().skip(1).any(|arg| arg == "--print-config") {
+        match configuration {
+            Ok(configuration) => commands::print_config(&configuration),
+            Err(err) => {
+                // printing the defaults instead would misrepresent what is in effect
+                eprintln!("{}", err);
+                exit(1);
+            }
+        }
+        return;
+    }
+    let configuration = configuration.unwrap_or_else(|err| {
+        warn!("{}, using default configuration", err);
+        Configuration::default()
+    });
+    if let Some("validate-config") = env::args().nth(1).as_deref() {
+        let path = env::args().nth(2).map(PathBuf::from);
+        let valid = commands::validate_config(path.as_deref()).await;
+        exit(if valid { 0 } else { 1 });
+    }
+
+    let bing = match Bing::new() {
+        Ok(bing) => bing
+            .with_download_attempts(configuration.get_download_attempts())
+            .with_timeout(configuration.get_request_timeout()),
+        Err(err) => {
+            error!("Failed to create HTTP client: {}", err);
+            exit(1);
+        }
+    };
+    match env::args().nth(1).as_deref() {
+        Some("markets") => {
+            commands::markets(&bing).await;
+            return;
+        }
+        Some("show") => {
+            let path = env::args().nth(2).map(PathBuf::from);
+            commands::show(&bing, &configuration.market, configuration.resolution.without_display_size(), path.as_deref()).await;
+            return;
+        }
+        _ => {}
+    }
+    if env::args().skip(1).any(|arg| arg == "--once") {
+        let manager = Manager::new(bing, configuration);
+        let downloaded = commands::once(&manager).await;
+        exit(if downloaded { 0 } else { 1 });
+    }
+
+    let bliss = match locate_bliss(&configuration).await {
+        Ok(bliss) => bliss,
+        Err(err) => {
+            error!("{}", err);
+            exit(1);
+        }
+    };
     let bliss = bliss.to_string_lossy().to_string();
-    let current_picture = Arc::new(Mutex::new(bliss));
+    let current_picture = Arc::new(Mutex::new(bliss.clone()));
+    let current_title = Arc::new(Mutex::new(String::new()));
+    let current_copyright = Arc::new(Mutex::new(String::new()));
+    let current_market = Arc::new(Mutex::new(String::new()));
+    let market_pictures = Arc::new(Mutex::new(HashMap::new()));
 
-    let bing = Bing::new();
-    let configuration = Configuration::default();
-    let manager = Manager::new(bing, configuration);
+    let (progress_tx, mut progress_rx) = watch::channel((0, None));
+    let manager = Arc::new(Manager::new(bing, configuration)
+        .with_download_progress(Arc::new(move |downloaded, total| {
+            let _ = progress_tx.send((downloaded, total));
+        })));
 
     // lock while looking for local pictures
     let mut picture = current_picture.lock().await;
 
+    // refresh requests received over d-bus, carrying whether the download should be forced
+    let (refresh_tx, mut refresh_rx) = mpsc::channel(1);
+    // replacements of a deleted current picture, whose property changes are signalled here
+    let (replaced_tx, mut replaced_rx) = mpsc::channel(1);
+
     // start d-bus service as soon as possible
-    let iface = BingDaily { current_picture: current_picture.clone() };
-    let connection = ConnectionBuilder::session().unwrap()
-        .name("net.boothwhack.BingDaily1").unwrap()
-        .serve_at("/net/boothwhack/BingDaily1", iface).unwrap()
-        .build()
-        .await.unwrap();
-
-    let mut wait_until = match manager.poll_local_picture().await {
-        Some(LocalPicture::Today(path)) => {
-            debug!("Located today's picture at {}", path.display());
-            // today's picture is already available, all is good
-            *picture = path.to_string_lossy().to_string();
-            predict_next_poll_time()
-        }
-        Some(LocalPicture::Yesterday(path)) => {
-            debug!("Located yesterday's picture at {}, refreshing in 1 minute", path.display());
-            // yesterday's picture is available, use it and download today's in a minute to avoid
-            // yesterday's picture appearing for only a split second
-            *picture = path.to_string_lossy().to_string();
-            Utc::now() + Duration::minutes(1)
-        }
-        // no local picture available, attempt to download one and fall back to bliss
-        None => match manager.poll_picture().await {
-            (Some(path), wait_until) => {
-                debug!("Downloaded initial picture: {}", path.display());
-                *picture = path.to_string_lossy().to_string();
+    let iface = BingDaily {
+        current_picture: current_picture.clone(),
+        current_title: current_title.clone(),
+        current_copyright: current_copyright.clone(),
+        current_market: current_market.clone(),
+        market_pictures: market_pictures.clone(),
+        manager: manager.clone(),
+        refresh: refresh_tx.clone(),
+        replaced: replaced_tx,
+        bliss,
+        config_path: Configuration::get_config_file(),
+        max_image_data_size: MAX_IMAGE_DATA_SIZE,
+    };
+    let dbus_path = manager.configuration().get_dbus_path().to_owned();
+    let connection = match connect_session_bus().await {
+        Ok(connection) => connection,
+        Err(err) => {
+            error!("Failed to connect to the session bus: {}", err);
+            exit(1);
+        }
+    };
+    if let Err(err) = connection.object_server().at(dbus_path.as_str(), iface).await {
+        error!("Failed to serve interface at {}: {}", dbus_path, err);
+        exit(1);
+    }
+    let dbus_name = manager.configuration().get_dbus_name().to_owned();
+    if let Err(err) = connection.request_name(dbus_name.as_str()).await {
+        error!("Failed to acquire D-Bus name {}: {}", dbus_name, err);
+        exit(1);
+    }
+
+    let notifier = match Notifier::new(&connection).await {
+        Ok(notifier) => Some(notifier),
+        Err(err) => {
+            warn!("Failed to connect to notification service: {}", err);
+            None
+        }
+    };
+
+    if manager.configuration().verify_cache_on_start {
+        let removed = manager.verify_cache().await;
+        debug!("Verified cache, removed {} corrupt pictures", removed);
+    }
+
+    let market = manager.configuration().market.clone();
+    let local = manager.poll_local_picture(&market).await;
+    let mut wait_until = match plan_startup(local.as_ref(), &manager.configuration(), manager.now()) {
+        Startup::Show(local, wait_until) => {
+            *current_market.lock().await = market.to_string();
+            *current_title.lock().await = sanitize_dbus_string(&local.title);
+            *current_copyright.lock().await = sanitize_dbus_string(&local.copyright);
+            *picture = local.path.to_string_lossy().to_string();
+            wait_until
+        }
+        // no usable local picture available or today's picture was missed, attempt to download
+        // one right away and fall back to bliss
+        Startup::Download => match manager.poll_picture(&market, false).await {
+            (Some(polled), wait_until) => {
+                debug!("Downloaded initial picture: {}", polled.path.display());
+                *picture = polled.path.to_string_lossy().to_string();
+                *current_title.lock().await = sanitize_dbus_string(&polled.title);
+                *current_copyright.lock().await = sanitize_dbus_string(&polled.copyright);
+                *current_market.lock().await = polled.market.as_ref().map(Market::to_string).unwrap_or_default();
+                notify_new_picture(notifier.as_ref(), &manager, &polled).await;
                 wait_until
             }
             (None, wait_until) => {
@@ -92,40 +249,796 @@ async fn main() {
     // drop lock to allow dbus property to be read
     drop(picture);
 
+    let iface_ref = match connection.object_server().interface::<_, BingDaily>(dbus_path.as_str()).await {
+        Ok(iface_ref) => iface_ref,
+        Err(err) => {
+            error!("Failed to look up served interface: {}", err);
+            exit(1);
+        }
+    };
+
+    if poll_market_pictures(&manager, &market_pictures, false).await {
+        let iface = iface_ref.get().await;
+        if let Err(err) = iface.market_pictures_changed(iface_ref.signal_context()).await {
+            error!("Error while notifying property changed: {}", err);
+        }
+    }
+
+    let poll_loop = async {
+        loop {
+            debug!("Sleeping until {}", wait_until);
+            let force = select! {
+                // a poll after a clock change computes the next poll time from the corrected clock.
+                // Resuming from suspend is detected as such a change rather than through logind's
+                // PrepareForSleep signal, so a picture gone stale while suspended is replaced right
+                // away without depending on logind
+                _ = sleep_until_or_clock_jump(manager.clock(), wait_until, CLOCK_CHECK_INTERVAL) => false,
+                Some(force) = refresh_rx.recv() => {
+                    debug!("Refresh requested (force: {})", force);
+                    force
+                }
+            };
+
+            let market = manager.configuration().market.clone();
+            let (polled, next) = manager.poll_picture(&market, force).await;
+            wait_until = next;
+
+            if let Some(polled) = polled {
+                let mut picture = current_picture.lock().await;
+                *picture = polled.path.to_string_lossy().to_string();
+                drop(picture);
+                *current_title.lock().await = sanitize_dbus_string(&polled.title);
+                *current_copyright.lock().await = sanitize_dbus_string(&polled.copyright);
+                *current_market.lock().await = polled.market.as_ref().map(Market::to_string).unwrap_or_default();
+                notify_new_picture(notifier.as_ref(), &manager, &polled).await;
+
+                notify_current_picture_changed(&iface_ref).await;
+                let path = polled.path.to_string_lossy();
+                let (title, copyright) = (sanitize_dbus_string(&polled.title), sanitize_dbus_string(&polled.copyright));
+                if let Err(err) = BingDaily::picture_changed(iface_ref.signal_context(), &path, &title, &copyright).await {
+                    error!("Error while emitting picture changed signal: {}", err);
+                }
+            }
+
+            if poll_market_pictures(&manager, &market_pictures, force).await {
+                let iface = iface_ref.get().await;
+                if let Err(err) = iface.market_pictures_changed(iface_ref.signal_context()).await {
+                    error!("Error while notifying property changed: {}", err);
+                }
+            }
+        }
+    };
+
+    let watch_network = async {
+        if manager.configuration().poll_on_network_up {
+            network::watch_network(refresh_tx).await;
+        }
+    };
+
+    let serve_metrics = async {
+        if let Some(address) = manager.configuration().get_metrics_address() {
+            if let Err(err) = bingdaily::metrics::serve(address, manager.metrics()).await {
+                error!("Failed to serve metrics at {}: {}", address, err);
+            }
+        }
+    };
+
+    let notify_replaced = async {
+        while replaced_rx.recv().await.is_some() {
+            notify_current_picture_changed(&iface_ref).await;
+        }
+    };
+
+    let report_progress = async {
+        // the initial download completed before the signal could be emitted
+        progress_rx.borrow_and_update();
+        while progress_rx.changed().await.is_ok() {
+            let (downloaded, total) = *progress_rx.borrow_and_update();
+            if let Err(err) = BingDaily::download_progress(iface_ref.signal_context(), downloaded, total.unwrap_or(0)).await {
+                error!("Error while emitting download progress: {}", err);
+            }
+            // coalesce the many small chunks of a download into a few signals per second
+            tokio::time::sleep(PROGRESS_INTERVAL).await;
+        }
+    };
+
+    select! {
+        _ = async { join!(poll_loop, run_maintenance(&manager, |time| sleep_until_or_clock_jump(manager.clock(), time, CLOCK_CHECK_INTERVAL)), watch_network, serve_metrics, report_progress, notify_replaced) } => {}
+        _ = shutdown_signal() => {
+            info!("Shutting down");
+            if let Err(err) = connection.release_name(dbus_name.as_str()).await {
+                warn!("Failed to release D-Bus name {}: {}", dbus_name, err);
+            }
+        }
+    }
+}
+
+/// Resolves once the process is asked to terminate by SIGTERM or SIGINT.
+async fn shutdown_signal() {
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(err) => {
+            warn!("Failed to listen for SIGTERM: {}", err);
+            let _ = tokio::signal::ctrl_c().await;
+            return;
+        }
+    };
+    select! {
+        _ = terminate.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+}
+
+/// Interval at which the wall clock is compared against monotonic time while sleeping.
+const CLOCK_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// Sleeps until the wall clock reaches `time`. Returns `true` early if `clock` jumped
+/// significantly relative to monotonic time, checked every `check_interval`, e.g. after an NTP
+/// correction or resuming from suspend, in which case `time` was likely computed from a wrong
+/// clock and must be recomputed.
+async fn sleep_until_or_clock_jump(clock: &dyn Clock, time: DateTime<Utc>, check_interval: std::time::Duration) -> bool {
+    let clock_jump_threshold = Duration::minutes(5);
+
+    let sleep = sleep_until(time);
+    tokio::pin!(sleep);
+    let mut interval = tokio::time::interval(check_interval);
+    interval.tick().await;
+
+    let mut wall_clock = clock.now();
+    let mut monotonic = Instant::now();
     loop {
-        debug!("Sleeping until {}", wait_until);
-        if let Err(err) = sleep_until(wait_until).await {
-            error!("Error while sleeping: {}", err);
+        select! {
+            result = &mut sleep => {
+                if let Err(err) = result {
+                    error!("Error while sleeping: {}", err);
+                }
+                return false;
+            }
+            _ = interval.tick() => {
+                let elapsed = Duration::from_std(monotonic.elapsed()).unwrap_or_else(|_| Duration::zero());
+                let drift = clock.now() - wall_clock - elapsed;
+                if drift.num_seconds().abs() > clock_jump_threshold.num_seconds() {
+                    warn!("Wall clock jumped by {} seconds, rescheduling", drift.num_seconds());
+                    return true;
+                }
+                wall_clock = clock.now();
+                monotonic = Instant::now();
+            }
         }
+    }
+}
 
-        let (path, next) = manager.poll_picture().await;
-        wait_until = next;
+/// Runs cache maintenance on its own schedule for as long as it is enabled, waiting for each run
+/// with `sleep`, usually [sleep_until_or_clock_jump].
+async fn run_maintenance<F: Future<Output = bool>>(manager: &Manager, mut sleep: impl FnMut(DateTime<Utc>) -> F) {
+    let mut next_run = manager.configuration().maintenance.next_run(manager.now());
+    loop {
+        debug!("Next cache maintenance at {}", next_run);
+        if sleep(next_run).await {
+            next_run = manager.configuration().maintenance.next_run(manager.now());
+            continue;
+        }
+
+        let configuration = manager.configuration();
+        if configuration.maintenance.enabled {
+            let report = manager.run_maintenance().await;
+            debug!("Cache maintenance pruned {} and removed {} corrupt pictures and {} stale partial files", report.pruned, report.corrupt, report.partial);
+        }
+        next_run += Duration::days(configuration.maintenance.interval_days.into());
+    }
+}
 
-        if let Some(path) = path {
-            let mut picture = current_picture.lock().await;
-            *picture = path.to_string_lossy().to_string();
-            drop(picture);
+/// Announces a newly polled picture if notifications are enabled.
+async fn notify_new_picture(notifier: Option<&Notifier>, manager: &Manager, polled: &PolledPicture) {
+    if let Some(notifier) = notifier {
+        if manager.configuration().notify_new_pictures {
+            notifier.notify_new_picture(polled).await;
+        }
+    }
+}
 
-            let iface_ref = connection.object_server().interface::<_, BingDaily>("/net/boothwhack/BingDaily1")
-                .await.unwrap();
-            let iface = iface_ref.get_mut().await;
-            if let Err(err) = iface.current_picture_changed(iface_ref.signal_context()).await {
-                error!("Error while notifying property changed: {}", err);
+/// How the service starts, given the picture found locally.
+enum Startup<'a> {
+    /// Show the local picture until the first poll at the given time.
+    Show(&'a PolledPicture, DateTime<Utc>),
+    /// Download today's picture before showing anything.
+    Download,
+}
+
+fn plan_startup<'a>(local: Option<&'a LocalPicture>, configuration: &Configuration, now: DateTime<Utc>) -> Startup<'a> {
+    let refresh_hour = configuration.get_refresh_hour();
+    match local {
+        Some(LocalPicture::Today(local)) => {
+            debug!("Located today's picture at {}", local.path.display());
+            // today's picture is already available, all is good
+            Startup::Show(local, predict_next_poll_time(now, refresh_hour))
+        }
+        Some(LocalPicture::Yesterday(local)) if !is_past_refresh_hour(now, refresh_hour) => {
+            debug!("Located yesterday's picture at {}, which is still current", local.path.display());
+            Startup::Show(local, predict_next_poll_time(now, refresh_hour))
+        }
+        Some(LocalPicture::Yesterday(local)) => match configuration.get_catch_up() {
+            CatchUp::Download => Startup::Download,
+            CatchUp::ShowYesterday => {
+                debug!("Located yesterday's picture at {}, refreshing right away", local.path.display());
+                Startup::Show(local, now)
+            }
+            CatchUp::Delay => {
+                debug!("Located yesterday's picture at {}, refreshing in 1 minute", local.path.display());
+                Startup::Show(local, now + Duration::minutes(1))
             }
+        },
+        Some(LocalPicture::Older(local)) if configuration.show_stale_picture_on_start => {
+            debug!("Located stale picture at {}, refreshing right away", local.path.display());
+            // show the stale picture for continuity, the poll loop downloads today's right away
+            Startup::Show(local, now)
         }
+        _ => Startup::Download,
     }
 }
 
+/// Signals that the current picture and its details changed.
+async fn notify_current_picture_changed(iface_ref: &InterfaceRef<BingDaily>) {
+    let iface = iface_ref.get().await;
+    if let Err(err) = iface.current_picture_changed(iface_ref.signal_context()).await {
+        error!("Error while notifying property changed: {}", err);
+    }
+    if let Err(err) = iface.current_thumbnail_changed(iface_ref.signal_context()).await {
+        error!("Error while notifying property changed: {}", err);
+    }
+    if let Err(err) = iface.title_changed(iface_ref.signal_context()).await {
+        error!("Error while notifying property changed: {}", err);
+    }
+    if let Err(err) = iface.copyright_changed(iface_ref.signal_context()).await {
+        error!("Error while notifying property changed: {}", err);
+    }
+    if let Err(err) = iface.current_market_changed(iface_ref.signal_context()).await {
+        error!("Error while notifying property changed: {}", err);
+    }
+}
+
+/// Polls the pictures of the additional markets, returning whether any of them changed.
+async fn poll_market_pictures(manager: &Manager, market_pictures: &Mutex<HashMap<String, String>>, force: bool) -> bool {
+    let mut changed = false;
+    for market in &manager.configuration().additional_markets {
+        if let (Some(polled), _) = manager.poll_picture(market, force).await {
+            let path = polled.path.to_string_lossy().to_string();
+            let mut pictures = market_pictures.lock().await;
+            if pictures.get(&market.to_string()) != Some(&path) {
+                pictures.insert(market.to_string(), path);
+                changed = true;
+            }
+        }
+    }
+    changed
+}
+
+/// Largest picture served by `GetImageData`, keeping well clear of D-Bus' message size limit.
+const MAX_IMAGE_DATA_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Strips NULs and other control characters, which D-Bus strings must not contain, from text
+/// received from Bing.
+fn sanitize_dbus_string(s: &str) -> String {
+    s.chars().filter(|c| !c.is_control()).collect()
+}
+
 struct BingDaily {
-    // todo: include metadata
     current_picture: Arc<Mutex<String>>,
+    current_title: Arc<Mutex<String>>,
+    current_copyright: Arc<Mutex<String>>,
+    current_market: Arc<Mutex<String>>,
+    market_pictures: Arc<Mutex<HashMap<String, String>>>,
+    manager: Arc<Manager>,
+    refresh: mpsc::Sender<bool>,
+    /// Notified when the current picture is replaced by a fallback, as property getters can't
+    /// emit signals themselves.
+    replaced: mpsc::Sender<()>,
+    /// Picture shown when no downloaded picture is available.
+    bliss: String,
+    /// Configuration file reloaded by `ReloadConfig` and updated by `SetMarket`.
+    config_path: PathBuf,
+    /// Largest picture served by `GetImageData`, normally [MAX_IMAGE_DATA_SIZE].
+    max_image_data_size: u64,
 }
 
 #[dbus_interface(name = "net.boothwhack.BingDaily1")]
 impl BingDaily {
+    /// Path of the current picture. Should the file have been deleted, e.g. by external cache
+    /// cleanup, the configured market's newest cached picture or bliss is reported instead.
     #[dbus_interface(property)]
     async fn current_picture(&self) -> String {
-        let current_picture = self.current_picture.lock().await;
+        let mut current_picture = self.current_picture.lock().await;
+        if let Ok(false) = tokio::fs::try_exists(current_picture.as_str()).await {
+            let market = self.manager.configuration().market.clone();
+            let (fallback, title, copyright, market) = match self.manager.newest_cached_picture(&market).await {
+                Some(local) => (
+                    local.path.to_string_lossy().to_string(),
+                    sanitize_dbus_string(&local.title),
+                    sanitize_dbus_string(&local.copyright),
+                    market.to_string(),
+                ),
+                None => (self.bliss.clone(), String::new(), String::new(), String::new()),
+            };
+            warn!("Current picture {} no longer exists, falling back to {}", current_picture, fallback);
+            *current_picture = fallback;
+            *self.current_title.lock().await = title;
+            *self.current_copyright.lock().await = copyright;
+            *self.current_market.lock().await = market;
+            // a full channel already has a notification pending
+            let _ = self.replaced.try_send(());
+        }
         current_picture.clone()
     }
+
+    /// Path of a thumbnail of the current picture for previews, empty when thumbnails are
+    /// disabled.
+    #[dbus_interface(property)]
+    async fn current_thumbnail(&self) -> String {
+        let current_picture = PathBuf::from(self.current_picture().await);
+        match self.manager.thumbnail(&current_picture, false).await {
+            Some(thumbnail) => thumbnail.to_string_lossy().to_string(),
+            None => String::new(),
+        }
+    }
+
+    /// Title of the current picture, empty when unknown.
+    #[dbus_interface(property)]
+    async fn title(&self) -> String {
+        let current_title = self.current_title.lock().await;
+        current_title.clone()
+    }
+
+    /// Attribution of the current picture, empty when unknown.
+    #[dbus_interface(property)]
+    async fn copyright(&self) -> String {
+        let current_copyright = self.current_copyright.lock().await;
+        current_copyright.clone()
+    }
+
+    /// Market code the current picture was fetched for, empty when not from Bing.
+    #[dbus_interface(property)]
+    async fn current_market(&self) -> String {
+        let current_market = self.current_market.lock().await;
+        current_market.clone()
+    }
+
+    /// Pictures of the additional markets, keyed by market code.
+    #[dbus_interface(property)]
+    async fn market_pictures(&self) -> HashMap<String, String> {
+        let market_pictures = self.market_pictures.lock().await;
+        market_pictures.clone()
+    }
+
+    /// Reports the size of the largest display, used to select the downloaded resolution when it
+    /// is configured to be chosen automatically.
+    async fn set_display_size(&self, width: u32, height: u32) {
+        debug!("Display size reported as {}x{}", width, height);
+        self.manager.set_display_size(width, height);
+    }
+
+    /// Returns the contents of the current picture along with its MIME type, for clients unable
+    /// to read the picture's path, e.g. sandboxed applications. Fails for pictures larger than
+    /// the service's limit, normally [MAX_IMAGE_DATA_SIZE], in which case the path must be used
+    /// instead.
+    async fn get_image_data(&self) -> fdo::Result<(Vec<u8>, String)> {
+        let path = PathBuf::from(self.current_picture.lock().await.clone());
+
+        let metadata = tokio::fs::metadata(&path).await
+            .map_err(|err| fdo::Error::IOError(err.to_string()))?;
+        if metadata.len() > self.max_image_data_size {
+            return Err(fdo::Error::LimitsExceeded(format!(
+                "Picture is {} bytes, exceeding the limit of {} bytes; read CurrentPicture's path instead",
+                metadata.len(), self.max_image_data_size,
+            )));
+        }
+
+        let data = tokio::fs::read(&path).await
+            .map_err(|err| fdo::Error::IOError(err.to_string()))?;
+        let mime_type = image::ImageFormat::from_path(&path)
+            .map(|format| format.to_mime_type())
+            .unwrap_or("application/octet-stream");
+        Ok((data, mime_type.to_owned()))
+    }
+
+    /// Loads the configuration file again and applies it from the next poll onwards, returning
+    /// the effective configuration. An invalid configuration is rejected and the current one kept.
+    async fn reload_config(&self) -> fdo::Result<String> {
+        let configuration = Configuration::load_from(&self.config_path).await
+            .map_err(|err| fdo::Error::Failed(err.to_string()))?;
+        debug!("Reloaded configuration: {:?}", configuration);
+        let effective = format!("{:#?}", configuration);
+        self.manager.set_configuration(configuration);
+        Ok(effective)
+    }
+
+    /// Switches to another market, e.g. `en-GB`, saving it to the configuration file and polling
+    /// the market's picture right away.
+    async fn set_market(&self, market: String) -> fdo::Result<()> {
+        let market = Market::from_str(&market)
+            .map_err(|err| fdo::Error::InvalidArgs(err.to_string()))?;
+        Configuration::persist_market_to(&self.config_path, &market).await
+            .map_err(|err| fdo::Error::Failed(err.to_string()))?;
+
+        debug!("Switching to market {}", market);
+        let mut configuration = (*self.manager.configuration()).clone();
+        configuration.market = market;
+        self.manager.set_configuration(configuration);
+
+        self.refresh.send(false).await
+            .map_err(|err| fdo::Error::Failed(err.to_string()))
+    }
+
+    /// Polls Bing for the picture of the day right away. When `force` is set, the picture is
+    /// downloaded again even if it has already been downloaded.
+    async fn refresh(&self, force: bool) -> fdo::Result<()> {
+        self.refresh.send(force).await
+            .map_err(|err| fdo::Error::Failed(err.to_string()))
+    }
+
+    /// Emitted when a new picture becomes available, carrying its path, title and copyright at
+    /// once rather than requiring them to be read from the properties.
+    #[dbus_interface(signal)]
+    async fn picture_changed(ctx: &SignalContext<'_>, path: &str, title: &str, copyright: &str) -> zbus::Result<()>;
+
+    /// Emitted while a picture is downloaded, carrying the bytes downloaded so far and the size
+    /// of the picture, which is 0 if unknown.
+    #[dbus_interface(signal)]
+    async fn download_progress(ctx: &SignalContext<'_>, downloaded: u64, total: u64) -> zbus::Result<()>;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::pin::Pin;
+    use crate::manager::{ManualClock, THUMBNAIL_WIDTH};
+    use super::*;
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    fn picture(name: &str) -> PolledPicture {
+        PolledPicture { path: PathBuf::from(name), title: String::new(), copyright: String::new(), market: Some(Market::EnglishUS) }
+    }
+
+    /// Time of the first poll when showing a local picture on startup, `None` when downloading first.
+    fn planned(local: Option<&LocalPicture>, configuration: &Configuration, now: &str) -> Option<DateTime<Utc>> {
+        match plan_startup(local, configuration, utc(now)) {
+            Startup::Show(_, wait_until) => Some(wait_until),
+            Startup::Download => None,
+        }
+    }
+
+    /// Service with the given current picture, caching pictures in `directory`, along with the
+    /// receiver of its replacement notifications.
+    fn service(directory: &Path, current_picture: &str) -> (BingDaily, mpsc::Receiver<()>) {
+        let configuration = Configuration {
+            pictures_directory: Some(directory.to_string_lossy().to_string()),
+            ..Configuration::default()
+        };
+        let (replaced_tx, replaced_rx) = mpsc::channel(1);
+        let service = BingDaily {
+            current_picture: Arc::new(Mutex::new(current_picture.to_owned())),
+            current_title: Arc::new(Mutex::new("Gone".to_owned())),
+            current_copyright: Arc::new(Mutex::new("© Gone".to_owned())),
+            current_market: Arc::new(Mutex::new("en-US".to_owned())),
+            market_pictures: Arc::new(Mutex::new(HashMap::new())),
+            manager: Arc::new(Manager::new(Bing::new().unwrap(), configuration)),
+            refresh: mpsc::channel(1).0,
+            replaced: replaced_tx,
+            bliss: "/usr/lib/bingdaily/bliss.jpg".to_owned(),
+            config_path: directory.join("config.toml"),
+            max_image_data_size: MAX_IMAGE_DATA_SIZE,
+        };
+        (service, replaced_rx)
+    }
+
+    #[tokio::test]
+    async fn deleted_pictures_fall_back_to_the_markets_newest() {
+        let directory = tempfile::tempdir().unwrap();
+        let fallback = directory.path().join("20231004-en-US-Lighthouse.jpg");
+        tokio::fs::write(&fallback, b"").await.unwrap();
+        tokio::fs::write(
+            fallback.with_extension("json"),
+            r#"{"startdate":"20231004","fullstartdate":"202310040700","enddate":"20231005","url":"/a.jpg","urlbase":"/a","title":"Lighthouse","copyright":"© Photographer"}"#,
+        ).await.unwrap();
+        // newer, but of another market
+        tokio::fs::write(directory.path().join("20231005-ja-JP-Temple.jpg"), b"").await.unwrap();
+        let deleted = directory.path().join("20231005-en-US-Gone.jpg");
+        let (service, mut replaced) = service(directory.path(), &deleted.to_string_lossy());
+
+        assert_eq!(service.current_picture().await, fallback.to_string_lossy());
+        assert_eq!(service.title().await, "Lighthouse");
+        assert_eq!(service.copyright().await, "© Photographer");
+        assert_eq!(service.current_market().await, "en-US");
+        assert_eq!(replaced.try_recv(), Ok(()));
+
+        // the fallback exists, so reading again changes nothing
+        assert_eq!(service.current_picture().await, fallback.to_string_lossy());
+        assert!(replaced.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn deleted_pictures_fall_back_to_bliss() {
+        let directory = tempfile::tempdir().unwrap();
+        tokio::fs::write(directory.path().join("20231005-ja-JP-Temple.jpg"), b"").await.unwrap();
+        let deleted = directory.path().join("20231005-en-US-Gone.jpg");
+        let (service, mut replaced) = service(directory.path(), &deleted.to_string_lossy());
+
+        assert_eq!(service.current_picture().await, "/usr/lib/bingdaily/bliss.jpg");
+        assert_eq!(service.title().await, "");
+        assert_eq!(service.copyright().await, "");
+        assert_eq!(service.current_market().await, "");
+        assert_eq!(replaced.try_recv(), Ok(()));
+    }
+
+    #[tokio::test]
+    async fn existing_pictures_are_kept() {
+        let directory = tempfile::tempdir().unwrap();
+        let current = directory.path().join("20231005-en-US-Current.jpg");
+        tokio::fs::write(&current, b"").await.unwrap();
+        tokio::fs::write(directory.path().join("20231006-en-US-Newer.jpg"), b"").await.unwrap();
+        let (service, mut replaced) = service(directory.path(), &current.to_string_lossy());
+
+        assert_eq!(service.current_picture().await, current.to_string_lossy());
+        assert_eq!(service.title().await, "Gone");
+        assert!(replaced.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn serves_the_current_pictures_data() {
+        let directory = tempfile::tempdir().unwrap();
+        let current = directory.path().join("20231005-en-US-Current.jpg");
+        let data: Vec<u8> = (0..2048).map(|i| (i % 251) as u8).collect();
+        tokio::fs::write(&current, &data).await.unwrap();
+        let (mut service, _) = service(directory.path(), &current.to_string_lossy());
+
+        assert_eq!(service.get_image_data().await.unwrap(), (data, "image/jpeg".to_owned()));
+
+        service.max_image_data_size = 2047;
+        let result = service.get_image_data().await;
+        assert!(matches!(&result, Err(fdo::Error::LimitsExceeded(message)) if message.starts_with("Picture is 2048 bytes")), "{:?}", result);
+    }
+
+    #[tokio::test]
+    async fn serves_thumbnails_of_the_current_picture() {
+        let directory = tempfile::tempdir().unwrap();
+        let current = directory.path().join("20231005-en-US-Current.jpg");
+        image::RgbImage::from_pixel(1280, 720, image::Rgb([40, 90, 160]))
+            .save_with_format(&current, image::ImageFormat::Jpeg)
+            .unwrap();
+        let (service, _) = service(directory.path(), &current.to_string_lossy());
+        assert_eq!(service.current_thumbnail().await, "");
+
+        service.manager.set_configuration(Configuration {
+            pictures_directory: Some(directory.path().to_string_lossy().to_string()),
+            thumbnails: true,
+            ..Configuration::default()
+        });
+        let thumbnail = PathBuf::from(service.current_thumbnail().await);
+
+        assert_eq!(thumbnail, directory.path().join(".thumbnails").join("20231005-en-US-Current.jpg"));
+        let reader = image::io::Reader::open(&thumbnail).unwrap().with_guessed_format().unwrap();
+        assert_eq!(reader.format(), Some(image::ImageFormat::Jpeg));
+        assert_eq!(reader.into_dimensions().unwrap(), (THUMBNAIL_WIDTH, 180));
+    }
+
+    #[tokio::test]
+    async fn reloading_applies_valid_configurations() {
+        let directory = tempfile::tempdir().unwrap();
+        let (service, _) = service(directory.path(), "");
+        tokio::fs::write(directory.path().join("config.toml"), "market = \"ja-JP\"\nrefresh_hour = 9\n").await.unwrap();
+
+        let effective = service.reload_config().await.unwrap();
+
+        assert!(effective.contains("market: ja-JP"), "{}", effective);
+        let configuration = service.manager.configuration();
+        assert_eq!(configuration.market, Market::JapaneseJapan);
+        assert_eq!(configuration.get_refresh_hour(), 9);
+    }
+
+    #[tokio::test]
+    async fn reloading_keeps_the_configuration_when_invalid() {
+        let directory = tempfile::tempdir().unwrap();
+        let (service, _) = service(directory.path(), "");
+        for contents in ["market = \"ja-JP\"\nrefresh_hour = 24\n", "market = "] {
+            tokio::fs::write(directory.path().join("config.toml"), contents).await.unwrap();
+
+            let result = service.reload_config().await;
+
+            assert!(matches!(result, Err(fdo::Error::Failed(_))), "{:?}", result);
+            let configuration = service.manager.configuration();
+            assert_eq!(configuration.market, Market::EnglishUS);
+            assert_eq!(configuration.pictures_directory.as_deref(), Some(&*directory.path().to_string_lossy()));
+        }
+    }
+
+    /// Runs the maintenance schedule against a manual clock for `runs` sleeps, each advancing the
+    /// clock to the time slept until, or jumping it to the time given. Returns the times slept
+    /// until along with the pictures left before each sleep.
+    async fn maintenance_schedule(configuration: Configuration, directory: &Path, now: &str, sleeps: &[Option<&str>]) -> Vec<(DateTime<Utc>, Vec<String>)> {
+        let clock = Arc::new(ManualClock::new(utc(now)));
+        let manager = Manager::new(Bing::new().unwrap(), configuration).with_clock(clock.clone());
+        let schedule = std::sync::Mutex::new(Vec::new());
+        let (done_tx, done_rx) = tokio::sync::oneshot::channel();
+        let mut done_tx = Some(done_tx);
+        let sleep = |time: DateTime<Utc>| -> Pin<Box<dyn Future<Output = bool>>> {
+            let mut names: Vec<_> = std::fs::read_dir(directory).unwrap()
+                .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+                .collect();
+            names.sort();
+            let mut schedule = schedule.lock().unwrap();
+            schedule.push((time, names));
+            let jump = match sleeps.get(schedule.len() - 1) {
+                Some(jump) => *jump,
+                None => {
+                    if let Some(done_tx) = done_tx.take() {
+                        let _ = done_tx.send(());
+                    }
+                    return Box::pin(std::future::pending());
+                }
+            };
+            clock.set(jump.map_or(time, utc));
+            Box::pin(std::future::ready(jump.is_some()))
+        };
+        select! {
+            _ = run_maintenance(&manager, sleep) => unreachable!(),
+            _ = done_rx => {}
+        }
+        schedule.into_inner().unwrap()
+    }
+
+    fn maintained(directory: &Path, enabled: bool) -> Configuration {
+        Configuration {
+            pictures_directory: Some(directory.to_string_lossy().to_string()),
+            maintenance: manager::MaintenanceConfiguration { enabled, hour: 3, interval_days: 2, retention_days: Some(30) },
+            ..Configuration::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn maintenance_runs_at_the_configured_hour_and_interval() {
+        let directory = tempfile::tempdir().unwrap();
+        tokio::fs::write(directory.path().join("20230801-en-US-Old.jpg"), b"").await.unwrap();
+
+        let schedule = maintenance_schedule(maintained(directory.path(), true), directory.path(), "2023-10-05T12:00:00Z", &[None, None]).await;
+
+        let times: Vec<_> = schedule.iter().map(|(time, _)| *time).collect();
+        assert_eq!(times, [utc("2023-10-06T03:00:00Z"), utc("2023-10-08T03:00:00Z"), utc("2023-10-10T03:00:00Z")]);
+        // nothing happens before the first run, which prunes the old picture
+        assert_eq!(schedule[0].1, ["20230801-en-US-Old.jpg"]);
+        assert_eq!(schedule[1].1, Vec::<String>::new());
+    }
+
+    #[tokio::test]
+    async fn maintenance_is_skipped_when_disabled() {
+        let directory = tempfile::tempdir().unwrap();
+        tokio::fs::write(directory.path().join("20230801-en-US-Old.jpg"), b"").await.unwrap();
+
+        let schedule = maintenance_schedule(maintained(directory.path(), false), directory.path(), "2023-10-05T12:00:00Z", &[None, None]).await;
+
+        assert_eq!(schedule.len(), 3);
+        assert_eq!(schedule[2], (utc("2023-10-10T03:00:00Z"), vec!["20230801-en-US-Old.jpg".to_owned()]));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn clock_jumps_end_the_sleep_early() {
+        let check_interval = std::time::Duration::from_secs(1);
+        let clock = ManualClock::new(Utc::now());
+        let time = Utc::now() + Duration::days(1);
+        let jump = async {
+            tokio::time::sleep(check_interval / 2).await;
+            clock.advance(Duration::minutes(10));
+        };
+
+        let (jumped, ()) = join!(tokio::time::timeout(check_interval * 2, sleep_until_or_clock_jump(&clock, time, check_interval)), jump);
+
+        assert_eq!(jumped, Ok(true));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sleeps_on_without_clock_jumps() {
+        let check_interval = std::time::Duration::from_secs(1);
+        let clock = ManualClock::new(Utc::now());
+        let time = Utc::now() + Duration::days(1);
+        // the clock keeps pace with monotonic time, give or take less than the threshold
+        let tick = async {
+            loop {
+                tokio::time::sleep(check_interval).await;
+                clock.advance(Duration::seconds(1));
+            }
+        };
+
+        let sleep = tokio::time::timeout(check_interval * 10, sleep_until_or_clock_jump(&clock, time, check_interval));
+        let jumped = select! {
+            jumped = sleep => jumped,
+            _ = tick => unreachable!(),
+        };
+
+        assert!(jumped.is_err(), "{:?}", jumped);
+    }
+
+    #[tokio::test]
+    async fn maintenance_is_rescheduled_after_clock_jumps() {
+        let directory = tempfile::tempdir().unwrap();
+
+        let schedule = maintenance_schedule(maintained(directory.path(), true), directory.path(), "2023-10-05T12:00:00Z", &[Some("2023-10-09T05:00:00Z")]).await;
+
+        let times: Vec<_> = schedule.iter().map(|(time, _)| *time).collect();
+        assert_eq!(times, [utc("2023-10-06T03:00:00Z"), utc("2023-10-10T03:00:00Z")]);
+    }
+
+    #[test]
+    fn catches_up_on_missed_pictures() {
+        let yesterday = LocalPicture::Yesterday(picture("yesterday.jpg"));
+        for (catch_up, expected) in [
+            (CatchUp::Download, None),
+            (CatchUp::ShowYesterday, Some("2023-10-05T12:00:00Z")),
+            (CatchUp::Delay, Some("2023-10-05T12:01:00Z")),
+        ] {
+            let configuration = Configuration { catch_up, ..Configuration::default() };
+            assert_eq!(planned(Some(&yesterday), &configuration, "2023-10-05T12:00:00Z"), expected.map(utc), "{:?}", catch_up);
+            // before the refresh hour, yesterday's picture is still the current one
+            assert_eq!(planned(Some(&yesterday), &configuration, "2023-10-05T06:00:00Z"), Some(utc("2023-10-05T07:00:00Z")), "{:?}", catch_up);
+        }
+    }
+
+    #[test]
+    fn shows_todays_picture_until_the_next_refresh() {
+        let today = LocalPicture::Today(picture("today.jpg"));
+        let configuration = Configuration::default();
+        assert_eq!(planned(Some(&today), &configuration, "2023-10-05T12:00:00Z"), Some(utc("2023-10-06T07:00:00Z")));
+        assert_eq!(planned(None, &configuration, "2023-10-05T12:00:00Z"), None);
+    }
+
+    #[test]
+    fn shows_stale_pictures_when_configured() {
+        let older = LocalPicture::Older(picture("older.jpg"));
+        assert_eq!(planned(Some(&older), &Configuration::default(), "2023-10-05T12:00:00Z"), None);
+        let configuration = Configuration { show_stale_picture_on_start: true, ..Configuration::default() };
+        assert_eq!(planned(Some(&older), &configuration, "2023-10-05T12:00:00Z"), Some(utc("2023-10-05T12:00:00Z")));
+    }
+
+    #[test]
+    fn parses_catch_up() {
+        for (value, expected) in [("download", CatchUp::Download), ("show_yesterday", CatchUp::ShowYesterday), ("delay", CatchUp::Delay)] {
+            let configuration: Configuration = toml::from_str(&format!("catch_up = \"{}\"", value)).unwrap();
+            assert_eq!(configuration.catch_up, expected);
+        }
+        assert!(toml::from_str::<Configuration>("delay_catch_up = true").is_err());
+
+        // the deprecated flag still loads, overriding the policy only when set
+        for (contents, expected) in [
+            ("skip_yesterday_fallback = true", CatchUp::Download),
+            ("skip_yesterday_fallback = true\ncatch_up = \"delay\"", CatchUp::Download),
+            ("skip_yesterday_fallback = false\ncatch_up = \"delay\"", CatchUp::Delay),
+        ] {
+            let configuration: Configuration = toml::from_str(contents).unwrap();
+            assert_eq!(configuration.get_catch_up(), expected, "{}", contents);
+        }
+    }
+
+    #[test]
+    fn skipping_yesterdays_fallback_downloads_right_away() {
+        let yesterday = LocalPicture::Yesterday(picture("yesterday.jpg"));
+        let configuration = Configuration { catch_up: CatchUp::ShowYesterday, skip_yesterday_fallback: true, ..Configuration::default() };
+        assert_eq!(planned(Some(&yesterday), &configuration, "2023-10-05T12:00:00Z"), None);
+    }
+
+    #[test]
+    fn sanitizes_dbus_strings() {
+        for (s, expected) in [
+            ("", ""),
+            ("Lighthouse", "Lighthouse"),
+            ("Leuchtturm in Westerhever, Deutschland", "Leuchtturm in Westerhever, Deutschland"),
+            ("© Photographer/Getty Images", "© Photographer/Getty Images"),
+            ("灯台 🌊", "灯台 🌊"),
+            ("Light\0house", "Lighthouse"),
+            ("\0\0", ""),
+            ("Light\nhouse\r\t", "Lighthouse"),
+            ("Light\u{1b}[31mhouse", "Light[31mhouse"),
+            ("Light\u{7f}house\u{85}", "Lighthouse"),
+        ] {
+            assert_eq!(sanitize_dbus_string(s), expected, "{:?}", s);
+        }
+    }
 }
@@ -1,20 +1,397 @@
-use std::ops::{Add, Deref};
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::ops::Add;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::sync::Mutex;
-use chrono::{DateTime, Duration, Timelike, Utc};
-use log::{debug, error, warn};
-use crate::bing::{Bing, BING_DATE_FORMAT, Market};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use chrono::{Datelike, DateTime, Duration, NaiveDate, Timelike, Utc};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+use thiserror::Error;
+use crate::bing::{Bing, BING_DATE_FORMAT, BingImage, DEFAULT_DOWNLOAD_ATTEMPTS, DEFAULT_REQUEST_TIMEOUT, ImageOfTheDayError, Market, MAX_HISTORY_DAYS, metadata_sidecar_path, PARTIAL_DOWNLOAD_SUFFIX, ProgressCallback, read_metadata_sidecar, Resolution, xmp_sidecar_path};
+use crate::metrics::Metrics;
+use crate::state::{load_state, persist_state, VersionedState, write_atomically};
 
-#[derive(Debug, Default)]
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WallpaperMode {
+    /// Always use the image of the day.
+    #[default]
+    Daily,
+    /// Use the highest scoring of the `count` most recent images, preferring images with long
+    /// titles, attribution and high resolution over e.g. occasional low quality graphics.
+    Best { count: u32 },
+    /// Use a random one of the `window` most recent images, picking a fresh one each day.
+    RandomArchive { window: u32 },
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResolutionPreference {
+    /// Always download the given resolution.
+    Fixed(Resolution),
+    /// Download the smallest resolution covering the largest display, as reported by the
+    /// wallpaper engine. Until a display size is reported, UHD is downloaded.
+    Auto,
+}
+
+impl Default for ResolutionPreference {
+    fn default() -> Self {
+        ResolutionPreference::Fixed(Resolution::UHD)
+    }
+}
+
+impl ResolutionPreference {
+    /// Resolution to download while no display size is known.
+    pub fn without_display_size(&self) -> Resolution {
+        match self {
+            ResolutionPreference::Fixed(resolution) => *resolution,
+            ResolutionPreference::Auto => Resolution::UHD,
+        }
+    }
+}
+
+/// Startup behavior when today's picture was published while the service wasn't running, so only
+/// yesterday's picture is cached.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CatchUp {
+    /// Download today's picture right away without showing yesterday's, falling back to bliss
+    /// only if the download fails.
+    #[default]
+    Download,
+    /// Show yesterday's picture while today's is downloaded right away.
+    ShowYesterday,
+    /// Show yesterday's picture for a minute before downloading today's, so that it doesn't
+    /// appear for only a split second on fast connections.
+    Delay,
+}
+
+/// A source of pictures, tried in the configured order until one yields a picture.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ImageSource {
+    /// Bing's picture of the day.
+    Classic,
+    /// A picture from a local directory, changing daily.
+    Local(PathBuf),
+}
+
+/// Decoder used for JPEG pictures by features processing the picture's pixels.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum JpegDecoder {
+    /// The `image` crate's decoder.
+    #[default]
+    Image,
+    /// zune-jpeg, which is considerably faster at decoding UHD pictures on weak CPUs. Requires the
+    /// `zune-jpeg` feature, otherwise the `image` crate's decoder is used.
+    Zune,
+}
+
+pub use bingcommon::{DEFAULT_DBUS_NAME, DEFAULT_DBUS_PATH};
+
+#[derive(Debug, Error)]
+pub enum ConfigurationError {
+    #[error("Failed to read configuration {0:?}: {1}")]
+    IoError(PathBuf, #[source] io::Error),
+    #[error("Failed to parse configuration {0:?}: {1}")]
+    ParseError(PathBuf, #[source] toml::de::Error),
+    #[error("Invalid configuration: {0}")]
+    Invalid(String),
+    #[error("Failed to edit configuration {0:?}: {1}")]
+    EditError(PathBuf, #[source] toml_edit::TomlError),
+    #[error("Failed to write configuration {0:?}: {1}")]
+    WriteError(PathBuf, #[source] io::Error),
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct Configuration {
     pub market: Market,
+    pub mode: WallpaperMode,
+    pub resolution: ResolutionPreference,
+    /// Further markets to download pictures for alongside `market`, allowing e.g. different
+    /// monitors to show different regions' pictures.
+    pub additional_markets: Vec<Market>,
     /// Alternative directory to store downloaded wallpaper files. Defaults to
     /// '$XDG_PICTURES_DIR/Bing Wallpapers' if available, otherwise the configuration directory.
     pub pictures_directory: Option<String>,
+    /// Verify the most recent cached pictures on startup, removing any that are corrupt so they
+    /// are downloaded again.
+    pub verify_cache_on_start: bool,
+    /// How to catch up on startup when only yesterday's picture is cached but today's has already
+    /// been published.
+    pub catch_up: CatchUp,
+    /// Deprecated alias of `catch_up = "download"`, kept so existing configurations still load.
+    /// Takes precedence over `catch_up` when set.
+    pub skip_yesterday_fallback: bool,
+    /// Show the newest cached picture on startup even when it is from before yesterday, while
+    /// today's picture is downloaded, rather than waiting for the download.
+    pub show_stale_picture_on_start: bool,
+    /// Poll right away when NetworkManager reports that connectivity returned, e.g. after
+    /// resuming a laptop, instead of waiting for the next scheduled poll.
+    pub poll_on_network_up: bool,
+    /// Write an XMP sidecar with the picture's title, copyright, date and source next to each
+    /// downloaded picture, for photo management tools.
+    pub xmp_sidecars: bool,
+    /// Send a desktop notification when a new picture becomes available.
+    pub notify_new_pictures: bool,
+    pub jpeg_decoder: JpegDecoder,
+    /// Write a small thumbnail of each picture for previews, exposed by the `CurrentThumbnail`
+    /// property.
+    pub thumbnails: bool,
+    /// Sources of pictures in order of priority, falling back to the next source when one fails.
+    /// Only Bing's picture of the day is used when empty.
+    pub sources: Vec<ImageSource>,
+    /// Number of attempts made at downloading a picture when the connection fails, backing off
+    /// exponentially between attempts. Defaults to [DEFAULT_DOWNLOAD_ATTEMPTS].
+    pub download_attempts: Option<u32>,
+    /// Seconds allowed for each request to Bing to complete, including downloading the picture.
+    /// Defaults to [DEFAULT_REQUEST_TIMEOUT].
+    pub request_timeout_secs: Option<u64>,
+    /// Store downloaded pictures in `YYYY/MM/` subdirectories of the pictures directory rather
+    /// than directly in it, keeping directory listings of large archives manageable. Pictures in
+    /// either layout are found regardless.
+    pub partition_by_date: bool,
+    /// Number of downloaded pictures to keep per market, removing the oldest ones after each
+    /// download. Pictures currently shown are never removed, even when they are among the oldest.
+    /// Pictures are kept indefinitely if not set.
+    pub max_cached_images: Option<usize>,
+    /// Markets to try in order when `market` has no images, which happens transiently for some
+    /// markets.
+    pub fallback_markets: Vec<Market>,
+    /// Well-known D-Bus name to serve, allowing several instances to run side by side. Defaults
+    /// to [DEFAULT_DBUS_NAME].
+    pub dbus_name: Option<String>,
+    /// Object path to serve the interface at. Defaults to [DEFAULT_DBUS_PATH].
+    pub dbus_path: Option<String>,
+    /// Local address, e.g. `127.0.0.1:9898`, to serve metrics in Prometheus' format at. Requires
+    /// the `metrics` feature.
+    pub metrics_address: Option<String>,
+    /// Hour of the day (UTC) at which Bing is assumed to publish the next picture and polling
+    /// happens. Defaults to [DEFAULT_REFRESH_HOUR].
+    pub refresh_hour: Option<u32>,
+    /// Picture shown until the first download succeeds, used instead of the bundled one.
+    /// Defaults to the first `bliss.jpg` found in `$XDG_DATA_HOME/bingdaily`,
+    /// `/usr/lib/bingdaily`, the working directory or the executable's directory.
+    pub fallback_picture: Option<String>,
+    pub maintenance: MaintenanceConfiguration,
+}
+
+/// Keeps the keys of `table` that deserialize into `T` on their own, describing the problem with
+/// every other key.
+fn valid_keys<T: DeserializeOwned>(table: toml::Table, prefix: &str, problems: &mut Vec<String>) -> toml::Table {
+    let mut valid = toml::Table::new();
+    for (key, value) in table {
+        let single = toml::Table::from_iter([(key.clone(), value.clone())]);
+        match T::deserialize(toml::Value::Table(single)) {
+            Ok(_) => {
+                valid.insert(key, value);
+            }
+            Err(err) => problems.push(format!("{}{}: {}", prefix, key, err.message())),
+        }
+    }
+    valid
+}
+
+/// Periodic cache maintenance, pruning old pictures and removing corrupt ones independently of
+/// polling for new pictures.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MaintenanceConfiguration {
+    pub enabled: bool,
+    /// Hour of the day (UTC) at which maintenance runs.
+    pub hour: u32,
+    /// Number of days between maintenance runs.
+    pub interval_days: u32,
+    /// Number of days pictures are kept for, counted from the picture's date. Pictures are kept
+    /// indefinitely if not set.
+    pub retention_days: Option<u32>,
+}
+
+impl Default for MaintenanceConfiguration {
+    fn default() -> Self {
+        MaintenanceConfiguration {
+            enabled: false,
+            hour: 3,
+            interval_days: 1,
+            retention_days: None,
+        }
+    }
+}
+
+impl MaintenanceConfiguration {
+    /// Time of the first maintenance run after `now`.
+    pub fn next_run(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        let today = now.date_naive().and_hms_opt(self.hour, 0, 0).unwrap().and_utc();
+        if today > now {
+            today
+        } else {
+            today + Duration::days(1)
+        }
+    }
+}
+
+/// Outcome of [Manager::run_maintenance].
+#[derive(Debug, Default)]
+pub struct MaintenanceReport {
+    pub pruned: usize,
+    pub corrupt: usize,
+    pub partial: usize,
 }
 
 impl Configuration {
+    /// Location of the configuration file, `$XDG_CONFIG_HOME/bingdaily/config.toml`.
+    pub fn get_config_file() -> PathBuf {
+        match directories::BaseDirs::new() {
+            Some(base_dirs) => base_dirs.config_dir().join("bingdaily").join("config.toml"),
+            None => PathBuf::from("~/.config/bingdaily/config.toml"),
+        }
+    }
+
+    /// Loads and validates the configuration file, using the default configuration when the
+    /// file doesn't exist.
+    pub async fn load() -> Result<Configuration, ConfigurationError> {
+        Self::load_from(&Self::get_config_file()).await
+    }
+
+    pub async fn load_from(path: &Path) -> Result<Configuration, ConfigurationError> {
+        let configuration = Self::parse_from(path).await?;
+        configuration.validate()?;
+        if configuration.skip_yesterday_fallback {
+            warn!("skip_yesterday_fallback is deprecated, use catch_up = \"download\" instead");
+        }
+        Ok(configuration)
+    }
+
+    /// Reads the configuration file without validating it.
+    pub async fn parse_from(path: &Path) -> Result<Configuration, ConfigurationError> {
+        let contents = match tokio::fs::read_to_string(path).await {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                debug!("No configuration at {}, using defaults", path.display());
+                return Ok(Configuration::default());
+            }
+            Err(err) => return Err(ConfigurationError::IoError(path.to_owned(), err)),
+        };
+
+        toml::from_str(&contents)
+            .map_err(|err| ConfigurationError::ParseError(path.to_owned(), err))
+    }
+
+    /// Reads the configuration file, skipping every key that is unknown or has an invalid value
+    /// rather than stopping at the first one. Returns the configuration made of the remaining keys
+    /// along with a description of each skipped key. Only fails if the file can't be read or
+    /// isn't valid TOML.
+    pub async fn parse_leniently_from(path: &Path) -> Result<(Configuration, Vec<String>), ConfigurationError> {
+        let contents = match tokio::fs::read_to_string(path).await {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok((Configuration::default(), Vec::new())),
+            Err(err) => return Err(ConfigurationError::IoError(path.to_owned(), err)),
+        };
+        let mut table: toml::Table = contents.parse()
+            .map_err(|err| ConfigurationError::ParseError(path.to_owned(), err))?;
+
+        let mut problems = Vec::new();
+        if let Some(toml::Value::Table(maintenance)) = table.get_mut("maintenance") {
+            *maintenance = valid_keys::<MaintenanceConfiguration>(std::mem::take(maintenance), "maintenance.", &mut problems);
+        }
+        let table = valid_keys::<Configuration>(table, "", &mut problems);
+        let configuration = Configuration::deserialize(toml::Value::Table(table))
+            .map_err(|err| ConfigurationError::ParseError(path.to_owned(), err))?;
+        Ok((configuration, problems))
+    }
+
+    /// Stores the market in the configuration file, preserving the rest of the file as is.
+    pub async fn persist_market(market: &Market) -> Result<(), ConfigurationError> {
+        Self::persist_market_to(&Self::get_config_file(), market).await
+    }
+
+    pub async fn persist_market_to(path: &Path, market: &Market) -> Result<(), ConfigurationError> {
+        let path = path.to_owned();
+        let contents = match tokio::fs::read_to_string(&path).await {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(ConfigurationError::IoError(path, err)),
+        };
+
+        let mut document: toml_edit::DocumentMut = contents.parse()
+            .map_err(|err| ConfigurationError::EditError(path.clone(), err))?;
+        document["market"] = toml_edit::value(market.to_string());
+
+        // a partial write would lose the user's whole configuration
+        write_atomically(&path, document.to_string().as_bytes()).await
+            .map_err(|err| ConfigurationError::WriteError(path, err))
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigurationError> {
+        match self.problems().as_slice() {
+            [] => Ok(()),
+            problems => Err(ConfigurationError::Invalid(problems.join("; "))),
+        }
+    }
+
+    /// Describes every problem with the configuration's values, naming the offending field.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if let WallpaperMode::Best { count } = self.mode {
+            if !(1..=MAX_HISTORY_DAYS).contains(&count) {
+                problems.push(format!("mode.best.count must be between 1 and {}, got {}", MAX_HISTORY_DAYS, count));
+            }
+        }
+        if let WallpaperMode::RandomArchive { window } = self.mode {
+            if !(1..=MAX_HISTORY_DAYS).contains(&window) {
+                problems.push(format!("mode.random_archive.window must be between 1 and {}, got {}", MAX_HISTORY_DAYS, window));
+            }
+        }
+        if let Some("") = self.pictures_directory.as_deref() {
+            problems.push("pictures_directory must not be empty".to_owned());
+        }
+        if self.get_refresh_hour() > 23 {
+            problems.push(format!("refresh_hour must be between 0 and 23, got {}", self.get_refresh_hour()));
+        }
+        if self.maintenance.hour > 23 {
+            problems.push(format!("maintenance.hour must be between 0 and 23, got {}", self.maintenance.hour));
+        }
+        if self.maintenance.interval_days == 0 {
+            problems.push("maintenance.interval_days must be at least 1".to_owned());
+        }
+        if let Some("") = self.fallback_picture.as_deref() {
+            problems.push("fallback_picture must not be empty".to_owned());
+        }
+        if let Some(0) = self.download_attempts {
+            problems.push("download_attempts must be at least 1".to_owned());
+        }
+        if let Some(0) = self.request_timeout_secs {
+            problems.push("request_timeout_secs must be at least 1".to_owned());
+        }
+        if let Some(0) = self.max_cached_images {
+            problems.push("max_cached_images must be at least 1".to_owned());
+        }
+        if let Some(0) = self.maintenance.retention_days {
+            problems.push("maintenance.retention_days must be at least 1".to_owned());
+        }
+        if let Some(address) = self.metrics_address.as_deref() {
+            match address.parse::<SocketAddr>() {
+                Ok(address) if !address.ip().is_loopback() => {
+                    problems.push(format!("metrics_address must be a loopback address, got {}", address));
+                }
+                Ok(_) => {}
+                Err(err) => problems.push(format!("metrics_address {} is not a valid address: {}", address, err)),
+            }
+        }
+        if let Err(err) = zbus::names::WellKnownName::try_from(self.get_dbus_name()) {
+            problems.push(format!("dbus_name is not a valid D-Bus name: {}", err));
+        }
+        if let Err(err) = zbus::zvariant::ObjectPath::try_from(self.get_dbus_path()) {
+            problems.push(format!("dbus_path is not a valid D-Bus object path: {}", err));
+        }
+        problems
+    }
+
     fn get_config_directory() -> PathBuf {
         match directories::BaseDirs::new() {
             Some(base_dirs) => base_dirs.config_dir().join(".."),
@@ -24,7 +401,40 @@ impl Configuration {
         }
     }
 
-    fn get_pictures_directory(&self) -> PathBuf {
+    pub fn get_dbus_name(&self) -> &str {
+        self.dbus_name.as_deref().unwrap_or(DEFAULT_DBUS_NAME)
+    }
+
+    pub fn get_dbus_path(&self) -> &str {
+        self.dbus_path.as_deref().unwrap_or(DEFAULT_DBUS_PATH)
+    }
+
+    pub fn get_metrics_address(&self) -> Option<SocketAddr> {
+        self.metrics_address.as_deref()?.parse().ok()
+    }
+
+    pub fn get_download_attempts(&self) -> u32 {
+        self.download_attempts.unwrap_or(DEFAULT_DOWNLOAD_ATTEMPTS)
+    }
+
+    pub fn get_request_timeout(&self) -> std::time::Duration {
+        self.request_timeout_secs.map_or(DEFAULT_REQUEST_TIMEOUT, std::time::Duration::from_secs)
+    }
+
+    /// Catch-up policy in effect, taking the deprecated `skip_yesterday_fallback` into account.
+    pub fn get_catch_up(&self) -> CatchUp {
+        if self.skip_yesterday_fallback {
+            CatchUp::Download
+        } else {
+            self.catch_up
+        }
+    }
+
+    pub fn get_refresh_hour(&self) -> u32 {
+        self.refresh_hour.unwrap_or(DEFAULT_REFRESH_HOUR)
+    }
+
+    pub fn get_pictures_directory(&self) -> PathBuf {
         if let Some(pictures_directory) = self.pictures_directory.as_ref() {
             return PathBuf::from(pictures_directory);
         }
@@ -40,90 +450,1571 @@ impl Configuration {
 
 pub struct Manager {
     bing: Bing,
-    configuration: Configuration,
+    configuration: RwLock<Arc<Configuration>>,
+    /// Size of the largest display, used by [ResolutionPreference::Auto].
+    display_size: Mutex<Option<(u32, u32)>>,
+    clock: Arc<dyn Clock>,
+    metrics: Metrics,
+    download_progress: Option<Arc<ProgressCallback>>,
+    /// Picture most recently handed out for each market, which pruning never removes as it may be
+    /// shown.
+    published: Mutex<HashMap<Market, PathBuf>>,
+}
+
+/// Source of the current time for scheduling, so it can be driven by something other than the
+/// system clock.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+/// [Clock] reading the system time.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// [Clock] standing still until set or advanced, for deterministic scheduling.
+pub struct ManualClock(Mutex<DateTime<Utc>>);
+
+impl ManualClock {
+    pub fn new(now: DateTime<Utc>) -> Self {
+        ManualClock(Mutex::new(now))
+    }
+
+    pub fn set(&self, now: DateTime<Utc>) {
+        *self.0.lock().unwrap() = now;
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.0.lock().unwrap() += duration;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.0.lock().unwrap()
+    }
+}
+
+/// Picture downloaded by [Manager::poll_picture].
+pub struct PolledPicture {
+    pub path: PathBuf,
+    pub title: String,
+    /// Attribution of the picture, empty when unknown.
+    pub copyright: String,
+    /// Market the picture was fetched for, `None` for pictures not from Bing.
+    pub market: Option<Market>,
 }
 
+/// Picture found by [Manager::poll_local_picture], along with the details recorded in its
+/// metadata sidecar, which are empty if the sidecar is missing.
 pub enum LocalPicture {
-    Today(PathBuf),
-    Yesterday(PathBuf),
+    Today(PolledPicture),
+    Yesterday(PolledPicture),
+    /// Most recent picture of the market, from before yesterday.
+    Older(PolledPicture),
 }
 
-pub fn predict_next_poll_time() -> DateTime<Utc> {
-    let now = Utc::now();
-    if now.hour() >= 7 {
-        now.date_naive().add(Duration::days(1)).and_hms_opt(7, 0, 0).unwrap().and_utc()
+/// Number of most recent cached pictures checked by [Manager::verify_cache], matching the number
+/// of days Bing keeps images available for.
+const VERIFIED_CACHE_PICTURES: usize = MAX_HISTORY_DAYS as usize;
+
+/// Whether the file name follows the `YYYYMMDD-` naming of downloaded pictures, excluding their
+/// XMP and metadata sidecars.
+pub fn is_bing_picture_name(name: &str) -> bool {
+    name.len() > 9
+        && name.as_bytes()[..8].iter().all(u8::is_ascii_digit)
+        && name.as_bytes()[8] == b'-'
+        && !name.ends_with(".xmp")
+        && !name.ends_with(".json")
+}
+
+/// Whether the file is a partial download or a temporary file written by [write_atomically].
+fn is_partial_file_name(name: &str) -> bool {
+    name.starts_with('.') && name.ends_with(PARTIAL_DOWNLOAD_SUFFIX)
+}
+
+/// Hours after which partial files left behind by failed downloads are swept by maintenance.
+const STALE_PARTIAL_FILE_HOURS: i64 = 24;
+
+/// Market code of a downloaded picture's file name, `YYYYMMDD-market-title.jpg`.
+fn picture_market(name: &str) -> &str {
+    // market codes are always `xx-YY`
+    name.get(9..14).unwrap_or_default()
+}
+
+/// Describes a cached picture of `market` using the details from its metadata sidecar.
+async fn local_picture(path: PathBuf, market: &Market) -> PolledPicture {
+    let image = read_metadata_sidecar(&path).await;
+    PolledPicture {
+        title: image.as_ref().map(|image| image.title().to_owned()).unwrap_or_default(),
+        copyright: image.as_ref().map(|image| image.copyright().to_owned()).unwrap_or_default(),
+        market: Some(market.clone()),
+        path,
+    }
+}
+
+/// Whether the directory name is a `YYYY` partition at `depth` 0 or a `MM` partition at depth 1.
+fn is_partition_name(name: &str, depth: usize) -> bool {
+    let digits = match depth {
+        0 => 4,
+        1 => 2,
+        _ => return false,
+    };
+    name.len() == digits && name.bytes().all(|byte| byte.is_ascii_digit())
+}
+
+/// Path of a downloaded picture within `directory`, in its `YYYY/MM/` partition when
+/// `partitioned` is set.
+fn picture_path(directory: &Path, file_name: &str, partitioned: bool) -> PathBuf {
+    if partitioned && is_bing_picture_name(file_name) {
+        directory.join(&file_name[..4]).join(&file_name[4..6]).join(file_name)
+    } else {
+        directory.join(file_name)
+    }
+}
+
+/// `YYYY/MM` partition of pictures of the given date.
+fn date_partition(date: DateTime<Utc>) -> PathBuf {
+    PathBuf::from(date.format("%Y").to_string()).join(date.format("%m").to_string())
+}
+
+/// Checks that the picture decodes completely. JPEG decoders fill in missing scan data of
+/// truncated files, so JPEGs must also end with the end of image marker.
+fn is_valid_image(path: &Path, decoder: JpegDecoder) -> bool {
+    let Ok(reader) = image::io::Reader::open(path).and_then(|reader| reader.with_guessed_format()) else {
+        return false;
+    };
+    if reader.format() == Some(image::ImageFormat::Jpeg)
+        && !std::fs::read(path).is_ok_and(|data| data.ends_with(&[0xFF, 0xD9]))
+    {
+        return false;
+    }
+    decode_image(path, decoder).is_some()
+}
+
+/// Decodes the picture's pixels with the given JPEG decoder, so processing is independent of the
+/// decoder in use. Pictures in other formats always use the `image` crate.
+pub fn decode_image(path: &Path, decoder: JpegDecoder) -> Option<image::DynamicImage> {
+    let reader = image::io::Reader::open(path).and_then(|reader| reader.with_guessed_format()).ok()?;
+    match (reader.format(), decoder) {
+        #[cfg(feature = "zune-jpeg")]
+        (Some(image::ImageFormat::Jpeg), JpegDecoder::Zune) => {
+            let data = std::fs::read(path).ok()?;
+            let mut decoder = zune_jpeg::JpegDecoder::new(&data);
+            let pixels = decoder.decode().ok()?;
+            let info = decoder.info()?;
+            image::RgbImage::from_raw(info.width.into(), info.height.into(), pixels)
+                .map(image::DynamicImage::ImageRgb8)
+        }
+        _ => reader.decode().ok(),
+    }
+}
+
+/// Width of thumbnails, see [Manager::thumbnail].
+pub const THUMBNAIL_WIDTH: u32 = 320;
+
+/// Encodes a JPEG thumbnail of the picture, at most [THUMBNAIL_WIDTH] pixels wide.
+fn encode_thumbnail(picture: &Path, decoder: JpegDecoder) -> Result<Vec<u8>, String> {
+    let image = decode_image(picture, decoder).ok_or("failed to decode picture")?;
+    let mut thumbnail = io::Cursor::new(Vec::new());
+    image.thumbnail(THUMBNAIL_WIDTH, u32::MAX)
+        .into_rgb8()
+        .write_to(&mut thumbnail, image::ImageFormat::Jpeg)
+        .map_err(|err| err.to_string())?;
+    Ok(thumbnail.into_inner())
+}
+
+/// Scores how well the image is suited as a wallpaper based on its metadata.
+fn image_score(image: &BingImage) -> u64 {
+    let mut score = image.title().chars().count() as u64;
+    if !image.copyright().is_empty() {
+        score += 100;
+    }
+    if let Some((width, height)) = image.implied_resolution() {
+        score += (width as u64 * height as u64) / 100_000;
+    }
+    score
+}
+
+/// Picks the highest scoring image, preferring the most recent one on ties.
+fn select_best(images: Vec<BingImage>) -> Option<BingImage> {
+    images.into_iter()
+        .rev()
+        .max_by_key(image_score)
+}
+
+/// Picks a random image, seeded by `date` so polling again on the same day, e.g. after a restart,
+/// picks the same and already downloaded image.
+fn select_random(images: Vec<BingImage>, date: NaiveDate) -> Option<BingImage> {
+    if images.is_empty() {
+        return None;
+    }
+    let mut rng = fastrand::Rng::with_seed(date.num_days_from_ce() as u64);
+    let index = rng.usize(..images.len());
+    images.into_iter().nth(index)
+}
+
+/// Drops the images Bing doesn't intend as wallpapers, unless that would drop every image.
+fn only_wallpapers(images: Vec<BingImage>) -> Vec<BingImage> {
+    if images.iter().any(BingImage::is_wallpaper) {
+        images.into_iter().filter(BingImage::is_wallpaper).collect()
+    } else {
+        images
+    }
+}
+
+/// Name of the file in the pictures directory mapping content hashes to cached pictures.
+const HASH_INDEX_FILE_NAME: &str = ".hashes.json";
+
+/// Cached pictures by the content hash Bing reports for them and their resolution.
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct HashIndex {
+    pictures: HashMap<String, String>,
+}
+
+impl VersionedState for HashIndex {
+    const VERSION: u32 = 1;
+}
+
+/// Hardlinks `path` to the identical picture at `original` instead of downloading it again,
+/// copying it if hardlinks are not supported. Does nothing if `path` exists or `original` doesn't.
+async fn link_duplicate(original: &Path, path: &Path) {
+    if original == path
+        || tokio::fs::try_exists(path).await.unwrap_or(true)
+        || !tokio::fs::try_exists(original).await.unwrap_or(false) {
+        return;
+    }
+    if let Some(parent) = path.parent() {
+        let _ = tokio::fs::create_dir_all(parent).await;
+    }
+
+    match tokio::fs::hard_link(original, path).await {
+        Ok(()) => debug!("Linked {} to identical {}", path.display(), original.display()),
+        Err(err) => {
+            debug!("Failed to hardlink {}: {}, copying instead", path.display(), err);
+            if let Err(err) = tokio::fs::copy(original, path).await {
+                warn!("Failed to copy identical picture {}: {}", original.display(), err);
+            }
+        }
+    }
+}
+
+/// Picks one of the pictures in `directory`, changing every day.
+async fn poll_directory_picture(directory: &Path, today: NaiveDate) -> Option<PolledPicture> {
+    let mut dir = tokio::fs::read_dir(directory).await.ok()?;
+    let mut pictures = Vec::new();
+    while let Ok(Some(entry)) = dir.next_entry().await {
+        let path = entry.path();
+        if image::ImageFormat::from_path(&path).is_ok() {
+            pictures.push(path);
+        }
+    }
+    if pictures.is_empty() {
+        return None;
+    }
+    pictures.sort();
+
+    let day = today.num_days_from_ce() as usize;
+    let path = pictures.swap_remove(day % pictures.len());
+    let title = path.file_stem().unwrap_or_default().to_string_lossy().to_string();
+    debug!("Using local picture {}", path.display());
+    Some(PolledPicture { path, title, copyright: String::new(), market: None })
+}
+
+/// Default hour of the day (UTC) at which Bing is assumed to publish the next picture.
+pub const DEFAULT_REFRESH_HOUR: u32 = 7;
+
+/// Next time the picture is refreshed after `now`, at `refresh_hour` (UTC) today or tomorrow.
+pub fn predict_next_poll_time(now: DateTime<Utc>, refresh_hour: u32) -> DateTime<Utc> {
+    if is_past_refresh_hour(now, refresh_hour) {
+        now.date_naive().add(Duration::days(1)).and_hms_opt(refresh_hour, 0, 0).unwrap().and_utc()
     } else {
-        now.date_naive().and_hms_opt(7, 0, 0).unwrap().and_utc()
+        now.date_naive().and_hms_opt(refresh_hour, 0, 0).unwrap().and_utc()
     }
 }
 
+/// Whether today's picture has been published by `now`. Until then, yesterday's picture is still
+/// the current one.
+pub fn is_past_refresh_hour(now: DateTime<Utc>, refresh_hour: u32) -> bool {
+    now.hour() >= refresh_hour
+}
+
 impl Manager {
     pub fn new(bing: Bing, configuration: Configuration) -> Self {
-        Manager { bing, configuration }
+        let metrics = Metrics::default();
+        Manager {
+            bing: bing.with_download_counter(metrics.download_counter()),
+            configuration: RwLock::new(Arc::new(configuration)),
+            display_size: Mutex::new(None),
+            clock: Arc::new(SystemClock),
+            metrics,
+            download_progress: None,
+            published: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Replaces the clock the manager schedules polls by, which defaults to [SystemClock].
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Reports the progress of downloading pictures from Bing to `progress`.
+    pub fn with_download_progress(mut self, progress: Arc<ProgressCallback>) -> Self {
+        self.download_progress = Some(progress);
+        self
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Current time according to the manager's clock.
+    pub fn now(&self) -> DateTime<Utc> {
+        self.clock.now()
+    }
+
+    /// Clock the manager schedules polls by, see [Manager::with_clock].
+    pub fn clock(&self) -> &dyn Clock {
+        self.clock.as_ref()
+    }
+
+    pub fn set_display_size(&self, width: u32, height: u32) {
+        *self.display_size.lock().unwrap() = Some((width, height));
+    }
+
+    fn resolution(&self) -> Resolution {
+        match (&self.configuration().resolution, *self.display_size.lock().unwrap()) {
+            (ResolutionPreference::Auto, Some((width, height))) => Resolution::for_display(width, height),
+            (preference, _) => preference.without_display_size(),
+        }
+    }
+
+    pub fn configuration(&self) -> Arc<Configuration> {
+        self.configuration.read().unwrap().clone()
+    }
+
+    /// Replaces the configuration, taking effect from the next poll.
+    pub fn set_configuration(&self, configuration: Configuration) {
+        *self.configuration.write().unwrap() = Arc::new(configuration);
+    }
+
+    /// Location of the thumbnail of the picture at `picture`.
+    fn thumbnail_path(&self, picture: &Path) -> PathBuf {
+        self.configuration().get_pictures_directory()
+            .join(".thumbnails")
+            .join(picture.file_name().unwrap_or_default())
+            .with_extension("jpg")
+    }
+
+    /// Returns the thumbnail of the picture, at most [THUMBNAIL_WIDTH] pixels wide, generating it
+    /// if it is missing or `force` is set. Returns `None` if thumbnails are disabled or the
+    /// thumbnail could not be generated.
+    pub async fn thumbnail(&self, picture: &Path, force: bool) -> Option<PathBuf> {
+        let configuration = self.configuration();
+        if !configuration.thumbnails {
+            return None;
+        }
+
+        let thumbnail = self.thumbnail_path(picture);
+        if !force && tokio::fs::try_exists(&thumbnail).await.unwrap_or(false) {
+            return Some(thumbnail);
+        }
+
+        debug!("Generating thumbnail {}", thumbnail.display());
+        let picture = picture.to_owned();
+        let decoder = configuration.jpeg_decoder;
+        match tokio::task::spawn_blocking(move || encode_thumbnail(&picture, decoder)).await {
+            // written atomically, so readers never see a partially written thumbnail
+            Ok(Ok(contents)) => match write_atomically(&thumbnail, &contents).await {
+                Ok(()) => Some(thumbnail),
+                Err(err) => {
+                    warn!("Failed to write thumbnail {}: {}", thumbnail.display(), err);
+                    None
+                }
+            },
+            Ok(Err(err)) => {
+                warn!("Failed to generate thumbnail {}: {}", thumbnail.display(), err);
+                None
+            }
+            Err(err) => {
+                error!("Thumbnail generation panicked: {}", err);
+                None
+            }
+        }
+    }
+
+    /// Lists downloaded pictures in both the flat and date partitioned layout, sorted from oldest
+    /// to newest.
+    async fn list_cached_pictures(&self) -> Vec<PathBuf> {
+        let mut pictures = self.list_pictures_directory(is_bing_picture_name).await;
+        // names start with the date, so the most recent pictures sort last
+        pictures.sort_by(|a, b| a.file_name().cmp(&b.file_name()));
+        pictures
+    }
+
+    /// Lists files with matching names in both the flat and date partitioned layout.
+    async fn list_pictures_directory(&self, matches: fn(&str) -> bool) -> Vec<PathBuf> {
+        let mut directories = vec![(self.configuration().get_pictures_directory(), 0)];
+        let mut files = Vec::new();
+        while let Some((directory, depth)) = directories.pop() {
+            let Ok(mut dir) = tokio::fs::read_dir(&directory).await else {
+                continue;
+            };
+            while let Ok(Some(entry)) = dir.next_entry().await {
+                let name = entry.file_name();
+                let name = name.to_string_lossy();
+                if matches(&name) {
+                    files.push(entry.path());
+                } else if is_partition_name(&name, depth) && entry.file_type().await.is_ok_and(|kind| kind.is_dir()) {
+                    directories.push((entry.path(), depth + 1));
+                }
+            }
+        }
+        files
+    }
+
+    /// Most recent cached picture of the market, described by its metadata sidecar. The picture
+    /// is protected from pruning as it is assumed to be shown.
+    pub async fn newest_cached_picture(&self, market: &Market) -> Option<PolledPicture> {
+        let market_code = market.to_string();
+        let newest = self.list_cached_pictures().await.into_iter().rev().find(|path| {
+            picture_market(&path.file_name().unwrap_or_default().to_string_lossy()) == market_code
+        })?;
+        self.publish(market, &newest);
+        Some(local_picture(newest, market).await)
+    }
+
+    /// Removes corrupt pictures among the most recent cached ones, returning how many were
+    /// removed. Removed pictures are downloaded again by the next poll if still available.
+    pub async fn verify_cache(&self) -> usize {
+        self.verify_pictures(VERIFIED_CACHE_PICTURES).await
+    }
+
+    async fn verify_pictures(&self, limit: usize) -> usize {
+        let mut removed = 0;
+        let decoder = self.configuration().jpeg_decoder;
+        for path in self.list_cached_pictures().await.into_iter().rev().take(limit) {
+            let check_path = path.clone();
+            let valid = tokio::task::spawn_blocking(move || is_valid_image(&check_path, decoder))
+                .await
+                .unwrap_or(true);
+            if valid {
+                continue;
+            }
+
+            warn!("Removing corrupt cached picture {}", path.display());
+            match tokio::fs::remove_file(&path).await {
+                Ok(()) => removed += 1,
+                Err(err) => error!("Failed to remove corrupt picture {}: {}", path.display(), err),
+            }
+        }
+        removed
+    }
+
+    /// Removes pictures dated more than `retention_days` days ago, returning how many were
+    /// removed.
+    async fn prune_pictures(&self, retention_days: u32) -> usize {
+        let oldest_kept = (self.now() - Duration::days(retention_days.into())).date_naive();
+
+        let mut removed = 0;
+        for path in self.list_cached_pictures().await {
+            let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+            let Ok(date) = NaiveDate::parse_from_str(&name[..8], BING_DATE_FORMAT) else {
+                continue;
+            };
+            if date >= oldest_kept {
+                // sorted by date, so every remaining picture is kept as well
+                break;
+            }
+
+            if !self.is_published(&path) && self.remove_picture(&path).await {
+                removed += 1;
+            }
+        }
+        removed
+    }
+
+    /// Records the picture handed out for `market`, protecting it from pruning.
+    fn publish(&self, market: &Market, path: &Path) {
+        self.published.lock().unwrap().insert(market.clone(), path.to_owned());
+    }
+
+    fn is_published(&self, path: &Path) -> bool {
+        self.published.lock().unwrap().values().any(|published| published == path)
     }
 
-    pub async fn poll_local_picture(&self) -> Option<LocalPicture> {
-        let today = Utc::now();
+    /// Removes all but the newest `max_cached_images` downloaded pictures of each market,
+    /// returning how many were removed. Published pictures are kept regardless of their age. Only
+    /// files following the naming of downloaded pictures are considered.
+    pub async fn cleanup_cache(&self, max_cached_images: usize) -> usize {
+        let mut markets: HashMap<String, Vec<PathBuf>> = HashMap::new();
+        for path in self.list_cached_pictures().await {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            markets.entry(picture_market(&name).to_owned()).or_default().push(path);
+        }
+
+        let mut removed = 0;
+        for pictures in markets.values() {
+            let excess = pictures.len().saturating_sub(max_cached_images);
+            for path in &pictures[..excess] {
+                if !self.is_published(path) && self.remove_picture(path).await {
+                    removed += 1;
+                }
+            }
+        }
+        removed
+    }
+
+    /// Removes the picture along with its sidecar and thumbnail, returning whether the picture
+    /// was removed.
+    async fn remove_picture(&self, path: &Path) -> bool {
+        debug!("Pruning picture {}", path.display());
+        let removed = match tokio::fs::remove_file(path).await {
+            Ok(()) => {
+                self.metrics.record_pruned();
+                true
+            }
+            Err(err) => {
+                error!("Failed to prune picture {}: {}", path.display(), err);
+                false
+            }
+        };
+        let _ = tokio::fs::remove_file(xmp_sidecar_path(path)).await;
+        let _ = tokio::fs::remove_file(metadata_sidecar_path(path)).await;
+        let _ = tokio::fs::remove_file(self.thumbnail_path(path)).await;
+        removed
+    }
+
+    /// Prunes pictures older than the retention window and removes corrupt pictures.
+    pub async fn run_maintenance(&self) -> MaintenanceReport {
+        let mut report = MaintenanceReport::default();
+        if let Some(retention_days) = self.configuration().maintenance.retention_days {
+            report.pruned = self.prune_pictures(retention_days).await;
+        }
+        report.corrupt = self.verify_pictures(usize::MAX).await;
+        report.partial = self.remove_stale_partial_files().await;
+        report
+    }
+
+    /// Removes partial files not modified for [STALE_PARTIAL_FILE_HOURS], e.g. of pictures that
+    /// are no longer polled, returning how many were removed.
+    async fn remove_stale_partial_files(&self) -> usize {
+        let stale_before = self.now() - Duration::hours(STALE_PARTIAL_FILE_HOURS);
+        let mut removed = 0;
+        for path in self.list_pictures_directory(is_partial_file_name).await {
+            let modified = tokio::fs::metadata(&path).await.and_then(|metadata| metadata.modified());
+            if !modified.is_ok_and(|modified| DateTime::<Utc>::from(modified) < stale_before) {
+                continue;
+            }
+            debug!("Removing stale partial file {}", path.display());
+            match tokio::fs::remove_file(&path).await {
+                Ok(()) => removed += 1,
+                Err(err) => warn!("Failed to remove stale partial file {}: {}", path.display(), err),
+            }
+        }
+        removed
+    }
+
+    pub async fn poll_local_picture(&self, market: &Market) -> Option<LocalPicture> {
+        let local = self.find_local_picture(market).await;
+        if let Some(LocalPicture::Today(picture) | LocalPicture::Yesterday(picture) | LocalPicture::Older(picture)) = &local {
+            self.publish(market, &picture.path);
+        }
+        local
+    }
+
+    async fn find_local_picture(&self, market: &Market) -> Option<LocalPicture> {
+        let today = self.now();
         let yesterday = today - Duration::hours(24);
-        let today = today.format(BING_DATE_FORMAT).to_string();
-        let yesterday = yesterday.format(BING_DATE_FORMAT).to_string();
+        let today_name = format!("{}-{}", today.format(BING_DATE_FORMAT), market);
+        let yesterday_name = format!("{}-{}", yesterday.format(BING_DATE_FORMAT), market);
 
-        debug!("Looking for today's picture {} and yesterday's as fallback {}", today, yesterday);
+        debug!("Looking for today's picture {} and yesterday's as fallback {}", today_name, yesterday_name);
         let mut yesterday_opt = None;
 
-        let picture_directory = self.configuration.get_pictures_directory();
-        let mut dir = tokio::fs::read_dir(picture_directory).await.ok()?;
-        while let Ok(Some(entry)) = dir.next_entry().await {
-            let name = entry.file_name();
-            let name = name.to_string_lossy();
-            if name.starts_with(&today) {
-                return Some(LocalPicture::Today(entry.path()));
-            } else if name.starts_with(&yesterday) {
-                yesterday_opt = Some(entry.path());
+        // only the partitions of today and yesterday can contain their pictures
+        let picture_directory = self.configuration().get_pictures_directory();
+        let mut directories = vec![picture_directory.clone()];
+        for partition in [date_partition(today), date_partition(yesterday)] {
+            let directory = picture_directory.join(partition);
+            if !directories.contains(&directory) {
+                directories.push(directory);
+            }
+        }
+        for directory in directories {
+            let Ok(mut dir) = tokio::fs::read_dir(directory).await else {
+                continue;
+            };
+            while let Ok(Some(entry)) = dir.next_entry().await {
+                let name = entry.file_name();
+                let name = name.to_string_lossy();
+                if !is_bing_picture_name(&name) {
+                    continue;
+                }
+                if name.starts_with(&today_name) {
+                    return Some(LocalPicture::Today(local_picture(entry.path(), market).await));
+                } else if name.starts_with(&yesterday_name) {
+                    yesterday_opt = Some(entry.path());
+                }
+            }
+        }
+        if let Some(yesterday) = yesterday_opt {
+            return Some(LocalPicture::Yesterday(local_picture(yesterday, market).await));
+        }
+
+        let market_prefix = format!("{}-", market);
+        let older = self.list_cached_pictures().await.into_iter().rev().find(|path| {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            name[9..].starts_with(&market_prefix) && name[..8] < yesterday_name[..8]
+        })?;
+        Some(LocalPicture::Older(local_picture(older, market).await))
+    }
+
+    /// Attempts to get a picture from each configured source in order and returns the time when
+    /// the next poll operation should be performed. When `force` is set, the picture is downloaded
+    /// again even if it already exists locally.
+    pub async fn poll_picture(&self, market: &Market, force: bool) -> (Option<PolledPicture>, DateTime<Utc>) {
+        let (picture, next) = self.poll_sources(market, force).await;
+        self.metrics.record_poll(picture.is_some(), self.now());
+        (picture, next)
+    }
+
+    async fn poll_sources(&self, market: &Market, force: bool) -> (Option<PolledPicture>, DateTime<Utc>) {
+        let configuration = self.configuration();
+        let sources = match configuration.sources.as_slice() {
+            [] => &[ImageSource::Classic],
+            sources => sources,
+        };
+
+        // the earliest retry requested by a failed source, so a fallback is replaced as soon as
+        // possible
+        let mut retry: Option<DateTime<Utc>> = None;
+        for source in sources {
+            match source {
+                ImageSource::Classic => match self.poll_bing_picture(market, force).await {
+                    (Some(picture), next) => return (Some(picture), next),
+                    (None, next) => retry = Some(retry.map_or(next, |retry| retry.min(next))),
+                },
+                ImageSource::Local(directory) => match poll_directory_picture(directory, self.now().date_naive()).await {
+                    Some(picture) => return (Some(picture), retry.unwrap_or_else(|| predict_next_poll_time(self.now(), configuration.get_refresh_hour()))),
+                    None => warn!("No pictures found in {}", directory.display()),
+                },
             }
         }
+        (None, retry.unwrap_or_else(|| self.now() + Duration::minutes(5)))
+    }
+
+    /// Fetches the candidate images for the wallpaper mode, newest first.
+    async fn fetch_images(&self, market: &Market, mode: &WallpaperMode) -> Result<Vec<BingImage>, ImageOfTheDayError> {
+        match mode {
+            WallpaperMode::Daily => match self.bing.image_of_the_day(market).await {
+                Ok(image) if !image.is_wallpaper() => {
+                    debug!("Image of the day is not meant as a wallpaper, looking for a recent one");
+                    self.bing.images_range(market, 0, MAX_HISTORY_DAYS).await.or_else(|_| Ok(vec![image]))
+                }
+                result => result.map(|image| vec![image]),
+            },
+            WallpaperMode::Best { count } => self.bing.images_range(market, 0, *count).await,
+            WallpaperMode::RandomArchive { window } => self.bing.images_range(market, 0, *window).await,
+        }
+    }
 
-        yesterday_opt.map(LocalPicture::Yesterday)
+    /// Fetches the candidate images for the market, trying the configured fallback markets in
+    /// order while Bing has no images for it.
+    async fn fetch_images_with_fallback(&self, market: &Market, configuration: &Configuration) -> Result<Vec<BingImage>, ImageOfTheDayError> {
+        let mut images = self.fetch_images(market, &configuration.mode).await;
+        // some markets transiently lack images
+        let mut tried = market;
+        for fallback in &configuration.fallback_markets {
+            match images {
+                Err(ImageOfTheDayError::NoImagesFound) => {
+                    warn!("No images found for {}, falling back to {}", tried, fallback);
+                    images = self.fetch_images(fallback, &configuration.mode).await;
+                    tried = fallback;
+                }
+                _ => break,
+            }
+        }
+        images
     }
 
     /// Attempts to downloads the image of the day from Bing and returns the time when the next
     /// poll operation should be performed.
-    pub async fn poll_picture(&self) -> (Option<PathBuf>, DateTime<Utc>) {
-        debug!("Polling picture");
-        let image = match self.bing.image_of_the_day().await {
-            Ok(image) => image,
+    async fn poll_bing_picture(&self, market: &Market, force: bool) -> (Option<PolledPicture>, DateTime<Utc>) {
+        debug!("Polling picture for {}", market);
+        let configuration = self.configuration();
+        let images = match self.fetch_images_with_fallback(market, &configuration).await {
+            Ok(images) => images,
             Err(error) => {
                 error!("Failed to query image of the day: {}, retrying in 5 minutes.", error);
-                return (None, DateTime::from(Utc::now() + Duration::minutes(5)));
+                return (None, self.now() + Duration::minutes(5));
             }
         };
-        image.get_image_file_name();
 
-        let picture_directory = self.configuration.get_pictures_directory();
-        let picture_path = picture_directory.join(image.get_image_file_name());
+        // the newest image determines when the next image becomes available
+        let end_date = images[0].get_end_date_or_estimate();
+        let images = only_wallpapers(images);
+        let image = match configuration.mode {
+            WallpaperMode::Daily => images.into_iter().next(),
+            WallpaperMode::Best { .. } => select_best(images),
+            WallpaperMode::RandomArchive { .. } => select_random(images, self.now().date_naive()),
+        }.expect("at least one image");
+        if image.market() != market {
+            info!("Using picture of fallback market {}", image.market());
+        }
+        let picture_directory = configuration.get_pictures_directory();
+        let picture_path = picture_path(&picture_directory, &image.get_image_file_name(), configuration.partition_by_date);
 
-        // check if picture is already downloaded
-        if let Ok(true) = tokio::fs::try_exists(&picture_path).await {
-            debug!("Picture already downloaded");
-        } else {
-            if let Err(error) = self.bing.download_image(&image, &picture_path).await {
-                error!("Failed to download image: {}, retrying in 5 minutes.", error);
-                return (None, DateTime::from(Utc::now() + Duration::minutes(5)));
+        let resolution = self.resolution();
+        // images shared by several markets are stored once and hardlinked
+        let hash_key = format!("{}_{}", image.hash(), resolution.suffix());
+        let index_path = picture_directory.join(HASH_INDEX_FILE_NAME);
+        let mut index: HashIndex = load_state(&index_path).await.unwrap_or_default();
+        if !force && !image.hash().is_empty() {
+            if let Some(original) = index.pictures.get(&hash_key) {
+                link_duplicate(&picture_directory.join(original), &picture_path).await;
             }
         }
 
-        (Some(picture_path), match image.get_end_date() {
-            Ok(end_date) if end_date < Utc::now() => {
-                let next = predict_next_poll_time();
+        if let Err(error) = self.bing.download_image_with_resolution(&image, resolution, &picture_path, force, self.download_progress.as_deref()).await {
+            error!("Failed to download image: {}, retrying in 5 minutes.", error);
+            return (None, self.now() + Duration::minutes(5));
+        }
+
+        // pictures may have been pruned since they were recorded, the new copy replaces them then
+        let original_exists = match index.pictures.get(&hash_key) {
+            Some(original) => tokio::fs::try_exists(picture_directory.join(original)).await.unwrap_or(false),
+            None => false,
+        };
+        if !image.hash().is_empty() && !original_exists {
+            let relative_path = picture_path.strip_prefix(&picture_directory).unwrap_or(&picture_path);
+            index.pictures.insert(hash_key, relative_path.to_string_lossy().to_string());
+            if let Err(error) = persist_state(&index_path, &index).await {
+                warn!("Failed to update picture hashes: {}", error);
+            }
+        }
+        let metadata_exists = tokio::fs::try_exists(metadata_sidecar_path(&picture_path)).await.unwrap_or(false);
+        if force || !metadata_exists {
+            if let Err(error) = self.bing.write_metadata_sidecar(&image, &picture_path).await {
+                warn!("Failed to write metadata sidecar: {}", error);
+            }
+        }
+        if configuration.xmp_sidecars {
+            let sidecar_exists = tokio::fs::try_exists(xmp_sidecar_path(&picture_path)).await.unwrap_or(false);
+            if force || !sidecar_exists {
+                if let Err(error) = self.bing.write_xmp_sidecar(&image, &picture_path).await {
+                    warn!("Failed to write XMP sidecar: {}", error);
+                }
+            }
+        }
+
+        self.thumbnail(&picture_path, force).await;
+
+        self.publish(market, &picture_path);
+        if let Some(max_cached_images) = configuration.max_cached_images {
+            let removed = self.cleanup_cache(max_cached_images).await;
+            if removed > 0 {
+                debug!("Removed {} old cached pictures", removed);
+            }
+        }
+
+        let picture = PolledPicture {
+            path: picture_path,
+            title: image.title().to_owned(),
+            copyright: image.copyright().to_owned(),
+            market: Some(image.market().clone()),
+        };
+        (Some(picture), match end_date {
+            Ok(end_date) if end_date < self.now() => {
+                let next = predict_next_poll_time(self.now(), configuration.get_refresh_hour());
                 warn!("Bing returned end date in the past, assuming {}", next);
                 next
             }
             Ok(end_date) => end_date,
             Err(err) => {
-                let next = predict_next_poll_time();
+                let next = predict_next_poll_time(self.now(), configuration.get_refresh_hour());
                 warn!("Failed to parse end date: {}, assuming {}", err, next);
                 next
             }
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::bing::tests::{ARCHIVE_RESPONSE, JPEG, MockServer, Response};
+    use super::*;
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn predicts_next_poll_time() {
+        for (now, refresh_hour, expected) in [
+            ("2023-10-05T06:59:59Z", 7, "2023-10-05T07:00:00Z"),
+            ("2023-10-05T07:00:00Z", 7, "2023-10-06T07:00:00Z"),
+            ("2023-10-05T07:00:01Z", 7, "2023-10-06T07:00:00Z"),
+            ("2023-10-05T23:59:59Z", 7, "2023-10-06T07:00:00Z"),
+            ("2023-10-06T00:00:00Z", 7, "2023-10-06T07:00:00Z"),
+            ("2023-10-05T23:59:59Z", 0, "2023-10-06T00:00:00Z"),
+            ("2023-10-06T00:00:00Z", 0, "2023-10-07T00:00:00Z"),
+            ("2023-10-05T22:59:59Z", 23, "2023-10-05T23:00:00Z"),
+            ("2023-10-05T23:00:00Z", 23, "2023-10-06T23:00:00Z"),
+            ("2023-12-31T08:00:00Z", 7, "2024-01-01T07:00:00Z"),
+            ("2024-02-28T12:00:00Z", 7, "2024-02-29T07:00:00Z"),
+        ] {
+            assert_eq!(predict_next_poll_time(utc(now), refresh_hour), utc(expected), "{} at hour {}", now, refresh_hour);
+        }
+    }
+
+    #[test]
+    fn predicts_next_poll_time_after_resuming_from_suspend() {
+        // scheduled in the evening, then suspended until the afternoon after the scheduled poll
+        let scheduled = predict_next_poll_time(utc("2023-10-04T20:00:00Z"), 7);
+        let resumed = utc("2023-10-06T13:00:00Z");
+
+        // the picture shown before suspending is stale, so the missed poll is due right away
+        assert!(scheduled < resumed);
+        assert!(is_past_refresh_hour(resumed, 7));
+        // and that poll schedules the next one from the resumed clock rather than the stale one
+        assert_eq!(predict_next_poll_time(resumed, 7), utc("2023-10-07T07:00:00Z"));
+        assert_eq!(predict_next_poll_time(utc("2023-10-06T06:30:00Z"), 7), utc("2023-10-06T07:00:00Z"));
+    }
+
+    #[test]
+    fn detects_refresh_hour() {
+        for (now, refresh_hour, expected) in [
+            ("2023-10-05T00:00:00Z", 0, true),
+            ("2023-10-05T00:00:00Z", 7, false),
+            ("2023-10-05T06:59:59Z", 7, false),
+            ("2023-10-05T07:00:00Z", 7, true),
+            ("2023-10-05T23:59:59Z", 7, true),
+            ("2023-10-05T22:59:59Z", 23, false),
+            ("2023-10-05T23:59:59Z", 23, true),
+        ] {
+            assert_eq!(is_past_refresh_hour(utc(now), refresh_hour), expected, "{} at hour {}", now, refresh_hour);
+        }
+    }
+
+    #[test]
+    fn schedules_maintenance() {
+        let maintenance = MaintenanceConfiguration { hour: 3, ..MaintenanceConfiguration::default() };
+        for (now, expected) in [
+            ("2023-10-05T00:00:00Z", "2023-10-05T03:00:00Z"),
+            ("2023-10-05T02:59:59Z", "2023-10-05T03:00:00Z"),
+            ("2023-10-05T03:00:00Z", "2023-10-06T03:00:00Z"),
+            ("2023-10-05T23:59:59Z", "2023-10-06T03:00:00Z"),
+            ("2023-12-31T04:00:00Z", "2024-01-01T03:00:00Z"),
+        ] {
+            assert_eq!(maintenance.next_run(utc(now)), utc(expected), "{}", now);
+        }
+
+        let midnight = MaintenanceConfiguration { hour: 0, ..MaintenanceConfiguration::default() };
+        assert_eq!(midnight.next_run(utc("2023-10-05T00:00:00Z")), utc("2023-10-06T00:00:00Z"));
+        assert_eq!(midnight.next_run(utc("2023-10-05T23:59:59Z")), utc("2023-10-06T00:00:00Z"));
+    }
+
+    #[test]
+    fn manual_clock_stands_still() {
+        let clock = ManualClock::new(utc("2023-10-05T06:00:00Z"));
+        assert_eq!(clock.now(), utc("2023-10-05T06:00:00Z"));
+        clock.advance(Duration::hours(2));
+        assert_eq!(clock.now(), utc("2023-10-05T08:00:00Z"));
+        clock.set(utc("2023-10-06T23:59:00Z"));
+        assert_eq!(clock.now(), utc("2023-10-06T23:59:00Z"));
+    }
+
+    #[tokio::test]
+    async fn polls_are_scheduled_by_the_clock() {
+        let directory = tempfile::tempdir().unwrap();
+        for name in ["a.jpg", "b.jpg"] {
+            tokio::fs::write(directory.path().join(name), b"").await.unwrap();
+        }
+        let configuration = Configuration {
+            sources: vec![ImageSource::Local(directory.path().to_owned())],
+            refresh_hour: Some(7),
+            ..Configuration::default()
+        };
+        let clock = Arc::new(ManualClock::new(utc("2023-10-05T23:30:00Z")));
+        let manager = Manager::new(Bing::new().unwrap(), configuration).with_clock(clock.clone());
+        assert_eq!(manager.now(), utc("2023-10-05T23:30:00Z"));
+
+        let (picture, next) = manager.poll_picture(&Market::EnglishUS, false).await;
+        let first = picture.unwrap().path;
+        assert_eq!(next, utc("2023-10-06T07:00:00Z"));
+
+        // past midnight, the local source moves on to the next picture
+        clock.advance(Duration::minutes(31));
+        let (picture, next) = manager.poll_picture(&Market::EnglishUS, false).await;
+        assert_ne!(picture.unwrap().path, first);
+        assert_eq!(next, utc("2023-10-06T07:00:00Z"));
+
+        clock.set(utc("2023-10-06T07:00:00Z"));
+        let (_, next) = manager.poll_picture(&Market::EnglishUS, false).await;
+        assert_eq!(next, utc("2023-10-07T07:00:00Z"));
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_the_next_source_until_one_supplies_a_picture() {
+        let directory = tempfile::tempdir().unwrap();
+        let local = directory.path().join("local");
+        tokio::fs::create_dir(&local).await.unwrap();
+        tokio::fs::write(local.join("Dunes.jpg"), JPEG).await.unwrap();
+        let empty = directory.path().join("empty");
+        tokio::fs::create_dir(&empty).await.unwrap();
+        let pictures = directory.path().join("pictures");
+
+        for (sources, responses, expected_title, expected_requests) in [
+            (
+                vec![ImageSource::Classic, ImageSource::Local(local.clone())],
+                vec![Response::new("500 Internal Server Error", &[], b"")],
+                "Dunes",
+                1,
+            ),
+            (
+                vec![ImageSource::Local(empty.clone()), ImageSource::Classic, ImageSource::Local(local.clone())],
+                vec![Response::json(ARCHIVE_RESPONSE), Response::jpeg(JPEG)],
+                "Guiding light",
+                2,
+            ),
+        ] {
+            let server = MockServer::start(responses).await;
+            let configuration = Configuration {
+                sources,
+                pictures_directory: Some(pictures.to_string_lossy().to_string()),
+                ..Configuration::default()
+            };
+            let manager = Manager::new(Bing::new().unwrap().with_base_url(&server.base_url()), configuration);
+
+            let (picture, _) = manager.poll_picture(&Market::EnglishUS, false).await;
+
+            let picture = picture.unwrap();
+            assert_eq!(picture.title, expected_title);
+            assert_eq!(server.requests().len(), expected_requests);
+        }
+    }
+
+    #[tokio::test]
+    async fn polls_query_the_configured_market() {
+        for mode in [WallpaperMode::Daily, WallpaperMode::Best { count: 3 }, WallpaperMode::RandomArchive { window: 8 }] {
+            let server = MockServer::start(vec![Response::json(r#"{"images":[]}"#)]).await;
+            let configuration = Configuration {
+                market: Market::DanishDenmark,
+                mode,
+                ..Configuration::default()
+            };
+            let manager = Manager::new(Bing::new().unwrap().with_base_url(&server.base_url()), configuration);
+
+            let market = manager.configuration().market.clone();
+            let (picture, _) = manager.poll_picture(&market, false).await;
+
+            assert!(picture.is_none());
+            let requests = server.requests();
+            assert_eq!(requests.len(), 1);
+            let request_line = requests[0].lines().next().unwrap();
+            assert!(request_line.contains("mkt=da-DK"), "{}", request_line);
+        }
+    }
+
+    const NO_IMAGES: &str = r#"{"images":[]}"#;
+
+    fn fallback_manager(server: &MockServer) -> (Manager, Arc<Configuration>) {
+        let configuration = Configuration {
+            market: Market::DanishDenmark,
+            fallback_markets: vec![Market::JapaneseJapan, Market::EnglishGB],
+            ..Configuration::default()
+        };
+        let manager = Manager::new(Bing::new().unwrap().with_base_url(&server.base_url()), configuration);
+        let configuration = manager.configuration();
+        (manager, configuration)
+    }
+
+    fn requested_markets(server: &MockServer) -> Vec<String> {
+        server.requests().iter()
+            .map(|request| {
+                let request_line = request.lines().next().unwrap();
+                let start = request_line.find("mkt=").unwrap() + 4;
+                request_line[start..].split(['&', ' ']).next().unwrap().to_owned()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_the_next_market_without_images() {
+        for (responses, expected_market, expected_requests) in [
+            (vec![ARCHIVE_RESPONSE], Market::DanishDenmark, vec!["da-DK"]),
+            (vec![NO_IMAGES, ARCHIVE_RESPONSE], Market::JapaneseJapan, vec!["da-DK", "ja-JP"]),
+            (vec![NO_IMAGES, NO_IMAGES, ARCHIVE_RESPONSE], Market::EnglishGB, vec!["da-DK", "ja-JP", "en-GB"]),
+        ] {
+            let server = MockServer::start(responses.into_iter().map(Response::json).collect()).await;
+            let (manager, configuration) = fallback_manager(&server);
+
+            let images = manager.fetch_images_with_fallback(&configuration.market, &configuration).await.unwrap();
+
+            assert_eq!(images[0].market(), &expected_market);
+            assert_eq!(requested_markets(&server), expected_requests);
+        }
+    }
+
+    #[tokio::test]
+    async fn fails_when_no_market_has_images() {
+        let server = MockServer::start(vec![Response::json(NO_IMAGES), Response::json(NO_IMAGES), Response::json(NO_IMAGES)]).await;
+        let (manager, configuration) = fallback_manager(&server);
+
+        let result = manager.fetch_images_with_fallback(&configuration.market, &configuration).await;
+
+        assert!(matches!(result, Err(ImageOfTheDayError::NoImagesFound)));
+        assert_eq!(requested_markets(&server), ["da-DK", "ja-JP", "en-GB"]);
+    }
+
+    #[tokio::test]
+    async fn other_errors_do_not_fall_back() {
+        let server = MockServer::start(vec![Response::json("{"), Response::json(ARCHIVE_RESPONSE)]).await;
+        let (manager, configuration) = fallback_manager(&server);
+
+        let result = manager.fetch_images_with_fallback(&configuration.market, &configuration).await;
+
+        assert!(matches!(result, Err(ImageOfTheDayError::RequestError(_))), "{:?}", result.err());
+        assert_eq!(requested_markets(&server), ["da-DK"]);
+    }
+
+    fn cleanup_manager(directory: &Path, max_cached_images: usize) -> Manager {
+        let configuration = Configuration {
+            pictures_directory: Some(directory.to_string_lossy().to_string()),
+            max_cached_images: Some(max_cached_images),
+            ..Configuration::default()
+        };
+        Manager::new(Bing::new().unwrap(), configuration)
+    }
+
+    async fn cached_names(directory: &Path) -> Vec<String> {
+        let mut entries = tokio::fs::read_dir(directory).await.unwrap();
+        let mut names = Vec::new();
+        while let Some(entry) = entries.next_entry().await.unwrap() {
+            names.push(entry.file_name().to_string_lossy().to_string());
+        }
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn extracts_picture_markets() {
+        for (name, expected) in [
+            ("20231005-en-US-Guiding light.jpg", "en-US"),
+            ("20231005-ja-JP-灯台.jpg", "ja-JP"),
+            ("20231005-en", ""),
+        ] {
+            assert_eq!(picture_market(name), expected, "{}", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn cleanup_keeps_newest_pictures_per_market() {
+        let directory = tempfile::tempdir().unwrap();
+        for name in [
+            "20231001-en-US-A.jpg", "20231002-en-US-B.jpg", "20231003-en-US-C.jpg", "20231004-en-US-D.jpg",
+            "20231001-ja-JP-E.jpg", "20231002-ja-JP-F.jpg",
+            "notes.txt",
+        ] {
+            tokio::fs::write(directory.path().join(name), b"").await.unwrap();
+        }
+        tokio::fs::write(directory.path().join("20231001-en-US-A.json"), b"{}").await.unwrap();
+        let manager = cleanup_manager(directory.path(), 2);
+
+        assert_eq!(manager.cleanup_cache(2).await, 2);
+
+        assert_eq!(cached_names(directory.path()).await, [
+            "20231001-ja-JP-E.jpg", "20231002-ja-JP-F.jpg",
+            "20231003-en-US-C.jpg", "20231004-en-US-D.jpg",
+            "notes.txt",
+        ]);
+    }
+
+    #[tokio::test]
+    async fn cleanup_never_removes_published_pictures() {
+        let directory = tempfile::tempdir().unwrap();
+        for name in ["20231001-en-US-A.jpg", "20231002-en-US-B.jpg", "20231003-en-US-C.jpg", "20231001-ja-JP-D.jpg", "20231002-ja-JP-E.jpg"] {
+            tokio::fs::write(directory.path().join(name), b"").await.unwrap();
+        }
+        let manager = cleanup_manager(directory.path(), 1);
+        // e.g. the best picture of the week, older than the market's newest one
+        manager.publish(&Market::EnglishUS, &directory.path().join("20231001-en-US-A.jpg"));
+        manager.publish(&Market::JapaneseJapan, &directory.path().join("20231002-ja-JP-E.jpg"));
+
+        assert_eq!(manager.cleanup_cache(1).await, 2);
+
+        assert_eq!(cached_names(directory.path()).await, ["20231001-en-US-A.jpg", "20231002-ja-JP-E.jpg", "20231003-en-US-C.jpg"]);
+    }
+
+    #[tokio::test]
+    async fn local_pictures_are_published() {
+        let directory = tempfile::tempdir().unwrap();
+        for name in ["20231001-en-US-A.jpg", "20231002-en-US-B.jpg"] {
+            tokio::fs::write(directory.path().join(name), b"").await.unwrap();
+        }
+        let manager = cleanup_manager(directory.path(), 1)
+            .with_clock(Arc::new(ManualClock::new(utc("2023-10-10T12:00:00Z"))));
+        manager.publish(&Market::EnglishUS, &directory.path().join("20231001-en-US-A.jpg"));
+
+        // the stale picture found on startup replaces the one published before
+        let Some(LocalPicture::Older(local)) = manager.poll_local_picture(&Market::EnglishUS).await else {
+            panic!("expected an older picture");
+        };
+        assert_eq!(local.path, directory.path().join("20231002-en-US-B.jpg"));
+        assert_eq!(manager.cleanup_cache(1).await, 1);
+
+        assert_eq!(cached_names(directory.path()).await, ["20231002-en-US-B.jpg"]);
+    }
+
+    #[test]
+    fn partitions_picture_paths() {
+        let directory = Path::new("/pictures");
+        for (file_name, partitioned, expected) in [
+            ("20231005-en-US-Lighthouse.jpg", false, "/pictures/20231005-en-US-Lighthouse.jpg"),
+            ("20231005-en-US-Lighthouse.jpg", true, "/pictures/2023/10/20231005-en-US-Lighthouse.jpg"),
+            ("20240101-de-DE-Leuchtturm.jpg", true, "/pictures/2024/01/20240101-de-DE-Leuchtturm.jpg"),
+            ("bliss.jpg", true, "/pictures/bliss.jpg"),
+            ("20231005.jpg", true, "/pictures/20231005.jpg"),
+            ("20231005-en-US-Lighthouse.json", true, "/pictures/20231005-en-US-Lighthouse.json"),
+        ] {
+            assert_eq!(picture_path(directory, file_name, partitioned), Path::new(expected), "{} {}", file_name, partitioned);
+        }
+    }
+
+    #[test]
+    fn partitions_dates() {
+        for (date, expected) in [
+            ("2023-10-05T07:00:00Z", "2023/10"),
+            ("2023-10-31T23:59:59Z", "2023/10"),
+            ("2023-11-01T00:00:00Z", "2023/11"),
+            ("2023-12-31T23:59:59Z", "2023/12"),
+            ("2024-01-01T00:00:00Z", "2024/01"),
+        ] {
+            assert_eq!(date_partition(utc(date)), Path::new(expected), "{}", date);
+        }
+    }
+
+    #[test]
+    fn recognizes_partition_names() {
+        for (name, depth, expected) in [
+            ("2023", 0, true),
+            ("10", 1, true),
+            ("2023", 1, false),
+            ("10", 0, false),
+            ("2023", 2, false),
+            ("202", 0, false),
+            ("20234", 0, false),
+            ("1", 1, false),
+            ("1a", 1, false),
+            ("thumbnails", 0, false),
+        ] {
+            assert_eq!(is_partition_name(name, depth), expected, "{} at depth {}", name, depth);
+        }
+    }
+
+    async fn partitioned_manager(directory: &Path, now: &str, pictures: &[&str]) -> Manager {
+        for picture in pictures {
+            let path = picture_path(directory, picture, true);
+            tokio::fs::create_dir_all(path.parent().unwrap()).await.unwrap();
+            tokio::fs::write(path, b"").await.unwrap();
+        }
+        let configuration = Configuration {
+            pictures_directory: Some(directory.to_string_lossy().to_string()),
+            partition_by_date: true,
+            ..Configuration::default()
+        };
+        Manager::new(Bing::new().unwrap(), configuration).with_clock(Arc::new(ManualClock::new(utc(now))))
+    }
+
+    #[tokio::test]
+    async fn finds_local_pictures_across_partitions() {
+        for (now, pictures, expected) in [
+            ("2023-11-01T08:00:00Z", vec!["20231031-en-US-A.jpg", "20231101-en-US-B.jpg"], "2023/11/20231101-en-US-B.jpg"),
+            ("2024-01-01T08:00:00Z", vec!["20231231-en-US-A.jpg", "20240101-en-US-B.jpg"], "2024/01/20240101-en-US-B.jpg"),
+            ("2023-11-01T08:00:00Z", vec!["20231031-en-US-A.jpg"], "2023/10/20231031-en-US-A.jpg"),
+            ("2024-01-01T08:00:00Z", vec!["20231231-en-US-A.jpg"], "2023/12/20231231-en-US-A.jpg"),
+        ] {
+            let directory = tempfile::tempdir().unwrap();
+            let manager = partitioned_manager(directory.path(), now, &pictures).await;
+
+            let path = match manager.poll_local_picture(&Market::EnglishUS).await {
+                Some(LocalPicture::Today(picture) | LocalPicture::Yesterday(picture)) => picture.path,
+                _ => panic!("expected today's or yesterday's picture at {}", now),
+            };
+            assert_eq!(path, directory.path().join(expected));
+        }
+    }
+
+    #[tokio::test]
+    async fn finds_older_pictures_in_earlier_partitions() {
+        let directory = tempfile::tempdir().unwrap();
+        let manager = partitioned_manager(directory.path(), "2023-11-01T08:00:00Z", &["20230915-en-US-A.jpg", "20231015-en-US-B.jpg", "20231020-de-DE-C.jpg"]).await;
+
+        let Some(LocalPicture::Older(picture)) = manager.poll_local_picture(&Market::EnglishUS).await else {
+            panic!("expected an older picture");
+        };
+        assert_eq!(picture.path, directory.path().join("2023/10/20231015-en-US-B.jpg"));
+    }
+
+    fn bing_image(start_date: &str, url: &str, title: &str, copyright: &str) -> BingImage {
+        serde_json::from_value(serde_json::json!({
+            "startdate": start_date,
+            "fullstartdate": format!("{}0700", start_date),
+            "enddate": start_date,
+            "url": url,
+            "urlbase": "/th?id=OHR.Lighthouse_EN-US1234",
+            "title": title,
+            "copyright": copyright,
+        })).unwrap()
+    }
+
+    fn wallpaper(title: &str, wp: bool) -> BingImage {
+        serde_json::from_value(serde_json::json!({
+            "startdate": "20231001",
+            "fullstartdate": "202310010700",
+            "enddate": "20231002",
+            "url": "/th?id=OHR.Lighthouse_EN-US1234_1920x1080.jpg",
+            "urlbase": "/th?id=OHR.Lighthouse_EN-US1234",
+            "title": title,
+            "wp": wp,
+        })).unwrap()
+    }
+
+    #[test]
+    fn keeps_only_wallpapers() {
+        for (images, expected) in [
+            (vec![], vec![]),
+            (vec![("A", true)], vec!["A"]),
+            (vec![("A", false)], vec!["A"]),
+            (vec![("A", true), ("B", false), ("C", true)], vec!["A", "C"]),
+            (vec![("A", false), ("B", true)], vec!["B"]),
+            (vec![("A", false), ("B", false)], vec!["A", "B"]),
+        ] {
+            let images = images.into_iter().map(|(title, wp)| wallpaper(title, wp)).collect();
+            let titles = only_wallpapers(images).iter().map(|image| image.title().to_owned()).collect::<Vec<_>>();
+            assert_eq!(titles, expected);
+        }
+        // images lacking the field are wallpapers
+        assert!(bing_image("20231001", "/a_1920x1080.jpg", "A", "").is_wallpaper());
+    }
+
+    #[test]
+    fn scores_images() {
+        for (url, title, copyright, expected) in [
+            ("/th?id=OHR.Lighthouse_EN-US1234_UHD.jpg", "", "", 0),
+            ("/th?id=OHR.Lighthouse_EN-US1234_UHD.jpg", "Lighthouse", "", 10),
+            ("/th?id=OHR.Lighthouse_EN-US1234_UHD.jpg", "Leuchttürme", "", 11),
+            ("/th?id=OHR.Lighthouse_EN-US1234_UHD.jpg", "", "© Photographer", 100),
+            ("/th?id=OHR.Lighthouse_EN-US1234_1920x1080.jpg", "", "", 20),
+            ("/th?id=OHR.Lighthouse_EN-US1234_3840x2160.jpg&rf=LaDigue_1920x1080.jpg", "", "", 82),
+            ("/th?id=OHR.Lighthouse_EN-US1234_1920x1080.jpg", "Lighthouse", "© Photographer", 130),
+        ] {
+            assert_eq!(image_score(&bing_image("20231001", url, title, copyright)), expected, "{} {:?} {:?}", url, title, copyright);
+        }
+    }
+
+    #[test]
+    fn selects_the_best_image() {
+        let url = "/th?id=OHR.Lighthouse_EN-US1234_1920x1080.jpg";
+        assert!(select_best(Vec::new()).is_none());
+        for (images, expected) in [
+            (vec![bing_image("20231003", url, "", "")], "20231003"),
+            (vec![bing_image("20231003", url, "", ""), bing_image("20231002", url, "Lighthouse", "")], "20231002"),
+            (vec![bing_image("20231003", url, "Lighthouse", ""), bing_image("20231002", url, "Lighthouse", "© Photographer")], "20231002"),
+            (vec![bing_image("20231003", url, "", ""), bing_image("20231002", "/th?id=OHR.Lighthouse_UHD.jpg", "", "")], "20231003"),
+            (vec![bing_image("20231003", url, "Lighthouse", ""), bing_image("20231002", url, "Lighthouse", ""), bing_image("20231001", url, "Lighthouse", "")], "20231003"),
+            (vec![bing_image("20231003", url, "", ""), bing_image("20231002", url, "Lighthouse", ""), bing_image("20231001", url, "Lighthouse", "")], "20231002"),
+        ] {
+            let best = select_best(images).unwrap().get_image_file_name();
+            assert!(best.starts_with(expected), "{} is not from {}", best, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn duplicates_are_hardlinked() {
+        use std::os::unix::fs::MetadataExt;
+
+        let directory = tempfile::tempdir().unwrap();
+        let original = directory.path().join("20231001-en-US-Lighthouse.jpg");
+        tokio::fs::write(&original, b"jpeg").await.unwrap();
+        let path = directory.path().join("de-DE").join("20231001-de-DE-Leuchtturm.jpg");
+
+        link_duplicate(&original, &path).await;
+
+        let original_metadata = tokio::fs::metadata(&original).await.unwrap();
+        let metadata = tokio::fs::metadata(&path).await.unwrap();
+        assert_eq!(metadata.ino(), original_metadata.ino());
+        assert_eq!(metadata.dev(), original_metadata.dev());
+        assert_eq!(original_metadata.nlink(), 2);
+    }
+
+    #[tokio::test]
+    async fn pruned_originals_are_replaced_by_the_next_copy() {
+        use std::os::unix::fs::MetadataExt;
+
+        let directory = tempfile::tempdir().unwrap();
+        let server = MockServer::start(vec![
+            Response::json(ARCHIVE_RESPONSE),
+            Response::jpeg(JPEG),
+            Response::json(ARCHIVE_RESPONSE),
+            Response::jpeg(JPEG),
+            Response::json(ARCHIVE_RESPONSE),
+        ]).await;
+        let configuration = Configuration {
+            pictures_directory: Some(directory.path().to_string_lossy().to_string()),
+            ..Configuration::default()
+        };
+        let manager = Manager::new(Bing::new().unwrap().with_base_url(&server.base_url()), configuration);
+        let (original, _) = manager.poll_picture(&Market::EnglishUS, false).await;
+        assert!(manager.remove_picture(&original.unwrap().path).await);
+
+        let (copy, _) = manager.poll_picture(&Market::GermanGermany, false).await;
+        let (duplicate, _) = manager.poll_picture(&Market::JapaneseJapan, false).await;
+
+        // the copy was downloaded again, the duplicate of it only linked
+        assert_eq!(server.requests().len(), 5);
+        let copy = tokio::fs::metadata(copy.unwrap().path).await.unwrap();
+        let duplicate = tokio::fs::metadata(duplicate.unwrap().path).await.unwrap();
+        assert_eq!(duplicate.ino(), copy.ino());
+    }
+
+    #[tokio::test]
+    async fn existing_pictures_are_not_linked() {
+        use std::os::unix::fs::MetadataExt;
+
+        let directory = tempfile::tempdir().unwrap();
+        let original = directory.path().join("20231001-en-US-Lighthouse.jpg");
+        let path = directory.path().join("20231001-de-DE-Leuchtturm.jpg");
+        tokio::fs::write(&original, b"jpeg").await.unwrap();
+        tokio::fs::write(&path, b"other").await.unwrap();
+
+        link_duplicate(&original, &path).await;
+        link_duplicate(&original, &original).await;
+
+        assert_eq!(tokio::fs::read(&path).await.unwrap(), b"other");
+        assert_ne!(tokio::fs::metadata(&path).await.unwrap().ino(), tokio::fs::metadata(&original).await.unwrap().ino());
+        assert_eq!(tokio::fs::metadata(&original).await.unwrap().nlink(), 1);
+    }
+
+    #[tokio::test]
+    async fn missing_originals_are_not_linked() {
+        let directory = tempfile::tempdir().unwrap();
+        let original = directory.path().join("20231001-en-US-Lighthouse.jpg");
+        let path = directory.path().join("20231001-de-DE-Leuchtturm.jpg");
+
+        link_duplicate(&original, &path).await;
+
+        assert!(!tokio::fs::try_exists(&path).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn loads_the_configuration_file() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("config.toml");
+        tokio::fs::write(&path, "market = \"de-DE\"\npictures_directory = \"/tmp/pictures\"\n").await.unwrap();
+
+        let configuration = Configuration::load_from(&path).await.unwrap();
+
+        assert_eq!(configuration.market, Market::GermanGermany);
+        assert_eq!(configuration.get_pictures_directory(), PathBuf::from("/tmp/pictures"));
+    }
+
+    #[tokio::test]
+    async fn loads_defaults_without_a_configuration_file() {
+        let directory = tempfile::tempdir().unwrap();
+
+        let configuration = Configuration::load_from(&directory.path().join("config.toml")).await.unwrap();
+
+        assert_eq!(configuration.market, Market::default());
+        assert_eq!(configuration.pictures_directory, None);
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_configuration_files() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("config.toml");
+        tokio::fs::write(&path, "market = ").await.unwrap();
+
+        assert!(matches!(Configuration::load_from(&path).await, Err(ConfigurationError::ParseError(..))));
+    }
+
+    #[tokio::test]
+    async fn persist_market_preserves_the_rest_of_the_file() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("config.toml");
+        tokio::fs::write(&path, "# my wallpapers\nmarket = \"en-US\" # default\npictures_directory = \"/tmp/pictures\"\n").await.unwrap();
+
+        Configuration::persist_market_to(&path, &Market::JapaneseJapan).await.unwrap();
+
+        let contents = tokio::fs::read_to_string(&path).await.unwrap();
+        assert!(contents.starts_with("# my wallpapers\n"));
+        assert!(contents.contains("market = \"ja-JP\""));
+        assert!(contents.contains("pictures_directory = \"/tmp/pictures\""));
+        assert_eq!(Configuration::parse_from(&path).await.unwrap().market, Market::JapaneseJapan);
+    }
+
+    #[tokio::test]
+    async fn persist_market_creates_the_file() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("bingdaily").join("config.toml");
+
+        Configuration::persist_market_to(&path, &Market::EnglishGB).await.unwrap();
+
+        assert_eq!(Configuration::parse_from(&path).await.unwrap().market, Market::EnglishGB);
+    }
+
+    #[tokio::test]
+    async fn persist_market_leaves_invalid_files_untouched() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("config.toml");
+        tokio::fs::write(&path, "market = ").await.unwrap();
+
+        assert!(matches!(Configuration::persist_market_to(&path, &Market::EnglishGB).await, Err(ConfigurationError::EditError(..))));
+        assert_eq!(tokio::fs::read_to_string(&path).await.unwrap(), "market = ");
+    }
+
+    /// Writes a small but valid picture of the given size to `path`.
+    fn write_jpeg(path: &Path, width: u32, height: u32) {
+        image::RgbImage::from_pixel(width, height, image::Rgb([40, 90, 160]))
+            .save_with_format(path, image::ImageFormat::Jpeg)
+            .unwrap();
+    }
+
+    #[test]
+    fn decodes_images_with_either_decoder() {
+        let directory = tempfile::tempdir().unwrap();
+        let jpeg = directory.path().join("lighthouse.jpg");
+        write_jpeg(&jpeg, 64, 36);
+        // the format is guessed from the contents, not the extension
+        let png = directory.path().join("glacier.jpg");
+        image::RgbImage::from_pixel(48, 30, image::Rgb([200, 10, 10]))
+            .save_with_format(&png, image::ImageFormat::Png)
+            .unwrap();
+        let corrupt = directory.path().join("corrupt.jpg");
+        let data = std::fs::read(&jpeg).unwrap();
+        std::fs::write(&corrupt, &data[..data.len() / 4]).unwrap();
+
+        for decoder in [JpegDecoder::Image, JpegDecoder::Zune] {
+            let image = decode_image(&jpeg, decoder).unwrap();
+            assert_eq!((image.width(), image.height()), (64, 36), "{:?}", decoder);
+            let image = decode_image(&png, decoder).unwrap();
+            assert_eq!((image.width(), image.height()), (48, 30), "{:?}", decoder);
+            assert!(decode_image(&corrupt, decoder).is_none(), "{:?}", decoder);
+            assert!(decode_image(&directory.path().join("missing.jpg"), decoder).is_none(), "{:?}", decoder);
+        }
+    }
+
+    #[tokio::test]
+    async fn thumbnails_replace_previous_ones_in_one_piece() {
+        let directory = tempfile::tempdir().unwrap();
+        let picture = directory.path().join("20231001-en-US-A.jpg");
+        write_jpeg(&picture, 1280, 720);
+        let configuration = Configuration {
+            pictures_directory: Some(directory.path().to_string_lossy().to_string()),
+            thumbnails: true,
+            ..Configuration::default()
+        };
+        let manager = Manager::new(Bing::new().unwrap(), configuration);
+
+        let thumbnail = manager.thumbnail(&picture, false).await.unwrap();
+        std::fs::write(&thumbnail, b"stale").unwrap();
+        assert_eq!(manager.thumbnail(&picture, true).await, Some(thumbnail.clone()));
+
+        assert_eq!(image::image_dimensions(&thumbnail).unwrap(), (THUMBNAIL_WIDTH, 180));
+        assert_eq!(cached_names(thumbnail.parent().unwrap()).await, ["20231001-en-US-A.jpg"]);
+    }
+
+    #[tokio::test]
+    async fn verifying_the_cache_removes_corrupt_pictures() {
+        let directory = tempfile::tempdir().unwrap();
+        write_jpeg(&directory.path().join("20231001-en-US-A.jpg"), 16, 9);
+        write_jpeg(&directory.path().join("20231002-en-US-B.jpg"), 16, 9);
+        let valid = std::fs::read(directory.path().join("20231002-en-US-B.jpg")).unwrap();
+        tokio::fs::write(directory.path().join("20231003-en-US-C.jpg"), &valid[..16]).await.unwrap();
+        tokio::fs::write(directory.path().join("20231004-en-US-D.jpg"), b"<html>rate limited</html>").await.unwrap();
+        let manager = cleanup_manager(directory.path(), 10);
+
+        assert_eq!(manager.verify_cache().await, 2);
+
+        assert_eq!(cached_names(directory.path()).await, ["20231001-en-US-A.jpg", "20231002-en-US-B.jpg"]);
+        assert_eq!(manager.verify_cache().await, 0);
+    }
+
+    #[tokio::test]
+    async fn verifying_the_cache_removes_pictures_truncated_in_their_scan_data() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("20231001-en-US-A.jpg");
+        image::RgbImage::from_fn(256, 256, |x, y| image::Rgb([((x * 7) ^ (y * 13)) as u8, (x * y) as u8, (x + y) as u8]))
+            .save_with_format(&path, image::ImageFormat::Jpeg)
+            .unwrap();
+        let data = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &data[..data.len() / 2]).unwrap();
+        // the header is intact, only pixels are missing
+        assert!(image::image_dimensions(&path).is_ok());
+        let manager = cleanup_manager(directory.path(), 10);
+
+        assert_eq!(manager.verify_cache().await, 1);
+
+        assert!(cached_names(directory.path()).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn maintenance_removes_stale_partial_files() {
+        let directory = tempfile::tempdir().unwrap();
+        let partition = directory.path().join("2023").join("10");
+        tokio::fs::create_dir_all(&partition).await.unwrap();
+        let stale = [
+            directory.path().join(".20231001-en-US-A.jpg.part"),
+            partition.join(".20231002-en-US-B.jpg.part"),
+            directory.path().join(".hashes.json.1234-0.part"),
+        ];
+        let fresh = partition.join(".20231003-en-US-C.jpg.part");
+        for path in stale.iter().chain([&fresh]) {
+            tokio::fs::write(path, b"partial").await.unwrap();
+        }
+        let two_days_ago = std::time::SystemTime::now() - std::time::Duration::from_secs(2 * 24 * 60 * 60);
+        for path in &stale {
+            std::fs::File::options().write(true).open(path).unwrap().set_modified(two_days_ago).unwrap();
+        }
+        let manager = cleanup_manager(directory.path(), 10);
+
+        let report = manager.run_maintenance().await;
+
+        assert_eq!(report.partial, 3);
+        for path in &stale {
+            assert!(!tokio::fs::try_exists(path).await.unwrap(), "{}", path.display());
+        }
+        assert!(tokio::fs::try_exists(&fresh).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn maintenance_prunes_old_and_removes_corrupt_pictures_in_one_run() {
+        let directory = tempfile::tempdir().unwrap();
+        let old = directory.path().join("20230901-en-US-Old.jpg");
+        let corrupt = directory.path().join("20231008-en-US-Corrupt.jpg");
+        let valid = directory.path().join("20231009-en-US-Valid.jpg");
+        write_jpeg(&old, 16, 9);
+        write_jpeg(&corrupt, 64, 36);
+        let data = std::fs::read(&corrupt).unwrap();
+        std::fs::write(&corrupt, &data[..data.len() / 2]).unwrap();
+        write_jpeg(&valid, 16, 9);
+        let configuration = Configuration {
+            pictures_directory: Some(directory.path().to_string_lossy().to_string()),
+            maintenance: MaintenanceConfiguration { retention_days: Some(7), ..MaintenanceConfiguration::default() },
+            ..Configuration::default()
+        };
+        let manager = Manager::new(Bing::new().unwrap(), configuration)
+            .with_clock(Arc::new(ManualClock::new(utc("2023-10-10T08:00:00Z"))));
+
+        let report = manager.run_maintenance().await;
+
+        assert_eq!((report.pruned, report.corrupt), (1, 1));
+        assert_eq!(cached_names(directory.path()).await, ["20231009-en-US-Valid.jpg"]);
+    }
+}
@@ -0,0 +1,191 @@
+//! Counters of the manager's activity, exposed in Prometheus' text format over HTTP with the
+//! `metrics` feature.
+
+use std::fmt::Write;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use chrono::{DateTime, Utc};
+
+/// Activity of a [crate::manager::Manager] since it was created.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    polls: AtomicU64,
+    poll_failures: AtomicU64,
+    pruned_pictures: AtomicU64,
+    /// Shared with the [crate::bing::Bing] client, which counts every chunk it downloads.
+    download_bytes: Arc<AtomicU64>,
+    /// Unix timestamp of the last successful poll, zero if none succeeded yet.
+    last_success: AtomicI64,
+}
+
+impl Metrics {
+    pub fn record_poll(&self, success: bool, now: DateTime<Utc>) {
+        self.polls.fetch_add(1, Ordering::Relaxed);
+        if success {
+            self.last_success.store(now.timestamp(), Ordering::Relaxed);
+        } else {
+            self.poll_failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn record_pruned(&self) {
+        self.pruned_pictures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counter of bytes downloaded, to be passed to [crate::bing::Bing::with_download_counter].
+    pub(crate) fn download_counter(&self) -> Arc<AtomicU64> {
+        self.download_bytes.clone()
+    }
+
+    /// Renders the counters in Prometheus' text exposition format.
+    pub fn render(&self) -> String {
+        let metrics = [
+            ("bingdaily_polls_total", "counter", "Polls for a new picture.", self.polls.load(Ordering::Relaxed) as i64),
+            ("bingdaily_poll_failures_total", "counter", "Polls that produced no picture.", self.poll_failures.load(Ordering::Relaxed) as i64),
+            ("bingdaily_pruned_pictures_total", "counter", "Cached pictures removed.", self.pruned_pictures.load(Ordering::Relaxed) as i64),
+            ("bingdaily_download_bytes_total", "counter", "Bytes of pictures downloaded.", self.download_bytes.load(Ordering::Relaxed) as i64),
+            ("bingdaily_last_success_timestamp_seconds", "gauge", "Time of the last successful poll.", self.last_success.load(Ordering::Relaxed)),
+        ];
+
+        let mut output = String::new();
+        for (name, kind, help, value) in metrics {
+            let _ = writeln!(output, "# HELP {} {}", name, help);
+            let _ = writeln!(output, "# TYPE {} {}", name, kind);
+            let _ = writeln!(output, "{} {}", name, value);
+        }
+        output
+    }
+}
+
+/// Serves the metrics to every HTTP request made to `address`, until binding fails.
+#[cfg(feature = "metrics")]
+pub async fn serve(address: SocketAddr, metrics: &Metrics) -> io::Result<()> {
+    use std::time::Duration;
+    use log::{debug, warn};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    let listener = TcpListener::bind(address).await?;
+    debug!("Serving metrics at http://{}/", address);
+    loop {
+        let mut stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                warn!("Failed to accept metrics connection: {}", err);
+                continue;
+            }
+        };
+
+        // the request itself is irrelevant, every path serves the metrics
+        let mut request = [0u8; 1024];
+        let _ = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut request)).await;
+
+        let body = metrics.render();
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body,
+        );
+        let _ = stream.write_all(response.as_bytes()).await;
+        let _ = stream.shutdown().await;
+    }
+}
+
+/// Without the `metrics` feature there is no HTTP server, so the configured address is ignored.
+#[cfg(not(feature = "metrics"))]
+pub async fn serve(address: SocketAddr, _metrics: &Metrics) -> io::Result<()> {
+    log::warn!("Not serving metrics at {}, bingdaily was built without the metrics feature", address);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utc(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    /// Value of every sample in Prometheus' text format, checking that each is preceded by its
+    /// HELP and TYPE lines.
+    fn samples(output: &str) -> Vec<(String, i64)> {
+        let lines = output.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len() % 3, 0, "{}", output);
+        lines.chunks(3)
+            .map(|lines| {
+                let (name, value) = lines[2].split_once(' ').unwrap();
+                assert!(lines[0].starts_with(&format!("# HELP {} ", name)), "{}", lines[0]);
+                let kind = lines[1].strip_prefix(&format!("# TYPE {} ", name)).unwrap();
+                assert!(["counter", "gauge"].contains(&kind), "{}", lines[1]);
+                assert!(name.bytes().all(|byte| byte.is_ascii_lowercase() || byte == b'_'), "{}", name);
+                (name.to_owned(), value.parse().unwrap())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn renders_nothing_recorded() {
+        assert_eq!(samples(&Metrics::default().render()), [
+            ("bingdaily_polls_total".to_owned(), 0),
+            ("bingdaily_poll_failures_total".to_owned(), 0),
+            ("bingdaily_pruned_pictures_total".to_owned(), 0),
+            ("bingdaily_download_bytes_total".to_owned(), 0),
+            ("bingdaily_last_success_timestamp_seconds".to_owned(), 0),
+        ]);
+    }
+
+    #[test]
+    fn renders_recorded_activity() {
+        let metrics = Metrics::default();
+        metrics.record_poll(false, utc("2023-10-05T07:00:00Z"));
+        metrics.record_poll(true, utc("2023-10-05T07:05:00Z"));
+        metrics.record_poll(false, utc("2023-10-06T07:00:00Z"));
+        metrics.record_pruned();
+        metrics.record_pruned();
+        metrics.download_counter().fetch_add(4096, Ordering::Relaxed);
+        metrics.download_counter().fetch_add(1500, Ordering::Relaxed);
+
+        let output = metrics.render();
+
+        assert_eq!(samples(&output), [
+            ("bingdaily_polls_total".to_owned(), 3),
+            ("bingdaily_poll_failures_total".to_owned(), 2),
+            ("bingdaily_pruned_pictures_total".to_owned(), 2),
+            ("bingdaily_download_bytes_total".to_owned(), 5596),
+            ("bingdaily_last_success_timestamp_seconds".to_owned(), 1696489500),
+        ]);
+        assert!(output.contains("# TYPE bingdaily_polls_total counter\n"));
+        assert!(output.contains("# TYPE bingdaily_last_success_timestamp_seconds gauge\n"));
+    }
+
+    #[cfg(feature = "metrics")]
+    #[tokio::test]
+    async fn serves_rendered_metrics() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::{TcpListener, TcpStream};
+
+        let address = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let metrics: &'static Metrics = Box::leak(Box::default());
+        metrics.record_poll(true, utc("2023-10-05T07:05:00Z"));
+        tokio::spawn(serve(address, metrics));
+
+        let mut stream = loop {
+            match TcpStream::connect(address).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::task::yield_now().await,
+            }
+        };
+        stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{}", head);
+        assert!(head.contains("Content-Type: text/plain; version=0.0.4"), "{}", head);
+        assert!(head.contains(&format!("Content-Length: {}", body.len())), "{}", head);
+        assert_eq!(body, metrics.render());
+        assert_eq!(samples(body)[0], ("bingdaily_polls_total".to_owned(), 1));
+    }
+}
@@ -0,0 +1,109 @@
+//! Detection of regained connectivity through NetworkManager, so a picture missed while offline
+//! is downloaded right away.
+
+use log::{debug, warn};
+use tokio::sync::mpsc;
+use zbus::{dbus_proxy, Connection};
+use zbus::export::futures_util::{Stream, StreamExt};
+
+/// NetworkManager's `NM_STATE_CONNECTED_GLOBAL`, full internet connectivity.
+const NM_STATE_CONNECTED_GLOBAL: u32 = 70;
+
+#[dbus_proxy(
+    interface = "org.freedesktop.NetworkManager",
+    default_service = "org.freedesktop.NetworkManager",
+    default_path = "/org/freedesktop/NetworkManager"
+)]
+trait NetworkManager {
+    #[dbus_proxy(signal)]
+    fn state_changed(&self, state: u32) -> zbus::Result<()>;
+}
+
+/// Requests a refresh through `refresh` whenever NetworkManager reports that full connectivity
+/// was regained. Returns if NetworkManager is unavailable.
+pub async fn watch_network(refresh: mpsc::Sender<bool>) {
+    let result: zbus::Result<()> = async {
+        let connection = Connection::system().await?;
+        let network_manager = NetworkManagerProxy::new(&connection).await?;
+        let state_changes = network_manager.receive_state_changed().await?
+            .filter_map(|signal| async move { signal.args().ok().map(|args| args.state) });
+        refresh_on_connectivity(state_changes, &refresh).await;
+        Ok(())
+    }.await;
+
+    if let Err(err) = result {
+        warn!("Not watching network connectivity: {}", err);
+    }
+}
+
+/// Requests a refresh through `refresh` whenever one of the NetworkManager `states` regains full
+/// connectivity. Returns once the states end or the receiver is dropped.
+async fn refresh_on_connectivity(states: impl Stream<Item = u32>, refresh: &mpsc::Sender<bool>) {
+    // NetworkManager only signals changes, so assume being offline until one arrives; any extra
+    // refresh this causes is harmless
+    let mut connected = false;
+
+    let mut states = std::pin::pin!(states);
+    while let Some(state) = states.next().await {
+        let was_connected = connected;
+        connected = state == NM_STATE_CONNECTED_GLOBAL;
+        if connected && !was_connected {
+            debug!("Network connectivity regained, refreshing");
+            if refresh.send(false).await.is_err() {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // NetworkManager's `NM_STATE_DISCONNECTED` and `NM_STATE_CONNECTED_SITE`
+    const DISCONNECTED: u32 = 20;
+    const CONNECTED_SITE: u32 = 60;
+
+    async fn refreshes(states: Vec<u32>) -> usize {
+        let (refresh_tx, mut refresh_rx) = mpsc::channel(16);
+        refresh_on_connectivity(tokio_stream::iter(states), &refresh_tx).await;
+        drop(refresh_tx);
+        let mut count = 0;
+        while let Some(force) = refresh_rx.recv().await {
+            assert!(!force);
+            count += 1;
+        }
+        count
+    }
+
+    #[tokio::test]
+    async fn refreshes_once_per_regained_connection() {
+        for (states, expected) in [
+            (vec![], 0),
+            (vec![DISCONNECTED], 0),
+            (vec![NM_STATE_CONNECTED_GLOBAL], 1),
+            (vec![DISCONNECTED, NM_STATE_CONNECTED_GLOBAL], 1),
+            (vec![NM_STATE_CONNECTED_GLOBAL, DISCONNECTED, NM_STATE_CONNECTED_GLOBAL], 2),
+            (vec![DISCONNECTED, CONNECTED_SITE, NM_STATE_CONNECTED_GLOBAL, CONNECTED_SITE, NM_STATE_CONNECTED_GLOBAL], 2),
+        ] {
+            assert_eq!(refreshes(states.clone()).await, expected, "{:?}", states);
+        }
+    }
+
+    #[tokio::test]
+    async fn repeated_connected_states_do_not_refresh_again() {
+        let states = vec![DISCONNECTED, NM_STATE_CONNECTED_GLOBAL, NM_STATE_CONNECTED_GLOBAL, NM_STATE_CONNECTED_GLOBAL];
+
+        assert_eq!(refreshes(states).await, 1);
+    }
+
+    #[tokio::test]
+    async fn stops_once_the_receiver_is_dropped() {
+        let (refresh_tx, refresh_rx) = mpsc::channel(16);
+        drop(refresh_rx);
+
+        // would never return if it kept waiting for states
+        let states = tokio_stream::iter([NM_STATE_CONNECTED_GLOBAL]).chain(tokio_stream::pending());
+        refresh_on_connectivity(states, &refresh_tx).await;
+    }
+}
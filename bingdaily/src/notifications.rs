@@ -0,0 +1,163 @@
+//! Desktop notifications announcing new pictures, sent through `org.freedesktop.Notifications`.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use zbus::{dbus_proxy, Connection};
+use zbus::export::futures_util::future::BoxFuture;
+use zbus::zvariant::Value;
+use crate::manager::PolledPicture;
+use crate::state::{load_state, persist_state, VersionedState};
+
+#[dbus_proxy(
+    interface = "org.freedesktop.Notifications",
+    default_service = "org.freedesktop.Notifications",
+    default_path = "/org/freedesktop/Notifications"
+)]
+trait Notifications {
+    #[allow(clippy::too_many_arguments)]
+    fn notify(
+        &self,
+        app_name: &str,
+        replaces_id: u32,
+        app_icon: &str,
+        summary: &str,
+        body: &str,
+        actions: &[&str],
+        hints: HashMap<&str, Value<'_>>,
+        expire_timeout: i32,
+    ) -> zbus::Result<u32>;
+}
+
+/// Remembers the last picture a notification was sent for, so restarting on the same day doesn't
+/// announce the same picture again.
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct NotificationState {
+    last_notified: Option<String>,
+}
+
+impl VersionedState for NotificationState {
+    const VERSION: u32 = 1;
+}
+
+fn state_file() -> PathBuf {
+    match directories::BaseDirs::new() {
+        Some(base_dirs) => base_dirs.state_dir()
+            .unwrap_or_else(|| base_dirs.data_local_dir())
+            .join("bingdaily")
+            .join("notifications.json"),
+        None => PathBuf::from("~/.local/state/bingdaily/notifications.json"),
+    }
+}
+
+/// Receives the notifications announcing new pictures.
+pub trait NotificationSink: Send + Sync {
+    fn notify<'a>(&'a self, picture: &'a PolledPicture) -> BoxFuture<'a, zbus::Result<()>>;
+}
+
+impl NotificationSink for NotificationsProxy<'static> {
+    fn notify<'a>(&'a self, picture: &'a PolledPicture) -> BoxFuture<'a, zbus::Result<()>> {
+        Box::pin(async move {
+            let icon = picture.path.to_string_lossy();
+            NotificationsProxy::notify(self, "bingdaily", 0, &icon, "New Bing picture", &picture.title, &[], HashMap::new(), -1).await?;
+            Ok(())
+        })
+    }
+}
+
+pub struct Notifier {
+    sink: Box<dyn NotificationSink>,
+    state_path: PathBuf,
+    state: Mutex<NotificationState>,
+}
+
+impl Notifier {
+    pub async fn new(connection: &Connection) -> zbus::Result<Notifier> {
+        let proxy = NotificationsProxy::new(connection).await?;
+        Ok(Notifier::with_sink(Box::new(proxy), state_file()).await)
+    }
+
+    /// Sends the notifications to `sink`, remembering the last announced picture in the state
+    /// file at `state_path`.
+    pub async fn with_sink(sink: Box<dyn NotificationSink>, state_path: PathBuf) -> Notifier {
+        let state = load_state(&state_path).await.unwrap_or_else(|err| {
+            warn!("{}, notifications may be repeated", err);
+            NotificationState::default()
+        });
+        Notifier { sink, state_path, state: Mutex::new(state) }
+    }
+
+    /// Announces the picture, unless it has been announced before.
+    pub async fn notify_new_picture(&self, picture: &PolledPicture) {
+        // file names identify the picture by date, market and title
+        let identity = picture.path.file_name().unwrap_or_default().to_string_lossy().to_string();
+
+        let mut state = self.state.lock().await;
+        if state.last_notified.as_ref() == Some(&identity) {
+            debug!("Already notified about {}", identity);
+            return;
+        }
+
+        if let Err(err) = self.sink.notify(picture).await {
+            warn!("Failed to send notification: {}", err);
+            return;
+        }
+
+        state.last_notified = Some(identity);
+        if let Err(err) = persist_state(&self.state_path, &*state).await {
+            warn!("{}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex as StdMutex};
+    use tempfile::TempDir;
+    use super::*;
+
+    /// Records the titles of the pictures it was notified about.
+    #[derive(Clone, Default)]
+    struct RecordingSink {
+        notified: Arc<StdMutex<Vec<String>>>,
+    }
+
+    impl NotificationSink for RecordingSink {
+        fn notify<'a>(&'a self, picture: &'a PolledPicture) -> BoxFuture<'a, zbus::Result<()>> {
+            self.notified.lock().unwrap().push(picture.title.clone());
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    fn picture(file_name: &str, title: &str) -> PolledPicture {
+        PolledPicture {
+            path: PathBuf::from("/pictures").join(file_name),
+            title: title.to_owned(),
+            copyright: String::new(),
+            market: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn notifies_only_about_new_pictures_across_restarts() {
+        let dir = TempDir::new().unwrap();
+        let state_path = dir.path().join("notifications.json");
+        let lighthouse = picture("20230815-en-US Lighthouse.jpg", "Lighthouse");
+        let sink = RecordingSink::default();
+
+        let notifier = Notifier::with_sink(Box::new(sink.clone()), state_path.clone()).await;
+        notifier.notify_new_picture(&lighthouse).await;
+        notifier.notify_new_picture(&lighthouse).await;
+        assert_eq!(*sink.notified.lock().unwrap(), ["Lighthouse"]);
+
+        // restarting on the same day finds the picture already announced
+        let restarted = Notifier::with_sink(Box::new(sink.clone()), state_path).await;
+        restarted.notify_new_picture(&lighthouse).await;
+        assert_eq!(*sink.notified.lock().unwrap(), ["Lighthouse"]);
+
+        restarted.notify_new_picture(&picture("20230816-en-US Glacier.jpg", "Glacier")).await;
+        assert_eq!(*sink.notified.lock().unwrap(), ["Lighthouse", "Glacier"]);
+    }
+}
@@ -0,0 +1,71 @@
+//! Wallpaper engines pictures are applied through.
+
+use std::path::Path;
+use hyprpaper::{Hyprpaper, HyprpaperError};
+use thiserror::Error;
+
+#[cfg(feature = "layer-shell")]
+use crate::layer_shell::LayerShellError;
+
+#[derive(Debug, Error)]
+pub enum BackendError {
+    #[error(transparent)]
+    Hyprpaper(#[from] HyprpaperError),
+    #[cfg(feature = "layer-shell")]
+    #[error(transparent)]
+    LayerShell(#[from] LayerShellError),
+}
+
+/// Wallpaper engine showing pictures on monitors. Pictures are preloaded before they are applied
+/// and unloaded once no monitor shows them anymore.
+pub trait WallpaperBackend: Send + Sync {
+    fn preload(&self, path: &Path) -> Result<(), BackendError>;
+
+    fn unload(&self, path: &Path) -> Result<(), BackendError>;
+
+    /// Checks that the engine is able to load the picture's format.
+    fn check_format(&self, _path: &Path) -> Result<(), BackendError> {
+        Ok(())
+    }
+
+    /// Whether the picture is loaded already, in which case preloading it again is unnecessary.
+    fn is_loaded(&self, path: &Path) -> bool;
+
+    /// Shows the picture on the monitor with the given name, or identifier from the engine's
+    /// configuration.
+    fn set_wallpaper(&self, monitor: &str, path: &Path) -> Result<(), BackendError>;
+
+    /// Shows the picture on the monitor whose description starts with `description`.
+    fn set_wallpaper_by_description(&self, description: &str, path: &Path) -> Result<(), BackendError>;
+}
+
+impl WallpaperBackend for Hyprpaper {
+    fn preload(&self, path: &Path) -> Result<(), BackendError> {
+        Hyprpaper::preload(self, path)?;
+        Ok(())
+    }
+
+    fn unload(&self, path: &Path) -> Result<(), BackendError> {
+        Hyprpaper::unload(self, path)?;
+        Ok(())
+    }
+
+    fn check_format(&self, path: &Path) -> Result<(), BackendError> {
+        Hyprpaper::check_format(self, path)?;
+        Ok(())
+    }
+
+    fn is_loaded(&self, path: &Path) -> bool {
+        Hyprpaper::is_loaded(self, path)
+    }
+
+    fn set_wallpaper(&self, monitor: &str, path: &Path) -> Result<(), BackendError> {
+        Hyprpaper::set_wallpaper(self, monitor, path)?;
+        Ok(())
+    }
+
+    fn set_wallpaper_by_description(&self, description: &str, path: &Path) -> Result<(), BackendError> {
+        Hyprpaper::set_wallpaper_by_description(self, description, path)?;
+        Ok(())
+    }
+}
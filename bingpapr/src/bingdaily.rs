@@ -19,6 +19,8 @@
 //!
 //! …consequently `zbus-xmlgen` did not generate code for the above interfaces.
 
+use std::collections::HashMap;
+
 use zbus::dbus_proxy;
 
 #[dbus_proxy(
@@ -27,7 +29,50 @@ use zbus::dbus_proxy;
     default_path = "/net/boothwhack/BingDaily1"
 )]
 trait BingDaily1 {
+    /// GetImageData method
+    fn get_image_data(&self) -> zbus::Result<(Vec<u8>, String)>;
+
+    /// Refresh method
+    fn refresh(&self, force: bool) -> zbus::Result<()>;
+
+    /// ReloadConfig method
+    fn reload_config(&self) -> zbus::Result<String>;
+
+    /// SetDisplaySize method
+    fn set_display_size(&self, width: u32, height: u32) -> zbus::Result<()>;
+
+    /// SetMarket method
+    fn set_market(&self, market: &str) -> zbus::Result<()>;
+
+    /// PictureChanged signal
+    #[dbus_proxy(signal)]
+    fn picture_changed(&self, path: &str, title: &str, copyright: &str) -> zbus::Result<()>;
+
+    /// DownloadProgress signal
+    #[dbus_proxy(signal)]
+    fn download_progress(&self, downloaded: u64, total: u64) -> zbus::Result<()>;
+
     /// CurrentPicture property
     #[dbus_proxy(property)]
     fn current_picture(&self) -> zbus::Result<String>;
+
+    /// CurrentThumbnail property
+    #[dbus_proxy(property)]
+    fn current_thumbnail(&self) -> zbus::Result<String>;
+
+    /// Title property
+    #[dbus_proxy(property)]
+    fn title(&self) -> zbus::Result<String>;
+
+    /// Copyright property
+    #[dbus_proxy(property)]
+    fn copyright(&self) -> zbus::Result<String>;
+
+    /// CurrentMarket property
+    #[dbus_proxy(property)]
+    fn current_market(&self) -> zbus::Result<String>;
+
+    /// MarketPictures property
+    #[dbus_proxy(property)]
+    fn market_pictures(&self) -> zbus::Result<HashMap<String, String>>;
 }
@@ -0,0 +1,215 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use bingcommon::{DEFAULT_DBUS_NAME, DEFAULT_DBUS_PATH};
+use log::debug;
+use serde::{Deserialize, Deserializer};
+use thiserror::Error;
+
+/// Default number of wallpapers applied through hyprpaper at once.
+pub const DEFAULT_MAX_CONCURRENT_OPERATIONS: usize = 4;
+
+#[derive(Debug, Error)]
+pub enum ConfigurationError {
+    #[error("Failed to read configuration {0:?}: {1}")]
+    IoError(PathBuf, #[source] io::Error),
+    #[error("Failed to parse configuration {0:?}: {1}")]
+    ParseError(PathBuf, #[source] toml::de::Error),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct Configuration {
+    pub backend: BackendKind,
+    /// Per-monitor settings, keyed by monitor name. Monitors without an entry show bingdaily's
+    /// current picture.
+    pub monitors: HashMap<String, MonitorConfiguration>,
+    /// Target monitors by their description rather than their connector name when applying
+    /// wallpapers, as connector names may change between reboots.
+    pub target_by_description: bool,
+    /// Delay before the initial wallpaper is applied, giving the compositor time to settle at
+    /// login, e.g. `2s`.
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub startup_delay: Duration,
+    /// Wait until the list of monitors is non-empty and stable before applying the initial
+    /// wallpaper.
+    pub wait_for_monitors: bool,
+    /// Whether the hyprpaper build in use is able to load WebP images, which not every build
+    /// supports.
+    pub hyprpaper_webp: bool,
+    pub no_monitors: NoMonitorsBehavior,
+    /// Also apply wallpapers to headless outputs created by Hyprland, e.g. for remote streaming,
+    /// which are usually transient.
+    pub include_headless: bool,
+    /// Preload wallpapers hyprpaper rejects for not being preloaded and apply them again, e.g.
+    /// after hyprpaper was restarted.
+    pub hyprpaper_preload_missing: bool,
+    /// Maximum number of wallpapers applied through hyprpaper at once. Defaults to
+    /// [DEFAULT_MAX_CONCURRENT_OPERATIONS].
+    pub hyprpaper_max_concurrent_operations: usize,
+    /// Also apply wallpapers to the monitor identifiers found in hyprpaper's own configuration
+    /// which don't match any monitor reported by Hyprland, for setups where the two disagree on
+    /// naming.
+    pub hyprpaper_config_monitors: bool,
+    /// Defer wallpaper changes while a fullscreen window, e.g. a game or video, is present on the
+    /// active workspace, applying them once fullscreen ends.
+    pub defer_while_fullscreen: bool,
+    /// Well-known D-Bus name of the bingdaily instance to follow, for setups running several.
+    pub bingdaily_name: String,
+    /// Object path the bingdaily instance serves its interface at.
+    pub bingdaily_path: String,
+    /// Re-apply the current wallpapers at this interval, for setups where other tools
+    /// occasionally replace the wallpaper, e.g. `10m`. Pictures are neither downloaded nor
+    /// preloaded again.
+    #[serde(deserialize_with = "deserialize_optional_duration")]
+    pub reassert_interval: Option<Duration>,
+}
+
+/// Wallpaper engine pictures are applied through.
+#[derive(Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BackendKind {
+    /// A running hyprpaper instance.
+    #[default]
+    Hyprpaper,
+    /// Experimental engine built into bingpapr, drawing pictures onto wlr-layer-shell background
+    /// surfaces itself so no wallpaper daemon is needed. Requires the `layer-shell` feature.
+    LayerShell,
+}
+
+/// What to do when no monitors are connected at startup, e.g. on a headless boot or with a
+/// laptop's lid closed.
+#[derive(Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum NoMonitorsBehavior {
+    /// Keep running and apply the wallpaper once monitors are added.
+    #[default]
+    KeepRunning,
+    /// Exit right away.
+    Exit,
+}
+
+impl Default for Configuration {
+    fn default() -> Self {
+        Configuration {
+            backend: BackendKind::default(),
+            monitors: HashMap::new(),
+            target_by_description: false,
+            startup_delay: Duration::ZERO,
+            wait_for_monitors: false,
+            hyprpaper_webp: true,
+            no_monitors: NoMonitorsBehavior::default(),
+            include_headless: false,
+            hyprpaper_preload_missing: true,
+            hyprpaper_max_concurrent_operations: DEFAULT_MAX_CONCURRENT_OPERATIONS,
+            hyprpaper_config_monitors: false,
+            defer_while_fullscreen: false,
+            bingdaily_name: DEFAULT_DBUS_NAME.to_owned(),
+            bingdaily_path: DEFAULT_DBUS_PATH.to_owned(),
+            reassert_interval: None,
+        }
+    }
+}
+
+impl Configuration {
+    /// Location of the configuration file, `$XDG_CONFIG_HOME/bingpapr/config.toml`.
+    pub fn get_config_file() -> PathBuf {
+        match directories::BaseDirs::new() {
+            Some(base_dirs) => base_dirs.config_dir().join("bingpapr").join("config.toml"),
+            None => PathBuf::from("~/.config/bingpapr/config.toml"),
+        }
+    }
+
+    /// Loads the configuration file, using the default configuration when the file doesn't
+    /// exist.
+    pub fn load() -> Result<Configuration, ConfigurationError> {
+        Self::load_from(&Self::get_config_file())
+    }
+
+    pub fn load_from(path: &Path) -> Result<Configuration, ConfigurationError> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                debug!("No configuration at {}, using defaults", path.display());
+                return Ok(Configuration::default());
+            }
+            Err(err) => return Err(ConfigurationError::IoError(path.to_owned(), err)),
+        };
+
+        toml::from_str(&contents)
+            .map_err(|err| ConfigurationError::ParseError(path.to_owned(), err))
+    }
+}
+
+/// Deserializes a duration written like `1m 30s`.
+fn deserialize_duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    let duration = String::deserialize(deserializer)?;
+    humantime::parse_duration(&duration).map_err(serde::de::Error::custom)
+}
+
+fn deserialize_optional_duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+    deserialize_duration(deserializer).map(Some)
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct MonitorConfiguration {
+    /// Market whose picture is shown on the monitor, e.g. `ja-JP`. The market must be one of
+    /// bingdaily's additional markets, otherwise the current picture is shown.
+    pub market: Option<String>,
+    /// Picture shown on the monitor rather than one of bingdaily's, e.g. a fixed picture for a
+    /// portrait monitor. Takes precedence over `market`.
+    pub picture: Option<PathBuf>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_options() {
+        let configuration: Configuration = toml::from_str(r#"
+            backend = "layer_shell"
+            target_by_description = true
+            startup_delay = "2s"
+            reassert_interval = "10m"
+            no_monitors = "exit"
+            hyprpaper_max_concurrent_operations = 2
+
+            [monitors.DP-1]
+            market = "ja-JP"
+
+            [monitors.DP-2]
+            picture = "/home/user/portrait.jpg"
+        "#).unwrap();
+        assert_eq!(configuration.backend, BackendKind::LayerShell);
+        assert!(configuration.target_by_description);
+        assert_eq!(configuration.startup_delay, Duration::from_secs(2));
+        assert_eq!(configuration.reassert_interval, Some(Duration::from_secs(600)));
+        assert_eq!(configuration.no_monitors, NoMonitorsBehavior::Exit);
+        assert_eq!(configuration.hyprpaper_max_concurrent_operations, 2);
+        assert_eq!(configuration.monitors["DP-1"].market.as_deref(), Some("ja-JP"));
+        assert_eq!(configuration.monitors["DP-2"].picture.as_deref(), Some(Path::new("/home/user/portrait.jpg")));
+        // unset options keep their defaults
+        assert!(configuration.hyprpaper_webp);
+        assert_eq!(configuration.bingdaily_name, "net.boothwhack.BingDaily1");
+    }
+
+    #[test]
+    fn missing_file_uses_defaults() {
+        let directory = tempfile::tempdir().unwrap();
+        let configuration = Configuration::load_from(&directory.path().join("config.toml")).unwrap();
+        assert!(configuration.monitors.is_empty());
+        assert_eq!(configuration.backend, BackendKind::Hyprpaper);
+        assert_eq!(configuration.startup_delay, Duration::ZERO);
+    }
+
+    #[test]
+    fn invalid_file_fails() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("config.toml");
+        std::fs::write(&path, "startup_delay = \"soon\"").unwrap();
+        assert!(matches!(Configuration::load_from(&path), Err(ConfigurationError::ParseError(..))));
+    }
+}
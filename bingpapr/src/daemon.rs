@@ -0,0 +1,88 @@
+//! Detaching from the controlling terminal, for running without a service manager.
+
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::fd::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+
+/// Location of the log file written to while daemonized.
+pub fn log_file() -> PathBuf {
+    match directories::BaseDirs::new() {
+        Some(base_dirs) => base_dirs.state_dir()
+            .unwrap_or_else(|| base_dirs.data_local_dir())
+            .join("bingpapr")
+            .join("bingpapr.log"),
+        None => PathBuf::from("/tmp/bingpapr.log"),
+    }
+}
+
+fn check(result: libc::c_int) -> io::Result<libc::c_int> {
+    if result == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
+
+/// Opens the log file for appending, creating it and its directory if missing.
+fn open_log(path: &Path) -> io::Result<File> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Points the file descriptor `fd` at `file`, so whatever is written to `fd` ends up in `file`.
+fn redirect(file: &File, fd: RawFd) -> io::Result<()> {
+    // SAFETY: the file descriptors are open for the duration of the call
+    check(unsafe { libc::dup2(file.as_raw_fd(), fd) })?;
+    Ok(())
+}
+
+/// Forks into the background, exiting in the parent. The child starts a new session so it is
+/// never reacquired by a terminal, reads from `/dev/null` and writes stdout and stderr to
+/// [log_file]. Must be called before any threads are spawned.
+pub fn daemonize() -> io::Result<()> {
+    let log = open_log(&log_file())?;
+    let null = File::open("/dev/null")?;
+
+    // SAFETY: the process is still single threaded, see above
+    unsafe {
+        if check(libc::fork())? != 0 {
+            libc::_exit(0);
+        }
+        check(libc::setsid())?;
+        // fork again so the daemon, no longer a session leader, can't acquire a terminal
+        if check(libc::fork())? != 0 {
+            libc::_exit(0);
+        }
+    }
+
+    std::env::set_current_dir("/")?;
+    redirect(&null, libc::STDIN_FILENO)?;
+    redirect(&log, libc::STDOUT_FILENO)?;
+    redirect(&log, libc::STDERR_FILENO)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use tempfile::TempDir;
+    use super::*;
+
+    #[test]
+    fn redirects_output_into_the_log_file() {
+        let dir = TempDir::new().unwrap();
+        let log_path = dir.path().join("state").join("bingpapr").join("bingpapr.log");
+        writeln!(open_log(&log_path).unwrap(), "earlier run").unwrap();
+
+        let log = open_log(&log_path).unwrap();
+        // stands in for stdout, which the test harness needs
+        let mut output = File::create(dir.path().join("output")).unwrap();
+        redirect(&log, output.as_raw_fd()).unwrap();
+        writeln!(output, "daemon started").unwrap();
+
+        assert_eq!(fs::read_to_string(&log_path).unwrap(), "earlier run\ndaemon started\n");
+        assert_eq!(fs::read_to_string(dir.path().join("output")).unwrap(), "");
+    }
+}
@@ -0,0 +1,426 @@
+//! Experimental wallpaper engine drawing pictures onto wlr-layer-shell background surfaces itself,
+//! for setups without hyprpaper.
+
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+use image::imageops::{self, FilterType};
+use image::RgbImage;
+use log::{debug, warn};
+use smithay_client_toolkit::compositor::{CompositorHandler, CompositorState};
+use smithay_client_toolkit::output::{OutputHandler, OutputState};
+use smithay_client_toolkit::reexports::calloop::channel::{self, Channel, Sender};
+use smithay_client_toolkit::reexports::calloop::EventLoop;
+use smithay_client_toolkit::reexports::calloop_wayland_source::WaylandSource;
+use smithay_client_toolkit::registry::{ProvidesRegistryState, RegistryState};
+use smithay_client_toolkit::shell::wlr_layer::{
+    Anchor, KeyboardInteractivity, Layer, LayerShell, LayerShellHandler, LayerSurface, LayerSurfaceConfigure,
+};
+use smithay_client_toolkit::shell::WaylandSurface;
+use smithay_client_toolkit::shm::slot::{Buffer, SlotPool};
+use smithay_client_toolkit::shm::{CreatePoolError, Shm, ShmHandler};
+use smithay_client_toolkit::{
+    delegate_compositor, delegate_layer, delegate_output, delegate_registry, delegate_shm, registry_handlers,
+};
+use smithay_client_toolkit::reexports::client::globals::{registry_queue_init, BindError, GlobalError};
+use smithay_client_toolkit::reexports::client::protocol::{wl_output, wl_shm, wl_surface};
+use smithay_client_toolkit::reexports::client::{ConnectError, Connection, DispatchError, QueueHandle};
+use thiserror::Error;
+
+use crate::backend::{BackendError, WallpaperBackend};
+
+/// Namespace of the layer surfaces, allowing compositors to recognize them.
+const NAMESPACE: &str = "bingpapr";
+
+#[derive(Debug, Error)]
+pub enum LayerShellError {
+    #[error("failed to connect to the Wayland compositor: {0}")]
+    Connect(#[from] ConnectError),
+    #[error("failed to list the compositor's globals: {0}")]
+    Globals(#[from] GlobalError),
+    #[error("compositor lacks a required protocol: {0}")]
+    Bind(#[from] BindError),
+    #[error("failed to allocate shared memory: {0}")]
+    Pool(#[from] CreatePoolError),
+    #[error("failed to dispatch Wayland events: {0}")]
+    Dispatch(#[from] DispatchError),
+    #[error(transparent)]
+    IoError(#[from] io::Error),
+    #[error("failed to load image {0:?}: {1}")]
+    Image(PathBuf, #[source] image::ImageError),
+    #[error("image {0:?} is not preloaded")]
+    NotLoaded(PathBuf),
+    #[error("no output matches {0}")]
+    NoSuchOutput(String),
+    #[error("the Wayland event loop stopped")]
+    Stopped,
+}
+
+/// Output a picture is shown on, identified like monitors are for hyprpaper.
+#[derive(Debug)]
+enum Target {
+    Name(String),
+    /// Start of the output's description.
+    Description(String),
+}
+
+impl Target {
+    fn matches(&self, name: Option<&str>, description: Option<&str>) -> bool {
+        match self {
+            Target::Name(target) => name == Some(target.as_str()),
+            Target::Description(target) => description.is_some_and(|description| description.starts_with(target.as_str())),
+        }
+    }
+}
+
+impl Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::Name(name) => write!(f, "{}", name),
+            Target::Description(description) => write!(f, "desc:{}", description),
+        }
+    }
+}
+
+/// Request sent to the thread running the Wayland event loop.
+struct Show {
+    target: Target,
+    image: Arc<RgbImage>,
+    reply: mpsc::Sender<Result<(), LayerShellError>>,
+}
+
+/// Shows pictures on background layer surfaces, one per output, drawn from a thread running the
+/// Wayland event loop. Preloading decodes a picture so applying it only has to scale it.
+pub struct LayerShellBackend {
+    loaded: Mutex<HashMap<PathBuf, Arc<RgbImage>>>,
+    requests: Sender<Show>,
+}
+
+impl LayerShellBackend {
+    /// Connects to the compositor named by the environment and starts the event loop.
+    pub fn connect() -> Result<LayerShellBackend, LayerShellError> {
+        let (requests, channel) = channel::channel();
+        let (started_tx, started_rx) = mpsc::channel();
+        thread::Builder::new()
+            .name("layer-shell".to_owned())
+            .spawn(move || {
+                let (mut event_loop, mut engine) = match Engine::start(channel) {
+                    Ok(started) => {
+                        let _ = started_tx.send(Ok(()));
+                        started
+                    }
+                    Err(error) => {
+                        let _ = started_tx.send(Err(error));
+                        return;
+                    }
+                };
+                while !engine.stopped {
+                    if let Err(error) = event_loop.dispatch(None, &mut engine) {
+                        warn!("Wayland event loop failed: {}", error);
+                        break;
+                    }
+                }
+            })?;
+        started_rx.recv().map_err(|_| LayerShellError::Stopped)??;
+        Ok(LayerShellBackend { loaded: Mutex::new(HashMap::new()), requests })
+    }
+
+    fn show(&self, target: Target, path: &Path) -> Result<(), LayerShellError> {
+        let image = self.loaded.lock().unwrap().get(path).cloned()
+            .ok_or_else(|| LayerShellError::NotLoaded(path.to_owned()))?;
+        let (reply, replied) = mpsc::channel();
+        self.requests.send(Show { target, image, reply }).map_err(|_| LayerShellError::Stopped)?;
+        replied.recv().map_err(|_| LayerShellError::Stopped)?
+    }
+}
+
+impl WallpaperBackend for LayerShellBackend {
+    fn preload(&self, path: &Path) -> Result<(), BackendError> {
+        let image = image::open(path).map_err(|error| LayerShellError::Image(path.to_owned(), error))?;
+        self.loaded.lock().unwrap().insert(path.to_owned(), Arc::new(image.into_rgb8()));
+        Ok(())
+    }
+
+    fn unload(&self, path: &Path) -> Result<(), BackendError> {
+        // surfaces showing the picture keep their already drawn buffers
+        self.loaded.lock().unwrap().remove(path);
+        Ok(())
+    }
+
+    fn check_format(&self, path: &Path) -> Result<(), BackendError> {
+        image::ImageFormat::from_path(path).map_err(|error| LayerShellError::Image(path.to_owned(), error))?;
+        Ok(())
+    }
+
+    fn is_loaded(&self, path: &Path) -> bool {
+        self.loaded.lock().unwrap().contains_key(path)
+    }
+
+    fn set_wallpaper(&self, monitor: &str, path: &Path) -> Result<(), BackendError> {
+        Ok(self.show(Target::Name(monitor.to_owned()), path)?)
+    }
+
+    fn set_wallpaper_by_description(&self, description: &str, path: &Path) -> Result<(), BackendError> {
+        Ok(self.show(Target::Description(description.to_owned()), path)?)
+    }
+}
+
+/// Background surface covering a single output.
+struct Surface {
+    output: wl_output::WlOutput,
+    layer: LayerSurface,
+    image: Arc<RgbImage>,
+    /// Logical size, known once the compositor configured the surface.
+    size: Option<(u32, u32)>,
+    scale: i32,
+    /// Buffer currently attached, kept alive until it is replaced.
+    buffer: Option<Buffer>,
+}
+
+/// State of the Wayland event loop.
+struct Engine {
+    registry_state: RegistryState,
+    output_state: OutputState,
+    compositor: CompositorState,
+    layer_shell: LayerShell,
+    shm: Shm,
+    pool: SlotPool,
+    surfaces: Vec<Surface>,
+    stopped: bool,
+}
+
+impl Engine {
+    fn start(requests: Channel<Show>) -> Result<(EventLoop<'static, Engine>, Engine), LayerShellError> {
+        let connection = Connection::connect_to_env()?;
+        let (globals, event_queue) = registry_queue_init(&connection)?;
+        let qh = event_queue.handle();
+        let shm = Shm::bind(&globals, &qh)?;
+        let pool = SlotPool::new(1920 * 1080 * 4, &shm)?;
+        let mut engine = Engine {
+            registry_state: RegistryState::new(&globals),
+            output_state: OutputState::new(&globals, &qh),
+            compositor: CompositorState::bind(&globals, &qh)?,
+            layer_shell: LayerShell::bind(&globals, &qh)?,
+            shm,
+            pool,
+            surfaces: Vec::new(),
+            stopped: false,
+        };
+
+        let event_loop = EventLoop::try_new().map_err(io::Error::from)?;
+        let mut event_queue = event_queue;
+        // learn the outputs' names before the first picture is shown
+        event_queue.roundtrip(&mut engine)?;
+        WaylandSource::new(connection, event_queue).insert(event_loop.handle())
+            .map_err(|error| io::Error::from(error.error))?;
+        event_loop.handle().insert_source(requests, move |event, _, engine: &mut Engine| match event {
+            channel::Event::Msg(show) => {
+                let result = engine.show(&qh, show.target, show.image);
+                let _ = show.reply.send(result);
+            }
+            channel::Event::Closed => engine.stopped = true,
+        }).map_err(|error| io::Error::from(error.error))?;
+        Ok((event_loop, engine))
+    }
+
+    fn show(&mut self, qh: &QueueHandle<Engine>, target: Target, image: Arc<RgbImage>) -> Result<(), LayerShellError> {
+        let output = self.output_state.outputs()
+            .find(|output| self.output_state.info(output)
+                .is_some_and(|info| target.matches(info.name.as_deref(), info.description.as_deref())))
+            .ok_or_else(|| LayerShellError::NoSuchOutput(target.to_string()))?;
+
+        if let Some(index) = self.surfaces.iter().position(|surface| surface.output == output) {
+            self.surfaces[index].image = image;
+            self.draw(index);
+            return Ok(());
+        }
+
+        let scale = self.output_state.info(&output).map_or(1, |info| info.scale_factor);
+        let surface = self.compositor.create_surface(qh);
+        let layer = self.layer_shell.create_layer_surface(qh, surface, Layer::Background, Some(NAMESPACE), Some(&output));
+        layer.set_anchor(Anchor::all());
+        layer.set_exclusive_zone(-1);
+        layer.set_keyboard_interactivity(KeyboardInteractivity::None);
+        layer.set_size(0, 0);
+        // drawn once the compositor responds to the initial commit with the surface's size
+        layer.commit();
+        self.surfaces.push(Surface { output, layer, image, size: None, scale, buffer: None });
+        Ok(())
+    }
+
+    fn draw(&mut self, index: usize) {
+        let surface = &mut self.surfaces[index];
+        let Some((width, height)) = surface.size else {
+            return;
+        };
+        let scale = surface.scale.max(1);
+        let (width, height) = (width * scale as u32, height * scale as u32);
+        let pixels = surface_buffer(&surface.image, width, height);
+        let (buffer, canvas) = match self.pool.create_buffer(width as i32, height as i32, width as i32 * 4, wl_shm::Format::Xrgb8888) {
+            Ok(buffer) => buffer,
+            Err(error) => {
+                warn!("Failed to allocate a {}x{} buffer: {}", width, height, error);
+                return;
+            }
+        };
+        canvas.copy_from_slice(&pixels);
+
+        let wl_surface = surface.layer.wl_surface();
+        wl_surface.set_buffer_scale(scale);
+        wl_surface.damage_buffer(0, 0, width as i32, height as i32);
+        if let Err(error) = buffer.attach_to(wl_surface) {
+            warn!("Failed to attach buffer: {}", error);
+            return;
+        }
+        surface.layer.commit();
+        surface.buffer = Some(buffer);
+    }
+}
+
+/// Pixels of `image` covering an output of the given size in `XRGB8888`, scaled to fill it while
+/// keeping its aspect ratio and cropping the excess evenly from both sides.
+fn surface_buffer(image: &RgbImage, width: u32, height: u32) -> Vec<u8> {
+    let (image_width, image_height) = image.dimensions();
+    // largest part of the image with the output's aspect ratio
+    let (crop_width, crop_height) = if image_width as u64 * height as u64 > image_height as u64 * width as u64 {
+        (((image_height as u64 * width as u64) / height as u64).max(1) as u32, image_height)
+    } else {
+        (image_width, ((image_width as u64 * height as u64) / width as u64).max(1) as u32)
+    };
+    let cropped = imageops::crop_imm(image, (image_width - crop_width) / 2, (image_height - crop_height) / 2, crop_width, crop_height);
+    let scaled = imageops::resize(&*cropped, width, height, FilterType::Triangle);
+
+    let mut pixels = Vec::with_capacity(width as usize * height as usize * 4);
+    for pixel in scaled.pixels() {
+        let [r, g, b] = pixel.0;
+        // little endian 0xXXRRGGBB
+        pixels.extend_from_slice(&[b, g, r, 0xff]);
+    }
+    pixels
+}
+
+impl CompositorHandler for Engine {
+    fn scale_factor_changed(&mut self, _: &Connection, _: &QueueHandle<Self>, surface: &wl_surface::WlSurface, new_factor: i32) {
+        if let Some(index) = self.surfaces.iter().position(|s| s.layer.wl_surface() == surface) {
+            self.surfaces[index].scale = new_factor;
+            self.draw(index);
+        }
+    }
+
+    fn transform_changed(&mut self, _: &Connection, _: &QueueHandle<Self>, _: &wl_surface::WlSurface, _: wl_output::Transform) {}
+
+    fn frame(&mut self, _: &Connection, _: &QueueHandle<Self>, _: &wl_surface::WlSurface, _: u32) {}
+}
+
+impl OutputHandler for Engine {
+    fn output_state(&mut self) -> &mut OutputState {
+        &mut self.output_state
+    }
+
+    fn new_output(&mut self, _: &Connection, _: &QueueHandle<Self>, _: wl_output::WlOutput) {}
+
+    fn update_output(&mut self, _: &Connection, _: &QueueHandle<Self>, _: wl_output::WlOutput) {}
+
+    fn output_destroyed(&mut self, _: &Connection, _: &QueueHandle<Self>, output: wl_output::WlOutput) {
+        self.surfaces.retain(|surface| surface.output != output);
+    }
+}
+
+impl LayerShellHandler for Engine {
+    fn closed(&mut self, _: &Connection, _: &QueueHandle<Self>, layer: &LayerSurface) {
+        debug!("Compositor closed a background surface");
+        self.surfaces.retain(|surface| &surface.layer != layer);
+    }
+
+    fn configure(&mut self, _: &Connection, _: &QueueHandle<Self>, layer: &LayerSurface, configure: LayerSurfaceConfigure, _: u32) {
+        let Some(index) = self.surfaces.iter().position(|surface| &surface.layer == layer) else {
+            return;
+        };
+        let (width, height) = configure.new_size;
+        if width == 0 || height == 0 {
+            warn!("Compositor configured a background surface without a size");
+            return;
+        }
+        self.surfaces[index].size = Some((width, height));
+        self.draw(index);
+    }
+}
+
+impl ShmHandler for Engine {
+    fn shm_state(&mut self) -> &mut Shm {
+        &mut self.shm
+    }
+}
+
+impl ProvidesRegistryState for Engine {
+    fn registry(&mut self) -> &mut RegistryState {
+        &mut self.registry_state
+    }
+    registry_handlers![OutputState];
+}
+
+delegate_compositor!(Engine);
+delegate_output!(Engine);
+delegate_shm!(Engine);
+delegate_layer!(Engine);
+delegate_registry!(Engine);
+
+#[cfg(test)]
+mod tests {
+    use image::Rgb;
+    use super::*;
+
+    const RED: Rgb<u8> = Rgb([255, 0, 0]);
+    const BLUE: Rgb<u8> = Rgb([0, 0, 255]);
+
+    /// Image whose left half is red and right half is blue.
+    fn halves(width: u32, height: u32) -> RgbImage {
+        RgbImage::from_fn(width, height, |x, _| if x < width / 2 { RED } else { BLUE })
+    }
+
+    fn pixel(buffer: &[u8], width: u32, x: u32, y: u32) -> [u8; 4] {
+        let offset = ((y * width + x) * 4) as usize;
+        buffer[offset..offset + 4].try_into().unwrap()
+    }
+
+    #[test]
+    fn fills_the_output() {
+        for (width, height) in [(1, 1), (3, 2), (1920, 1080), (1080, 1920)] {
+            assert_eq!(surface_buffer(&halves(64, 36), width, height).len(), (width * height * 4) as usize, "{}x{}", width, height);
+        }
+    }
+
+    #[test]
+    fn writes_xrgb_in_little_endian() {
+        let buffer = surface_buffer(&RgbImage::from_pixel(4, 4, Rgb([10, 20, 30])), 2, 2);
+        assert_eq!(pixel(&buffer, 2, 1, 1), [30, 20, 10, 0xff]);
+    }
+
+    #[test]
+    fn crops_the_sides_of_wider_images() {
+        // only the middle two columns of the image fit a square output
+        let buffer = surface_buffer(&halves(4, 2), 2, 2);
+        assert_eq!(pixel(&buffer, 2, 0, 0), [0, 0, 255, 0xff]);
+        assert_eq!(pixel(&buffer, 2, 1, 1), [255, 0, 0, 0xff]);
+    }
+
+    #[test]
+    fn crops_the_top_and_bottom_of_taller_images() {
+        let image = RgbImage::from_fn(2, 6, |_, y| if y < 2 { RED } else if y < 4 { BLUE } else { RED });
+        let buffer = surface_buffer(&image, 2, 2);
+        for (x, y) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+            assert_eq!(pixel(&buffer, 2, x, y), [255, 0, 0, 0xff], "{}, {}", x, y);
+        }
+    }
+
+    #[test]
+    fn scales_images_to_the_output_size() {
+        let buffer = surface_buffer(&halves(16, 9), 32, 18);
+        assert_eq!(pixel(&buffer, 32, 0, 0), [0, 0, 255, 0xff]);
+        assert_eq!(pixel(&buffer, 32, 31, 17), [255, 0, 0, 0xff]);
+    }
+}
@@ -1,112 +1,814 @@
-use std::fmt::Debug;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Debug, Display};
+use std::future::Future;
 use std::io;
-use std::mem::swap;
 use std::path::{Path, PathBuf};
-use std::str::FromStr;
+use std::process::exit;
 use std::sync::Arc;
+use std::time::Duration;
 
+use hyprland::data::{FullscreenState, Monitor, Monitors};
 use hyprland::event_listener::EventListener;
 use hyprland::prelude::*;
-use log::{error, warn};
+use log::{debug, error, info, warn};
 use thiserror::Error;
-use tokio::{join, spawn};
-use tokio::sync::Mutex;
+use tokio::{join, select, spawn};
+use tokio::task::spawn_blocking;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{Mutex, Semaphore};
+use tokio::time::{Instant, sleep, sleep_until};
 use zbus::Connection;
+use zbus::export::futures_util::future::join_all;
 use zbus::export::futures_util::StreamExt;
 
-use hyprpaper::Hyprpaper;
+use hyprpaper::{Hyprpaper, ImageFormat};
 
+use crate::backend::{BackendError, WallpaperBackend};
+use crate::bingdaily::BingDaily1Proxy;
+
+use crate::configuration::{BackendKind, Configuration, DEFAULT_MAX_CONCURRENT_OPERATIONS, NoMonitorsBehavior};
+use crate::monitors::{Hyprland, MonitorSource};
+
+mod backend;
 mod bingdaily;
+pub mod configuration;
+mod daemon;
+#[cfg(feature = "layer-shell")]
+mod layer_shell;
+mod monitors;
+mod state;
 
 #[derive(Debug, Error)]
+#[allow(clippy::enum_variant_names)]
 enum ApplyWallpaperError {
     #[error(transparent)]
     HyprError(#[from] hyprland::shared::HyprError),
     #[error(transparent)]
-    HyprpaperError(#[from] hyprpaper::HyprpaperError),
+    BackendError(#[from] BackendError),
     #[error(transparent)]
     IoError(#[from] io::Error),
+    #[error("failed to apply wallpaper to {} monitor(s): {}", .0.len(), format_failures(.0))]
+    MonitorsError(Vec<MonitorFailure>),
+}
+
+/// Error applying a wallpaper to a single monitor.
+#[derive(Debug)]
+struct MonitorFailure {
+    monitor: String,
+    error: ApplyWallpaperError,
+}
+
+fn format_failures(failures: &[MonitorFailure]) -> String {
+    failures.iter()
+        .map(|failure| format!("{}: {}", failure.monitor, failure.error))
+        .collect::<Vec<_>>()
+        .join(", ")
 }
 
 struct BingPapr {
-    hyprpaper: Hyprpaper,
+    configuration: Configuration,
+    backend: Arc<dyn WallpaperBackend>,
+    /// Bounds the number of wallpapers applied at once, as hyprpaper is easily overwhelmed by many
+    /// simultaneous connections.
+    operations: Arc<Semaphore>,
+    monitors: Box<dyn MonitorSource>,
     active_picture: PathBuf,
+    /// Pictures of bingdaily's additional markets, keyed by market code.
+    market_pictures: HashMap<String, PathBuf>,
+    /// Picture last applied to each monitor, keyed by monitor name.
+    applied: HashMap<String, PathBuf>,
+    /// Monitor identifiers from hyprpaper's configuration.
+    configured_monitors: Vec<String>,
+    /// Whether a fullscreen window is present on the active workspace.
+    fullscreen: bool,
+    /// Active and market pictures whose application was deferred while fullscreen.
+    pending: Option<(PathBuf, HashMap<String, PathBuf>)>,
+    /// Previous pictures kept loaded because the new ones failed to apply to some monitors.
+    retained: HashSet<PathBuf>,
+}
+
+/// How the backend is told which monitor to apply a wallpaper to.
+enum MonitorTarget {
+    Name(String),
+    Description(String),
+}
+
+/// Whether the monitor is a headless output created by Hyprland rather than a physical display.
+fn is_headless(monitor: &Monitor) -> bool {
+    monitor.name.starts_with("HEADLESS-") || monitor.description.to_lowercase().contains("headless")
+}
+
+/// Whether hyprpaper's monitor identifier, either a name or `desc:` followed by the start of a
+/// description, refers to the monitor.
+fn matches_identifier(monitor: &Monitor, identifier: &str) -> bool {
+    match identifier.strip_prefix("desc:") {
+        Some(description) => monitor.description.starts_with(description),
+        None => monitor.name == identifier,
+    }
+}
+
+fn to_market_pictures(pictures: HashMap<String, String>) -> HashMap<String, PathBuf> {
+    pictures.into_iter()
+        .map(|(market, path)| (market, PathBuf::from(path)))
+        .collect()
 }
 
 impl BingPapr {
+    /// Limits the number of wallpapers applied at once to `limit`, at least one.
+    fn with_max_concurrent_operations(mut self, limit: usize) -> BingPapr {
+        self.operations = Arc::new(Semaphore::new(limit.max(1)));
+        self
+    }
+
     async fn set_new_wallpaper(&mut self, path: impl Into<PathBuf>) -> Result<(), ApplyWallpaperError> {
-        let mut old_picture = path.into();
-        swap(&mut old_picture, &mut self.active_picture);
+        let (_, market_pictures) = self.desired_pictures();
+        self.set_pictures(path.into(), market_pictures).await
+    }
+
+    async fn set_market_pictures(&mut self, market_pictures: HashMap<String, PathBuf>) -> Result<(), ApplyWallpaperError> {
+        let (active_picture, _) = self.desired_pictures();
+        self.set_pictures(active_picture, market_pictures).await
+    }
+
+    /// Active and market pictures to show, including changes deferred while fullscreen.
+    fn desired_pictures(&self) -> (PathBuf, HashMap<String, PathBuf>) {
+        match &self.pending {
+            Some(pending) => pending.clone(),
+            None => (self.active_picture.clone(), self.market_pictures.clone()),
+        }
+    }
 
-        // apply new wallpaper before unloading the old one
-        self.hyprpaper.preload(&self.active_picture)?;
-        if let Err(error) = self.apply_wallpaper_to_all_monitors(&self.active_picture).await {
-            warn!("Failed to apply wallpaper '{}' to all monitors: {}", self.active_picture.display(), error);
+    async fn set_pictures(&mut self, active_picture: PathBuf, market_pictures: HashMap<String, PathBuf>) -> Result<(), ApplyWallpaperError> {
+        if self.configuration.defer_while_fullscreen && self.fullscreen {
+            debug!("Fullscreen window present, deferring wallpaper change");
+            self.pending = Some((active_picture, market_pictures));
+            return Ok(());
+        }
+        self.pending = None;
+
+        let monitors = self.connected_monitors().await;
+        let old_pictures = self.pictures_for(&monitors);
+        self.active_picture = active_picture;
+        self.market_pictures = market_pictures;
+        let new_pictures = self.pictures_for(&monitors);
+
+        // apply new wallpapers before unloading the old ones
+        for picture in new_pictures.difference(&old_pictures) {
+            if self.retained.remove(picture) {
+                continue;
+            }
+            self.backend.preload(picture)?;
+        }
+        self.retained.extend(old_pictures.difference(&new_pictures).cloned());
+        if let Err(error) = self.apply_wallpaper_to_all_monitors(false).await {
+            // monitors the new wallpaper failed on may still be showing an old one
+            warn!("Failed to apply wallpaper to all monitors, keeping previous wallpapers loaded: {}", error);
+            return Ok(());
+        }
+        for picture in std::mem::take(&mut self.retained) {
+            self.backend.unload(&picture)?;
         }
-        self.hyprpaper.unload(&old_picture)?;
 
         Ok(())
     }
 
-    fn on_monitor_added(&self, monitor: &str) {
-        if let Err(err) = self.apply_wallpaper_to_monitor(&monitor, &self.active_picture) {
+    /// Picture that should be shown on the given monitor, taking its configured picture or market
+    /// into account.
+    fn picture_for_monitor(&self, monitor: &str) -> &Path {
+        let Some(configuration) = self.configuration.monitors.get(monitor) else {
+            return &self.active_picture;
+        };
+        if let Some(picture) = &configuration.picture {
+            return picture;
+        }
+        configuration.market.as_ref()
+            .and_then(|market| self.market_pictures.get(market))
+            .unwrap_or(&self.active_picture)
+    }
+
+    /// Pictures shown by the given monitors, along with the active picture.
+    fn pictures_for(&self, monitors: &[String]) -> HashSet<PathBuf> {
+        let mut pictures = HashSet::from([self.active_picture.clone()]);
+        for monitor in monitors {
+            pictures.insert(self.picture_for_monitor(monitor).to_path_buf());
+        }
+        pictures
+    }
+
+    /// Every picture any configured monitor could show, whether connected or not.
+    fn pictures_in_use(&self) -> HashSet<PathBuf> {
+        let monitors: Vec<_> = self.configuration.monitors.keys().cloned().collect();
+        self.pictures_for(&monitors)
+    }
+
+    /// Names of the monitors wallpapers are applied to, including the identifiers from
+    /// hyprpaper's configuration. Configured monitors which aren't connected are left out, so
+    /// their pictures aren't preloaded. Falls back to every configured monitor if Hyprland can't be
+    /// queried.
+    async fn connected_monitors(&self) -> Vec<String> {
+        match self.monitors.monitors().await {
+            Ok(monitors) => monitors.into_iter()
+                .filter(|monitor| self.should_apply(monitor))
+                .map(|monitor| monitor.name)
+                .chain(self.configured_monitors.iter().cloned())
+                .collect(),
+            Err(error) => {
+                warn!("Failed to list monitors: {}", error);
+                self.configuration.monitors.keys().cloned().collect()
+            }
+        }
+    }
+
+    fn should_apply(&self, monitor: &Monitor) -> bool {
+        self.configuration.include_headless || !is_headless(monitor)
+    }
+
+    async fn on_fullscreen_changed(&mut self, fullscreen: bool) {
+        self.fullscreen = fullscreen;
+        if fullscreen {
+            return;
+        }
+        if let Some((active_picture, market_pictures)) = self.pending.take() {
+            debug!("Fullscreen ended, applying deferred wallpaper change");
+            if let Err(error) = self.set_pictures(active_picture, market_pictures).await {
+                warn!("Failed to apply deferred wallpaper: {}", error);
+            }
+        }
+    }
+
+    /// Applies the current wallpapers to every monitor again without preloading them, replacing
+    /// wallpapers set by other tools.
+    async fn reassert_wallpaper(&mut self) {
+        if self.configuration.defer_while_fullscreen && self.fullscreen {
+            debug!("Fullscreen window present, skipping wallpaper reassertion");
+            return;
+        }
+        debug!("Reasserting wallpaper");
+        if let Err(error) = self.apply_wallpaper_to_all_monitors(true).await {
+            warn!("Failed to reassert wallpaper: {}", error);
+        }
+    }
+
+    async fn on_monitor_added(&mut self, monitor: &str) {
+        let result = match self.monitors.monitors().await {
+            Ok(monitors) => match monitors.into_iter().find(|candidate| candidate.name == monitor) {
+                Some(monitor) if !self.should_apply(&monitor) => {
+                    debug!("Skipping headless monitor {}", monitor.name);
+                    Ok(())
+                }
+                Some(monitor) => {
+                    // pictures of disconnected monitors aren't preloaded
+                    let path = self.picture_for_monitor(&monitor.name);
+                    if !self.backend.is_loaded(path) {
+                        if let Err(error) = self.backend.preload(path) {
+                            warn!("Failed to preload wallpaper '{}': {}", path.display(), error);
+                        }
+                    }
+                    self.assign_wallpaper(&monitor, true).await
+                }
+                None => {
+                    warn!("Added monitor {} is no longer connected", monitor);
+                    Ok(())
+                }
+            },
+            Err(err) => Err(err.into()),
+        };
+        if let Err(err) = result {
             error!("Failed to apply wallpaper to monitor: {}", err);
         }
+        self.persist_applied().await;
     }
 
-    async fn apply_wallpaper_to_all_monitors(&self, path: &Path) -> Result<(), ApplyWallpaperError> {
-        let monitors = hyprland::data::Monitors::get_async().await?;
+    /// Applies wallpapers to every monitor, continuing with the remaining monitors when applying
+    /// to one of them fails. Monitors already showing their picture are skipped unless `force` is
+    /// set. Wallpapers are applied to several monitors at once, see [BingPapr::operations].
+    async fn apply_wallpaper_to_all_monitors(&mut self, force: bool) -> Result<(), ApplyWallpaperError> {
+        let monitors = self.monitors.monitors().await?;
 
-        for monitor in monitors {
-            self.apply_wallpaper_to_monitor(&monitor.name, path)?;
+        // monitors to apply to, along with whether to record the assignment
+        let mut targets = Vec::new();
+        for monitor in &monitors {
+            if !self.should_apply(monitor) {
+                continue;
+            }
+            let path = self.picture_for_monitor(&monitor.name).to_path_buf();
+            if !force && self.applied.get(&monitor.name) == Some(&path) {
+                debug!("Wallpaper '{}' already applied to monitor {}", path.display(), monitor.name);
+                continue;
+            }
+            targets.push((monitor.name.clone(), self.target(monitor), path, true));
+        }
+        // identifiers hyprpaper knows under a name Hyprland doesn't report
+        for identifier in &self.configured_monitors {
+            if monitors.iter().any(|monitor| matches_identifier(monitor, identifier)) {
+                continue;
+            }
+            debug!("Applying wallpaper to monitor {} from hyprpaper's configuration", identifier);
+            let path = self.picture_for_monitor(identifier).to_path_buf();
+            targets.push((identifier.clone(), MonitorTarget::Name(identifier.clone()), path, false));
+        }
+
+        let bingpapr = &*self;
+        let results = join_all(targets.into_iter().map(|(monitor, target, path, record)| async move {
+            let result = bingpapr.set_wallpaper(target, path.clone()).await;
+            (monitor, path, record, result)
+        })).await;
+        let mut failures = Vec::new();
+        for (monitor, path, record, result) in results {
+            match result {
+                Ok(()) if record => {
+                    self.applied.insert(monitor, path);
+                }
+                Ok(()) => {}
+                Err(error) => failures.push(MonitorFailure { monitor, error }),
+            }
+        }
+        self.persist_applied().await;
+
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(ApplyWallpaperError::MonitorsError(failures))
         }
+    }
 
+    /// Applies the monitor's picture to it, recording the assignment. Does nothing if the picture
+    /// was already applied successfully, unless `force` is set.
+    async fn assign_wallpaper(&mut self, monitor: &Monitor, force: bool) -> Result<(), ApplyWallpaperError> {
+        let path = self.picture_for_monitor(&monitor.name).to_path_buf();
+        if !force && self.applied.get(&monitor.name) == Some(&path) {
+            debug!("Wallpaper '{}' already applied to monitor {}", path.display(), monitor.name);
+            return Ok(());
+        }
+        self.set_wallpaper(self.target(monitor), path.clone()).await?;
+        self.applied.insert(monitor.name.clone(), path);
         Ok(())
     }
 
-    fn apply_wallpaper_to_monitor(&self, monitor: &str, path: &Path) -> Result<(), ApplyWallpaperError> {
-        self.hyprpaper.set_wallpaper(monitor, path)?;
+    /// Unloads every wallpaper preloaded by this instance, including the ones currently shown,
+    /// before exiting so hyprpaper doesn't keep them in memory.
+    fn unload_pictures(&mut self) {
+        let mut pictures = self.pictures_in_use();
+        pictures.extend(self.applied.values().cloned());
+        pictures.extend(std::mem::take(&mut self.retained));
+        for picture in pictures {
+            if let Err(error) = self.backend.unload(&picture) {
+                warn!("Failed to unload wallpaper '{}': {}", picture.display(), error);
+            }
+        }
+    }
+
+    /// Writes the applied wallpapers to `state_path` one last time, in case the last change wasn't
+    /// recorded, and unloads every wallpaper before exiting.
+    async fn shut_down(&mut self, state_path: Option<&Path>) {
+        if let Some(state_path) = state_path {
+            if let Err(err) = state::persist_applied_to(state_path, &self.applied).await {
+                warn!("Failed to persist applied wallpapers: {}", err);
+            }
+        }
+        self.unload_pictures();
+    }
+
+    async fn persist_applied(&self) {
+        if let Err(err) = state::persist_applied(&self.applied).await {
+            warn!("Failed to persist applied wallpapers: {}", err);
+        }
+    }
+
+    fn target(&self, monitor: &Monitor) -> MonitorTarget {
+        if self.configuration.target_by_description {
+            MonitorTarget::Description(monitor.description.clone())
+        } else {
+            MonitorTarget::Name(monitor.name.clone())
+        }
+    }
+
+    /// Applies the wallpaper through the backend on a blocking thread, once one of the
+    /// [BingPapr::operations] permits is available.
+    async fn set_wallpaper(&self, target: MonitorTarget, path: PathBuf) -> Result<(), ApplyWallpaperError> {
+        let _permit = self.operations.acquire().await.expect("semaphore closed");
+        let backend = self.backend.clone();
+        spawn_blocking(move || match target {
+            MonitorTarget::Name(name) => backend.set_wallpaper(&name, &path),
+            MonitorTarget::Description(description) => backend.set_wallpaper_by_description(&description, &path),
+        }).await.map_err(io::Error::other)??;
         Ok(())
     }
 }
 
+/// Restores the wallpapers applied to still connected monitors before the last restart, returning
+/// the pictures that were preloaded to do so.
+async fn restore_applied(backend: &dyn WallpaperBackend, monitors: &dyn MonitorSource, applied: &HashMap<String, PathBuf>) -> HashSet<PathBuf> {
+    let mut preloaded = HashSet::new();
+    let monitors = match monitors.monitors().await {
+        Ok(monitors) => monitors,
+        Err(error) => {
+            warn!("Failed to list monitors: {}", error);
+            return preloaded;
+        }
+    };
+
+    for monitor in monitors {
+        let Some(path) = applied.get(&monitor.name) else {
+            continue;
+        };
+        if !path.exists() {
+            continue;
+        }
+        if !preloaded.contains(path) {
+            if let Err(error) = backend.preload(path) {
+                warn!("Failed to restore wallpaper '{}': {}", path.display(), error);
+                continue;
+            }
+            preloaded.insert(path.clone());
+        }
+        debug!("Restoring wallpaper '{}' on monitor {}", path.display(), monitor.name);
+        if let Err(error) = backend.set_wallpaper(&monitor.name, path) {
+            warn!("Failed to restore wallpaper on monitor {}: {}", monitor.name, error);
+        }
+    }
+    preloaded
+}
+
+/// Tells bingdaily the size of the largest monitor, which it uses to select the resolution of
+/// downloaded pictures.
+async fn report_display_size(bingdaily: &BingDaily1Proxy<'_>) {
+    let monitors = match Monitors::get_async().await {
+        Ok(monitors) => monitors,
+        Err(error) => {
+            warn!("Failed to list monitors: {}", error);
+            return;
+        }
+    };
+    let largest = monitors.max_by_key(|monitor| monitor.width as u32 * monitor.height as u32);
+    if let Some(monitor) = largest {
+        if let Err(error) = bingdaily.set_display_size(monitor.width.into(), monitor.height.into()).await {
+            warn!("Failed to report display size: {}", error);
+        }
+    }
+}
+
+/// Interval between two checks of [wait_for_stable_monitors].
+const MONITORS_SETTLE_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Waits until the same non-empty list of monitors is reported twice in a row, checking every
+/// `interval` and giving up after a few attempts.
+async fn wait_for_stable_monitors(source: &dyn MonitorSource, interval: Duration) {
+    const ATTEMPTS: u32 = 20;
+    let mut previous = Vec::new();
+    for _ in 0..ATTEMPTS {
+        match source.monitors().await {
+            Ok(monitors) => {
+                let mut names: Vec<_> = monitors.into_iter().map(|monitor| monitor.name).collect();
+                names.sort();
+                if !names.is_empty() && names == previous {
+                    debug!("Monitors settled: {:?}", names);
+                    return;
+                }
+                previous = names;
+            }
+            Err(err) => debug!("Failed to list monitors: {}", err),
+        }
+        sleep(interval).await;
+    }
+    warn!("Monitors did not settle, applying wallpaper anyway");
+}
+
+/// Whether to exit on startup because no monitors are connected, according to `behavior`. Keeps
+/// running if the monitors can't be listed.
+async fn exits_without_monitors(source: &dyn MonitorSource, behavior: &NoMonitorsBehavior) -> bool {
+    match source.monitors().await.map(|monitors| monitors.len()) {
+        Ok(0) => match behavior {
+            NoMonitorsBehavior::KeepRunning => {
+                info!("No monitors connected, waiting for monitors to be added");
+                false
+            }
+            NoMonitorsBehavior::Exit => {
+                info!("No monitors connected, exiting");
+                true
+            }
+        },
+        Ok(_) => false,
+        Err(error) => {
+            warn!("Failed to list monitors: {}", error);
+            false
+        }
+    }
+}
+
+/// Delay before subscribing to a property's changes again after the subscription ended.
+const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(1);
+
+/// Reads bingdaily's pictures through a fresh proxy, whose values aren't cached from before a
+/// restart, and applies them. Retries with backoff while bingdaily is still starting up.
+async fn resync_pictures(connection: &Connection, bingpaper: &Mutex<BingPapr>) {
+    const ATTEMPTS: u32 = 5;
+    let mut backoff = Duration::from_millis(500);
+    for attempt in 1..=ATTEMPTS {
+        let proxy = bingdaily_proxy(connection, &bingpaper.lock().await.configuration).await;
+        let pictures = match proxy {
+            Ok(proxy) => match proxy.current_picture().await {
+                Ok(picture) => Ok((picture, proxy.market_pictures().await.ok())),
+                Err(error) => Err(error),
+            },
+            Err(error) => Err(error),
+        };
+        match pictures {
+            Ok((picture, market_pictures)) => {
+                let mut bingpaper = bingpaper.lock().await;
+                let market_pictures = match market_pictures {
+                    Some(market_pictures) => to_market_pictures(market_pictures),
+                    None => bingpaper.desired_pictures().1,
+                };
+                if let Err(error) = bingpaper.set_pictures(PathBuf::from(picture), market_pictures).await {
+                    warn!("Failed to apply bingdaily's pictures: {}", error);
+                }
+                return;
+            }
+            Err(error) if attempt < ATTEMPTS => {
+                debug!("Failed to read bingdaily's pictures: {}, retrying in {:?}", error, backoff);
+                sleep(backoff).await;
+                backoff *= 2;
+            }
+            Err(error) => warn!("Failed to read bingdaily's pictures: {}", error),
+        }
+    }
+}
+
+/// Creates a proxy for the configured bingdaily instance, failing if its name or path are not
+/// well-formed.
+async fn bingdaily_proxy(connection: &Connection, configuration: &Configuration) -> zbus::Result<BingDaily1Proxy<'static>> {
+    BingDaily1Proxy::builder(connection)
+        .destination(configuration.bingdaily_name.clone())?
+        .path(configuration.bingdaily_path.clone())?
+        .build()
+        .await
+}
+
+/// Number of attempts made at reaching the session bus and bingdaily on startup.
+const STARTUP_ATTEMPTS: u32 = 8;
+
+/// Runs `operation` until it succeeds, backing off exponentially between attempts, for services
+/// which may not be ready yet when started early during login.
+async fn retry_startup<T, E, F>(description: &str, mut operation: impl FnMut() -> F) -> Result<T, E>
+where
+    E: Display,
+    F: Future<Output = Result<T, E>>,
+{
+    let mut backoff = Duration::from_millis(500);
+    let mut attempt = 1;
+    loop {
+        match operation().await {
+            Err(error) if attempt < STARTUP_ATTEMPTS => {
+                warn!("Failed to {}: {}, retrying in {:?}", description, error, backoff);
+                sleep(backoff).await;
+                backoff *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Applies the hyprpaper options of the configuration to `hyprpaper`.
+fn configure_hyprpaper(hyprpaper: Hyprpaper, configuration: &Configuration) -> Hyprpaper {
+    let mut hyprpaper = hyprpaper.with_preload_missing(configuration.hyprpaper_preload_missing);
+    if !configuration.hyprpaper_webp {
+        hyprpaper.supported_formats.retain(|format| *format != ImageFormat::WebP);
+    }
+    hyprpaper
+}
+
+/// Checks that the backend is able to load bingdaily's current picture and the pictures configured
+/// for monitors, which may come from local directories in any format, so an unsupported one fails
+/// on startup rather than on every change.
+fn check_formats(configuration: &Configuration, backend: &dyn WallpaperBackend, picture: &Path) -> Result<(), BackendError> {
+    backend.check_format(picture)?;
+    for path in configuration.monitors.values().filter_map(|monitor| monitor.picture.as_deref()) {
+        backend.check_format(path)?;
+    }
+    Ok(())
+}
+
+fn main() {
+    // stays in the foreground by default, as expected by service managers
+    if std::env::args().skip(1).any(|arg| arg == "--daemonize") {
+        if let Err(error) = daemon::daemonize() {
+            eprintln!("Failed to daemonize: {}", error);
+            exit(1);
+        }
+    }
+
+    run();
+}
+
 #[tokio::main]
-async fn main() {
+async fn run() {
     env_logger::builder().target(env_logger::Target::Stdout).init();
 
-    let connection = Connection::session().await.expect("dbus session");
-    let bingwallpaper = bingdaily::BingDaily1Proxy::new(&connection).await.expect("BingWallpaper proxy");
+    let configuration = Configuration::load().unwrap_or_else(|error| {
+        error!("{}, using default configuration", error);
+        Configuration::default()
+    });
+
+    let connection = match retry_startup("connect to the session bus", Connection::session).await {
+        Ok(connection) => connection,
+        Err(error) => {
+            error!("Failed to connect to the session bus: {}", error);
+            exit(1);
+        }
+    };
+    let bingwallpaper = match bingdaily_proxy(&connection, &configuration).await {
+        Ok(proxy) => proxy,
+        Err(error) => {
+            error!("Failed to connect to bingdaily at {} {}: {}", configuration.bingdaily_name, configuration.bingdaily_path, error);
+            exit(1);
+        }
+    };
+
+    let backend: Arc<dyn WallpaperBackend> = match configuration.backend {
+        BackendKind::Hyprpaper => {
+            let Some(hyprpaper) = Hyprpaper::new() else {
+                error!("Failed to locate hyprpaper's IPC socket");
+                exit(1);
+            };
+            let hyprpaper = configure_hyprpaper(hyprpaper, &configuration);
+            if let Err(error) = hyprpaper.probe() {
+                warn!("{}", error);
+            } else if let Err(error) = hyprpaper.unload_all() {
+                // wallpapers preloaded before a crash are no longer tracked, start from a clean state
+                warn!("Failed to unload stale wallpapers: {}", error);
+            }
+            Arc::new(hyprpaper)
+        }
+        #[cfg(feature = "layer-shell")]
+        BackendKind::LayerShell => match layer_shell::LayerShellBackend::connect() {
+            Ok(backend) => Arc::new(backend),
+            Err(error) => {
+                error!("Failed to start the layer shell backend: {}", error);
+                exit(1);
+            }
+        },
+        #[cfg(not(feature = "layer-shell"))]
+        BackendKind::LayerShell => {
+            error!("The layer_shell backend requires bingpapr to be built with the layer-shell feature");
+            exit(1);
+        }
+    };
 
-    let hyprpaper = Hyprpaper::new().expect("failed to connect to hyprpaper IPC");
+    let configured_monitors = if configuration.hyprpaper_config_monitors {
+        hyprpaper::configured_monitors().unwrap_or_default()
+    } else {
+        Vec::new()
+    };
+
+    // restore the previous wallpapers while waiting for bingdaily
+    let applied = state::load_applied().await;
+    let restored = restore_applied(backend.as_ref(), &Hyprland, &applied).await;
 
     // get initial wallpaper
-    let path = bingwallpaper.current_picture().await.expect("wallpaper property");
-    let path = PathBuf::from_str(&path).expect("wallpaper path");
+    let path = match retry_startup("get bingdaily's current picture", || bingwallpaper.current_picture()).await {
+        Ok(path) => PathBuf::from(path),
+        Err(error) => {
+            error!("Failed to get bingdaily's current picture: {}", error);
+            exit(1);
+        }
+    };
+    if let Err(error) = check_formats(&configuration, backend.as_ref(), &path) {
+        error!("{}", error);
+        exit(1);
+    }
+    let market_pictures = match bingwallpaper.market_pictures().await {
+        Ok(pictures) => to_market_pictures(pictures),
+        Err(error) => {
+            warn!("Failed to get market pictures: {}", error);
+            HashMap::new()
+        }
+    };
 
+    if !configuration.startup_delay.is_zero() {
+        debug!("Delaying initial wallpaper by {:?}", configuration.startup_delay);
+        sleep(configuration.startup_delay).await;
+    }
+    if configuration.wait_for_monitors {
+        wait_for_stable_monitors(&Hyprland, MONITORS_SETTLE_INTERVAL).await;
+    }
+    if exits_without_monitors(&Hyprland, &configuration.no_monitors).await {
+        return;
+    }
+
+    let fullscreen = configuration.defer_while_fullscreen
+        && FullscreenState::get_async().await.is_ok_and(FullscreenState::bool);
+
+    let max_concurrent_operations = configuration.hyprpaper_max_concurrent_operations;
     let bingpaper = Arc::new(Mutex::new(BingPapr {
-        active_picture: path.clone(),
-        hyprpaper,
-    }));
+        configuration,
+        active_picture: path,
+        market_pictures,
+        applied,
+        configured_monitors,
+        fullscreen,
+        pending: None,
+        retained: HashSet::new(),
+        backend,
+        operations: Arc::new(Semaphore::new(DEFAULT_MAX_CONCURRENT_OPERATIONS)),
+        monitors: Box::new(Hyprland),
+    }.with_max_concurrent_operations(max_concurrent_operations)));
 
     // apply initial wallpaper
     {
-        let bingpaper = bingpaper.lock().await;
-        bingpaper.hyprpaper.preload(&path).expect("preload wallpaper");
-        if let Err(error) = bingpaper.apply_wallpaper_to_all_monitors(&path).await {
-            warn!("Failed to apply wallpaper '{}' to all monitors: {}", path.display(), error)
+        let mut bingpaper = bingpaper.lock().await;
+        let monitors = bingpaper.connected_monitors().await;
+        let pictures = bingpaper.pictures_for(&monitors);
+        for picture in pictures.difference(&restored) {
+            if let Err(error) = bingpaper.backend.preload(picture) {
+                warn!("Failed to preload wallpaper '{}': {}", picture.display(), error);
+            }
+        }
+        // restoring may have failed on some monitors, so apply regardless of what was recorded
+        if let Err(error) = bingpaper.apply_wallpaper_to_all_monitors(true).await {
+            warn!("Failed to apply wallpaper to all monitors: {}", error)
+        }
+        for picture in restored.difference(&pictures) {
+            if let Err(error) = bingpaper.backend.unload(picture) {
+                warn!("Failed to unload restored wallpaper '{}': {}", picture.display(), error);
+            }
         }
     }
+    report_display_size(&bingwallpaper).await;
+
+    let watch_market_pictures_task = {
+        let bingpaper = bingpaper.clone();
+        let bingwallpaper = bingwallpaper.clone();
+        spawn(async move {
+            loop {
+                let mut changes = bingwallpaper.receive_market_pictures_changed().await;
+                while let Some(pictures) = changes.next().await {
+                    let pictures = match pictures.get().await {
+                        Ok(pictures) => pictures,
+                        Err(error) => {
+                            warn!("Failed to get market pictures: {}", error);
+                            continue;
+                        }
+                    };
+
+                    let mut bingpaper = bingpaper.lock().await;
+                    if let Err(error) = bingpaper.set_market_pictures(to_market_pictures(pictures)).await {
+                        warn!("Failed to set new market pictures: {}", error);
+                    }
+                }
+                warn!("Stopped receiving market picture changes, subscribing again");
+                sleep(RESUBSCRIBE_DELAY).await;
+            }
+        })
+    };
 
     let watch_property_task = {
         let bingpaper = bingpaper.clone();
+        let bingwallpaper = bingwallpaper.clone();
         spawn(async move {
-            while let Some(wallpaper) = bingwallpaper.receive_current_picture_changed().await.next().await {
-                let wallpaper = wallpaper.get().await.expect("wallpaper property");
-                let path = PathBuf::from_str(&wallpaper).expect("wallpaper path");
+            loop {
+                let mut changes = bingwallpaper.receive_current_picture_changed().await;
+                while let Some(wallpaper) = changes.next().await {
+                    let wallpaper = match wallpaper.get().await {
+                        Ok(wallpaper) => wallpaper,
+                        Err(error) => {
+                            warn!("Failed to get wallpaper: {}", error);
+                            continue;
+                        }
+                    };
+                    let path = PathBuf::from(wallpaper);
 
-                let mut bingpaper = bingpaper.lock().await;
-                if let Err(error) = bingpaper.set_new_wallpaper(&path).await {
-                    warn!("Failed to set new wallpaper '{}': {}", path.display(), error);
+                    let mut bingpaper = bingpaper.lock().await;
+                    if let Err(error) = bingpaper.set_new_wallpaper(&path).await {
+                        warn!("Failed to set new wallpaper '{}': {}", path.display(), error);
+                    }
+                }
+                warn!("Stopped receiving wallpaper changes, subscribing again");
+                sleep(RESUBSCRIBE_DELAY).await;
+            }
+        })
+    };
+
+    let watch_owner_task = {
+        let bingpaper = bingpaper.clone();
+        let bingwallpaper = bingwallpaper.clone();
+        let connection = connection.clone();
+        spawn(async move {
+            let mut owner_changes = match bingwallpaper.inner().receive_owner_changed().await {
+                Ok(owner_changes) => owner_changes,
+                Err(error) => {
+                    warn!("Failed to watch for bingdaily restarts: {}", error);
+                    return;
+                }
+            };
+            while let Some(owner) = owner_changes.next().await {
+                match owner {
+                    Some(_) => {
+                        info!("bingdaily (re)started, catching up on its pictures");
+                        resync_pictures(&connection, &bingpaper).await;
+                    }
+                    None => info!("bingdaily stopped, keeping the current wallpaper"),
                 }
             }
         })
@@ -114,20 +816,641 @@ async fn main() {
 
     let watch_monitors_task = {
         let bingpaper = bingpaper.clone();
+        let bingwallpaper = bingwallpaper.clone();
         spawn(async move {
             let mut event_listener = EventListener::new();
+            let fullscreen_bingpaper = bingpaper.clone();
+            event_listener.add_fullscreen_state_change_handler(move |fullscreen| {
+                let bingpaper = fullscreen_bingpaper.clone();
+                spawn(async move {
+                    bingpaper.lock().await.on_fullscreen_changed(fullscreen).await;
+                });
+            });
             event_listener.add_monitor_added_handler(move |monitor| {
                 let bingpaper = bingpaper.clone();
+                let bingwallpaper = bingwallpaper.clone();
                 spawn(async move {
-                    let bingpaper = bingpaper.lock().await;
-                    bingpaper.on_monitor_added(&monitor);
+                    let mut bingpaper = bingpaper.lock().await;
+                    bingpaper.on_monitor_added(&monitor).await;
+                    report_display_size(&bingwallpaper).await;
                 });
             });
 
-            event_listener.start_listener_async().await
-                .expect("failed to start event listener");
+            if let Err(error) = event_listener.start_listener_async().await {
+                error!("Failed to listen to Hyprland's events: {}", error);
+            }
         })
     };
 
-    join!(watch_property_task, watch_monitors_task);
+    let reassert_task = {
+        let bingpaper = bingpaper.clone();
+        spawn(async move {
+            let interval = bingpaper.lock().await.configuration.reassert_interval;
+            let Some(interval) = interval.filter(|interval| !interval.is_zero()) else {
+                return;
+            };
+            let mut next = Instant::now() + interval;
+            loop {
+                sleep_until(next).await;
+                next += interval;
+                bingpaper.lock().await.reassert_wallpaper().await;
+            }
+        })
+    };
+
+    let tasks = async {
+        let (watch_property, watch_market_pictures, watch_owner, watch_monitors, reassert) =
+            join!(watch_property_task, watch_market_pictures_task, watch_owner_task, watch_monitors_task, reassert_task);
+        if let Err(err) = watch_property {
+            error!("Property watcher task failed: {}", err);
+        }
+        if let Err(err) = watch_market_pictures {
+            error!("Market pictures watcher task failed: {}", err);
+        }
+        if let Err(err) = watch_owner {
+            error!("bingdaily owner watcher task failed: {}", err);
+        }
+        if let Err(err) = watch_monitors {
+            error!("Monitor watcher task failed: {}", err);
+        }
+        if let Err(err) = reassert {
+            error!("Wallpaper reassertion task failed: {}", err);
+        }
+    };
+
+    select! {
+        _ = tasks => {}
+        _ = shutdown_signal() => {
+            info!("Shutting down");
+            bingpaper.lock().await.shut_down(state::state_file().as_deref()).await;
+        }
+    }
+}
+
+/// Resolves once the process is asked to terminate by SIGTERM or SIGINT.
+async fn shutdown_signal() {
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(error) => {
+            warn!("Failed to listen for SIGTERM: {}", error);
+            let _ = tokio::signal::ctrl_c().await;
+            return;
+        }
+    };
+    select! {
+        _ = terminate.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::sync::Mutex as SyncMutex;
+    use zbus::export::futures_util::future::BoxFuture;
+    use hyprpaper::HyprpaperError;
+    use super::*;
+    use crate::configuration::MonitorConfiguration;
+
+    /// Backend recording the operations performed on it instead of showing anything.
+    #[derive(Clone, Default)]
+    struct RecordingBackend {
+        operations: Arc<SyncMutex<Vec<String>>>,
+        loaded: Arc<SyncMutex<HashSet<PathBuf>>>,
+        /// Monitors applying a wallpaper to fails on.
+        failing: Arc<SyncMutex<HashSet<String>>>,
+    }
+
+    impl RecordingBackend {
+        fn fail_on(&self, monitor: &str) {
+            self.failing.lock().unwrap().insert(monitor.to_owned());
+        }
+
+        fn stop_failing_on(&self, monitor: &str) {
+            self.failing.lock().unwrap().remove(monitor);
+        }
+
+        fn record(&self, operation: String) {
+            self.operations.lock().unwrap().push(operation);
+        }
+
+        fn operations(&self) -> Vec<String> {
+            let mut operations = self.operations.lock().unwrap().clone();
+            // pictures are kept in sets, so their order isn't defined
+            operations.sort();
+            operations
+        }
+    }
+
+    impl WallpaperBackend for RecordingBackend {
+        fn preload(&self, path: &Path) -> Result<(), BackendError> {
+            self.record(format!("preload {}", path.display()));
+            self.loaded.lock().unwrap().insert(path.to_owned());
+            Ok(())
+        }
+
+        fn unload(&self, path: &Path) -> Result<(), BackendError> {
+            self.record(format!("unload {}", path.display()));
+            self.loaded.lock().unwrap().remove(path);
+            Ok(())
+        }
+
+        fn is_loaded(&self, path: &Path) -> bool {
+            self.loaded.lock().unwrap().contains(path)
+        }
+
+        fn set_wallpaper(&self, monitor: &str, path: &Path) -> Result<(), BackendError> {
+            self.record(format!("set {} {}", monitor, path.display()));
+            if self.failing.lock().unwrap().contains(monitor) {
+                return Err(HyprpaperError::Command("wallpaper failed (no such monitor)".to_owned()).into());
+            }
+            Ok(())
+        }
+
+        fn set_wallpaper_by_description(&self, description: &str, path: &Path) -> Result<(), BackendError> {
+            self.record(format!("set desc:{} {}", description, path.display()));
+            Ok(())
+        }
+    }
+
+    fn monitor(name: &str, description: &str) -> Monitor {
+        serde_json::from_value(serde_json::json!({
+            "id": 0, "name": name, "description": description, "width": 3840, "height": 2160,
+            "refreshRate": 60.0, "x": 0, "y": 0, "activeWorkspace": { "id": 1, "name": "1" },
+            "reserved": [0, 0, 0, 0], "scale": 1.0, "transform": 0, "focused": true,
+            "dpmsStatus": true, "vrr": false,
+        })).unwrap()
+    }
+
+    /// Source reporting the given lists of monitor names in turn, then the last one repeatedly.
+    /// An empty script fails to list any monitors, as if Hyprland couldn't be reached.
+    struct ScriptedMonitors {
+        script: SyncMutex<VecDeque<Vec<&'static str>>>,
+        calls: SyncMutex<usize>,
+    }
+
+    impl ScriptedMonitors {
+        fn new(script: &[&[&'static str]]) -> ScriptedMonitors {
+            ScriptedMonitors {
+                script: SyncMutex::new(script.iter().map(|names| names.to_vec()).collect()),
+                calls: SyncMutex::new(0),
+            }
+        }
+
+        fn unavailable() -> ScriptedMonitors {
+            ScriptedMonitors::new(&[])
+        }
+
+        fn calls(&self) -> usize {
+            *self.calls.lock().unwrap()
+        }
+    }
+
+    impl MonitorSource for ScriptedMonitors {
+        fn monitors(&self) -> BoxFuture<'_, hyprland::Result<Vec<Monitor>>> {
+            *self.calls.lock().unwrap() += 1;
+            let mut script = self.script.lock().unwrap();
+            let names = match script.len() {
+                0 => return Box::pin(async { Err(hyprland::shared::HyprError::NotOkDispatch("no socket".to_owned())) }),
+                1 => script[0].clone(),
+                _ => script.pop_front().unwrap(),
+            };
+            Box::pin(async move { Ok(names.iter().map(|name| monitor(name, &format!("Dell Inc. {}", name))).collect()) })
+        }
+    }
+
+    #[tokio::test]
+    async fn waits_until_monitors_are_stable() {
+        let source = ScriptedMonitors::new(&[&[], &[], &["DP-1"], &["DP-1", "HDMI-A-1"], &["HDMI-A-1", "DP-1"]]);
+        wait_for_stable_monitors(&source, Duration::ZERO).await;
+        // the same monitors reported in a different order count as stable
+        assert_eq!(source.calls(), 5);
+
+        let source = ScriptedMonitors::new(&[&[]]);
+        wait_for_stable_monitors(&source, Duration::ZERO).await;
+        assert_eq!(source.calls(), 20);
+    }
+
+    #[tokio::test]
+    async fn applies_to_every_monitor_once_they_settled() {
+        let backend = RecordingBackend::default();
+        let mut bingpapr = bingpapr_with(backend.clone(), &[], &[]);
+        bingpapr.monitors = Box::new(ScriptedMonitors::new(&[&[], &["DP-1"], &["DP-1", "HDMI-A-1"]]));
+
+        wait_for_stable_monitors(bingpapr.monitors.as_ref(), Duration::ZERO).await;
+        bingpapr.apply_wallpaper_to_all_monitors(true).await.unwrap();
+
+        assert_eq!(backend.operations(), [
+            "set DP-1 /pictures/20240101-en-US-Current.jpg",
+            "set HDMI-A-1 /pictures/20240101-en-US-Current.jpg",
+        ]);
+    }
+
+    #[test]
+    fn detects_headless_monitors() {
+        for (name, description, headless) in [
+            ("DP-1", "Dell Inc. DELL U2720Q 8LXMZ13", false),
+            ("HDMI-A-1", "", false),
+            ("HEADLESS-1", "", true),
+            ("HEADLESS-2", "Headless output", true),
+            ("WL-1", "Headless display for streaming", true),
+            ("headless-1", "", false),
+        ] {
+            assert_eq!(is_headless(&monitor(name, description)), headless, "{}", name);
+        }
+    }
+
+    #[test]
+    fn headless_monitors_are_applied_only_when_included() {
+        let mut bingpapr = bingpapr(&[], &[]);
+        for (include_headless, expected) in [(false, [true, false]), (true, [true, true])] {
+            bingpapr.configuration.include_headless = include_headless;
+            let applied = [monitor("DP-1", "Dell Inc."), monitor("HEADLESS-1", "")].map(|monitor| bingpapr.should_apply(&monitor));
+            assert_eq!(applied, expected, "include_headless: {}", include_headless);
+        }
+    }
+
+    #[tokio::test]
+    async fn exits_without_monitors_only_when_configured() {
+        for (monitors, behavior, expected) in [
+            (ScriptedMonitors::new(&[&[]]), NoMonitorsBehavior::KeepRunning, false),
+            (ScriptedMonitors::new(&[&[]]), NoMonitorsBehavior::Exit, true),
+            (ScriptedMonitors::new(&[&["DP-1"]]), NoMonitorsBehavior::Exit, false),
+            (ScriptedMonitors::unavailable(), NoMonitorsBehavior::Exit, false),
+        ] {
+            assert_eq!(exits_without_monitors(&monitors, &behavior).await, expected, "{:?}", behavior);
+        }
+    }
+
+    fn bingpapr(monitors: &[(&str, &str)], market_pictures: &[(&str, &str)]) -> BingPapr {
+        bingpapr_with(RecordingBackend::default(), monitors, market_pictures)
+    }
+
+    fn bingpapr_with(backend: RecordingBackend, monitors: &[(&str, &str)], market_pictures: &[(&str, &str)]) -> BingPapr {
+        let configuration = Configuration {
+            monitors: monitors.iter()
+                .map(|(monitor, market)| (monitor.to_string(), MonitorConfiguration { market: Some(market.to_string()), picture: None }))
+                .collect(),
+            ..Configuration::default()
+        };
+        BingPapr {
+            configuration,
+            backend: Arc::new(backend),
+            operations: Arc::new(Semaphore::new(DEFAULT_MAX_CONCURRENT_OPERATIONS)),
+            monitors: Box::new(ScriptedMonitors::new(&[&[]])),
+            active_picture: PathBuf::from("/pictures/20240101-en-US-Current.jpg"),
+            market_pictures: market_pictures.iter()
+                .map(|(market, path)| (market.to_string(), PathBuf::from(path)))
+                .collect(),
+            applied: HashMap::new(),
+            configured_monitors: Vec::new(),
+            fullscreen: false,
+            pending: None,
+            retained: HashSet::new(),
+        }
+    }
+
+    #[test]
+    fn monitors_show_their_market_picture() {
+        let bingpapr = bingpapr(
+            &[("DP-1", "ja-JP"), ("DP-2", "en-GB")],
+            &[("ja-JP", "/pictures/20240101-ja-JP-Fuji.jpg"), ("en-GB", "/pictures/20240101-en-GB-Dover.jpg")],
+        );
+        assert_eq!(bingpapr.picture_for_monitor("DP-1"), Path::new("/pictures/20240101-ja-JP-Fuji.jpg"));
+        assert_eq!(bingpapr.picture_for_monitor("DP-2"), Path::new("/pictures/20240101-en-GB-Dover.jpg"));
+        assert_eq!(bingpapr.pictures_in_use().len(), 3);
+    }
+
+    #[test]
+    fn configured_picture_takes_precedence() {
+        let mut bingpapr = bingpapr(&[("DP-1", "ja-JP")], &[("ja-JP", "/pictures/20240101-ja-JP-Fuji.jpg")]);
+        bingpapr.configuration.monitors.get_mut("DP-1").unwrap().picture = Some(PathBuf::from("/home/user/portrait.jpg"));
+        assert_eq!(bingpapr.picture_for_monitor("DP-1"), Path::new("/home/user/portrait.jpg"));
+    }
+
+    #[test]
+    fn disconnected_monitors_are_not_preloaded() {
+        let bingpapr = bingpapr(
+            &[("DP-1", "ja-JP"), ("DP-2", "en-GB")],
+            &[("ja-JP", "/pictures/20240101-ja-JP-Fuji.jpg"), ("en-GB", "/pictures/20240101-en-GB-Dover.jpg")],
+        );
+        let pictures = bingpapr.pictures_for(&["DP-1".to_owned(), "HDMI-A-1".to_owned()]);
+        assert_eq!(pictures, HashSet::from([
+            PathBuf::from("/pictures/20240101-en-US-Current.jpg"),
+            PathBuf::from("/pictures/20240101-ja-JP-Fuji.jpg"),
+        ]));
+    }
+
+    #[test]
+    fn unconfigured_monitors_show_current_picture() {
+        let bingpapr = bingpapr(&[("DP-1", "de-DE")], &[]);
+        // the market isn't one of bingdaily's additional markets
+        assert_eq!(bingpapr.picture_for_monitor("DP-1"), Path::new("/pictures/20240101-en-US-Current.jpg"));
+        assert_eq!(bingpapr.picture_for_monitor("HDMI-A-1"), Path::new("/pictures/20240101-en-US-Current.jpg"));
+    }
+
+    #[tokio::test]
+    async fn changes_are_deferred_while_fullscreen() {
+        let backend = RecordingBackend::default();
+        let mut bingpapr = bingpapr_with(backend.clone(), &[], &[]);
+        bingpapr.configuration.defer_while_fullscreen = true;
+        bingpapr.fullscreen = true;
+
+        bingpapr.set_new_wallpaper("/pictures/20240102-en-US-Next.jpg").await.unwrap();
+
+        assert_eq!(backend.operations(), Vec::<String>::new());
+        assert_eq!(bingpapr.desired_pictures().0, PathBuf::from("/pictures/20240102-en-US-Next.jpg"));
+        assert_eq!(bingpapr.active_picture, PathBuf::from("/pictures/20240101-en-US-Current.jpg"));
+
+        bingpapr.monitors = Box::new(ScriptedMonitors::new(&[&["DP-1"]]));
+        bingpapr.on_fullscreen_changed(false).await;
+
+        assert_eq!(backend.operations(), [
+            "preload /pictures/20240102-en-US-Next.jpg",
+            "set DP-1 /pictures/20240102-en-US-Next.jpg",
+            "unload /pictures/20240101-en-US-Current.jpg",
+        ]);
+        assert_eq!(bingpapr.active_picture, PathBuf::from("/pictures/20240102-en-US-Next.jpg"));
+        assert!(bingpapr.pending.is_none());
+    }
+
+    #[tokio::test]
+    async fn reasserting_applies_wallpapers_again_without_loading_them() {
+        let backend = RecordingBackend::default();
+        let mut bingpapr = bingpapr_with(backend.clone(), &[("DP-1", "ja-JP")], &[("ja-JP", "/pictures/20240101-ja-JP-Fuji.jpg")]);
+        bingpapr.monitors = Box::new(ScriptedMonitors::new(&[&["DP-1", "HDMI-A-1"]]));
+
+        bingpapr.reassert_wallpaper().await;
+        bingpapr.reassert_wallpaper().await;
+
+        let expected = [
+            "set DP-1 /pictures/20240101-ja-JP-Fuji.jpg",
+            "set DP-1 /pictures/20240101-ja-JP-Fuji.jpg",
+            "set HDMI-A-1 /pictures/20240101-en-US-Current.jpg",
+            "set HDMI-A-1 /pictures/20240101-en-US-Current.jpg",
+        ];
+        assert_eq!(backend.operations(), expected);
+
+        // a fullscreen window isn't disturbed
+        bingpapr.configuration.defer_while_fullscreen = true;
+        bingpapr.on_fullscreen_changed(true).await;
+        bingpapr.reassert_wallpaper().await;
+        assert_eq!(backend.operations(), expected);
+    }
+
+    #[test]
+    fn unloads_every_picture_on_exit() {
+        let backend = RecordingBackend::default();
+        let mut bingpapr = bingpapr_with(backend.clone(), &[("DP-1", "ja-JP")], &[("ja-JP", "/pictures/20240101-ja-JP-Fuji.jpg")]);
+        bingpapr.applied.insert("HDMI-A-1".to_owned(), PathBuf::from("/pictures/20231231-en-US-Restored.jpg"));
+        bingpapr.retained.insert(PathBuf::from("/pictures/20231230-en-US-Previous.jpg"));
+
+        bingpapr.unload_pictures();
+
+        assert_eq!(backend.operations(), [
+            "unload /pictures/20231230-en-US-Previous.jpg",
+            "unload /pictures/20231231-en-US-Restored.jpg",
+            "unload /pictures/20240101-en-US-Current.jpg",
+            "unload /pictures/20240101-ja-JP-Fuji.jpg",
+        ]);
+        assert!(bingpapr.retained.is_empty());
+    }
+
+    #[tokio::test]
+    async fn keeps_previous_wallpapers_loaded_until_a_change_applies_everywhere() {
+        let backend = RecordingBackend::default();
+        backend.fail_on("HDMI-A-1");
+        let mut bingpapr = bingpapr_with(backend.clone(), &[], &[]);
+        bingpapr.monitors = Box::new(ScriptedMonitors::new(&[&["DP-1", "HDMI-A-1"]]));
+
+        bingpapr.set_new_wallpaper("/pictures/20240102-en-US-Next.jpg").await.unwrap();
+
+        // HDMI-A-1 may still show the previous picture
+        assert_eq!(bingpapr.retained, HashSet::from([PathBuf::from("/pictures/20240101-en-US-Current.jpg")]));
+        assert!(!backend.operations().iter().any(|operation| operation.starts_with("unload")), "{:?}", backend.operations());
+
+        backend.stop_failing_on("HDMI-A-1");
+        bingpapr.set_new_wallpaper("/pictures/20240103-en-US-Later.jpg").await.unwrap();
+
+        assert!(bingpapr.retained.is_empty());
+        let unloaded: Vec<_> = backend.operations().into_iter().filter(|operation| operation.starts_with("unload")).collect();
+        assert_eq!(unloaded, [
+            "unload /pictures/20240101-en-US-Current.jpg",
+            "unload /pictures/20240102-en-US-Next.jpg",
+        ]);
+    }
+
+    #[tokio::test]
+    async fn skips_monitors_already_showing_their_wallpaper() {
+        let backend = RecordingBackend::default();
+        let mut bingpapr = bingpapr_with(backend.clone(), &[], &[]);
+        bingpapr.monitors = Box::new(ScriptedMonitors::new(&[&["DP-1", "HDMI-A-1"]]));
+
+        bingpapr.apply_wallpaper_to_all_monitors(false).await.unwrap();
+        bingpapr.apply_wallpaper_to_all_monitors(false).await.unwrap();
+        assert_eq!(backend.operations(), [
+            "set DP-1 /pictures/20240101-en-US-Current.jpg",
+            "set HDMI-A-1 /pictures/20240101-en-US-Current.jpg",
+        ]);
+
+        bingpapr.active_picture = PathBuf::from("/pictures/20240102-en-US-Next.jpg");
+        bingpapr.apply_wallpaper_to_all_monitors(false).await.unwrap();
+        bingpapr.apply_wallpaper_to_all_monitors(true).await.unwrap();
+        assert_eq!(backend.operations(), [
+            "set DP-1 /pictures/20240101-en-US-Current.jpg",
+            "set DP-1 /pictures/20240102-en-US-Next.jpg",
+            "set DP-1 /pictures/20240102-en-US-Next.jpg",
+            "set HDMI-A-1 /pictures/20240101-en-US-Current.jpg",
+            "set HDMI-A-1 /pictures/20240102-en-US-Next.jpg",
+            "set HDMI-A-1 /pictures/20240102-en-US-Next.jpg",
+        ]);
+    }
+
+    #[tokio::test]
+    async fn assigns_a_wallpaper_only_when_it_changed_or_forced() {
+        let backend = RecordingBackend::default();
+        let mut bingpapr = bingpapr_with(backend.clone(), &[], &[]);
+        let dp1 = monitor("DP-1", "Dell Inc. DP-1");
+
+        bingpapr.assign_wallpaper(&dp1, false).await.unwrap();
+        bingpapr.assign_wallpaper(&dp1, false).await.unwrap();
+        assert_eq!(backend.operations(), ["set DP-1 /pictures/20240101-en-US-Current.jpg"]);
+
+        bingpapr.active_picture = PathBuf::from("/pictures/20240102-en-US-Next.jpg");
+        bingpapr.assign_wallpaper(&dp1, false).await.unwrap();
+        bingpapr.assign_wallpaper(&dp1, true).await.unwrap();
+        assert_eq!(backend.operations(), [
+            "set DP-1 /pictures/20240101-en-US-Current.jpg",
+            "set DP-1 /pictures/20240102-en-US-Next.jpg",
+            "set DP-1 /pictures/20240102-en-US-Next.jpg",
+        ]);
+        assert_eq!(bingpapr.applied["DP-1"], PathBuf::from("/pictures/20240102-en-US-Next.jpg"));
+    }
+
+    #[tokio::test]
+    async fn shutting_down_persists_unrecorded_changes() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("applied.json");
+        let backend = RecordingBackend::default();
+        let mut bingpapr = bingpapr_with(backend.clone(), &[], &[]);
+        // recorded in memory only, as when stopped right after a change
+        bingpapr.applied.insert("DP-1".to_owned(), PathBuf::from("/pictures/20240101-en-US-Current.jpg"));
+
+        bingpapr.shut_down(Some(&path)).await;
+
+        assert_eq!(state::load_applied_from(&path).await, bingpapr.applied);
+        assert_eq!(backend.operations(), ["unload /pictures/20240101-en-US-Current.jpg"]);
+    }
+
+    #[tokio::test]
+    async fn applying_to_all_monitors_reports_every_failure() {
+        let backend = RecordingBackend::default();
+        backend.fail_on("DP-1");
+        backend.fail_on("HDMI-A-1");
+        let mut bingpapr = bingpapr_with(backend.clone(), &[], &[]);
+        bingpapr.monitors = Box::new(ScriptedMonitors::new(&[&["DP-1", "DP-2", "HDMI-A-1"]]));
+
+        let error = bingpapr.apply_wallpaper_to_all_monitors(false).await.unwrap_err();
+
+        let ApplyWallpaperError::MonitorsError(failures) = &error else {
+            panic!("unexpected error {:?}", error);
+        };
+        let failed: Vec<_> = failures.iter().map(|failure| failure.monitor.as_str()).collect();
+        assert_eq!(failed, ["DP-1", "HDMI-A-1"]);
+        assert!(error.to_string().starts_with("failed to apply wallpaper to 2 monitor(s): DP-1: "), "{}", error);
+        // the monitor in between still got its wallpaper
+        assert_eq!(bingpapr.applied.keys().collect::<Vec<_>>(), ["DP-2"]);
+        assert_eq!(backend.operations().len(), 3);
+    }
+
+    /// Backend taking a while to apply wallpapers, tracking how many it applies at once.
+    #[derive(Default)]
+    struct SlowBackend {
+        in_flight: SyncMutex<usize>,
+        max_in_flight: SyncMutex<usize>,
+    }
+
+    impl WallpaperBackend for SlowBackend {
+        fn preload(&self, _path: &Path) -> Result<(), BackendError> {
+            Ok(())
+        }
+
+        fn unload(&self, _path: &Path) -> Result<(), BackendError> {
+            Ok(())
+        }
+
+        fn is_loaded(&self, _path: &Path) -> bool {
+            true
+        }
+
+        fn set_wallpaper(&self, _monitor: &str, _path: &Path) -> Result<(), BackendError> {
+            {
+                let mut in_flight = self.in_flight.lock().unwrap();
+                *in_flight += 1;
+                let mut max_in_flight = self.max_in_flight.lock().unwrap();
+                *max_in_flight = (*max_in_flight).max(*in_flight);
+            }
+            std::thread::sleep(Duration::from_millis(50));
+            *self.in_flight.lock().unwrap() -= 1;
+            Ok(())
+        }
+
+        fn set_wallpaper_by_description(&self, description: &str, path: &Path) -> Result<(), BackendError> {
+            self.set_wallpaper(description, path)
+        }
+    }
+
+    #[tokio::test]
+    async fn bounds_the_wallpapers_applied_at_once() {
+        for limit in [1, 3] {
+            let backend = Arc::new(SlowBackend::default());
+            let mut bingpapr = bingpapr(&[], &[]).with_max_concurrent_operations(limit);
+            bingpapr.backend = backend.clone();
+            bingpapr.monitors = Box::new(ScriptedMonitors::new(&[&["DP-1", "DP-2", "DP-3", "DP-4", "DP-5", "DP-6", "DP-7"]]));
+
+            bingpapr.apply_wallpaper_to_all_monitors(true).await.unwrap();
+
+            assert_eq!(*backend.max_in_flight.lock().unwrap(), limit);
+            assert_eq!(bingpapr.applied.len(), 7);
+        }
+    }
+
+    #[tokio::test]
+    async fn applying_to_all_monitors_fails_when_they_cannot_be_listed() {
+        let backend = RecordingBackend::default();
+        let mut bingpapr = bingpapr_with(backend.clone(), &[], &[]);
+        bingpapr.monitors = Box::new(ScriptedMonitors::unavailable());
+
+        let error = bingpapr.apply_wallpaper_to_all_monitors(true).await.unwrap_err();
+
+        assert!(matches!(error, ApplyWallpaperError::HyprError(_)), "{:?}", error);
+        assert_eq!(backend.operations(), Vec::<String>::new());
+    }
+
+    #[tokio::test]
+    async fn restores_wallpapers_of_connected_monitors() {
+        let directory = tempfile::tempdir().unwrap();
+        let current = directory.path().join("20240101-en-US-Current.jpg");
+        let fuji = directory.path().join("20240101-ja-JP-Fuji.jpg");
+        std::fs::write(&current, b"").unwrap();
+        std::fs::write(&fuji, b"").unwrap();
+        let applied = HashMap::from([
+            ("DP-1".to_owned(), current.clone()),
+            ("DP-2".to_owned(), current.clone()),
+            // deleted since
+            ("HDMI-A-1".to_owned(), directory.path().join("20231231-en-US-Gone.jpg")),
+            // no longer connected
+            ("DP-3".to_owned(), fuji),
+        ]);
+        let backend = RecordingBackend::default();
+        let monitors = ScriptedMonitors::new(&[&["DP-1", "DP-2", "HDMI-A-1"]]);
+
+        let restored = restore_applied(&backend, &monitors, &applied).await;
+
+        assert_eq!(restored, HashSet::from([current.clone()]));
+        assert_eq!(backend.operations(), [
+            format!("preload {}", current.display()),
+            format!("set DP-1 {}", current.display()),
+            format!("set DP-2 {}", current.display()),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn restores_nothing_when_monitors_cannot_be_listed() {
+        let directory = tempfile::tempdir().unwrap();
+        let current = directory.path().join("20240101-en-US-Current.jpg");
+        std::fs::write(&current, b"").unwrap();
+        let backend = RecordingBackend::default();
+
+        let restored = restore_applied(&backend, &ScriptedMonitors::unavailable(), &HashMap::from([("DP-1".to_owned(), current)])).await;
+
+        assert!(restored.is_empty());
+        assert_eq!(backend.operations(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn rejects_pictures_hyprpaper_cannot_load_on_startup() {
+        let portrait = || MonitorConfiguration { market: None, picture: Some(PathBuf::from("/home/user/portrait.webp")) };
+        for (webp, picture, monitors, expected) in [
+            (true, "/pictures/current.webp", vec![], true),
+            (true, "/pictures/current.jpg", vec![("DP-2", portrait())], true),
+            (false, "/pictures/current.jpg", vec![], true),
+            (false, "/pictures/current.webp", vec![], false),
+            (false, "/pictures/current.jpg", vec![("DP-2", portrait())], false),
+        ] {
+            let configuration = Configuration {
+                hyprpaper_webp: webp,
+                monitors: monitors.into_iter().map(|(name, monitor)| (name.to_owned(), monitor)).collect(),
+                ..Configuration::default()
+            };
+            let hyprpaper = configure_hyprpaper(Hyprpaper::with_socket(PathBuf::from("/nonexistent/.hyprpaper.sock")), &configuration);
+
+            let result = check_formats(&configuration, &hyprpaper, Path::new(picture));
+
+            assert_eq!(result.is_ok(), expected, "{} with WebP {}: {:?}", picture, webp, result.err());
+            if let Err(error) = result {
+                assert!(matches!(error, BackendError::Hyprpaper(HyprpaperError::UnsupportedFormat(_))), "{:?}", error);
+            }
+        }
+    }
 }
@@ -0,0 +1,19 @@
+//! Sources listing the connected monitors.
+
+use hyprland::data::{Monitor, Monitors};
+use hyprland::prelude::*;
+use zbus::export::futures_util::future::BoxFuture;
+
+/// Lists the monitors currently connected.
+pub trait MonitorSource: Send + Sync {
+    fn monitors(&self) -> BoxFuture<'_, hyprland::Result<Vec<Monitor>>>;
+}
+
+/// Monitors as reported by Hyprland.
+pub struct Hyprland;
+
+impl MonitorSource for Hyprland {
+    fn monitors(&self) -> BoxFuture<'_, hyprland::Result<Vec<Monitor>>> {
+        Box::pin(async { Ok(Monitors::get_async().await?.collect()) })
+    }
+}
@@ -0,0 +1,87 @@
+//! Wallpapers applied to each monitor, persisted so the exact assignment can be restored after a
+//! restart, before bingdaily is even reachable.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use bingcommon::state::{load_state, persist_state, StateError, VersionedState};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+/// Wallpaper applied to each monitor, keyed by monitor name.
+#[derive(Default, Deserialize, Serialize)]
+#[serde(transparent)]
+struct Applied(HashMap<String, PathBuf>);
+
+impl VersionedState for Applied {
+    const VERSION: u32 = 1;
+}
+
+/// Location of the persisted state, if the user's state directory can be determined.
+pub fn state_file() -> Option<PathBuf> {
+    let base_dirs = directories::BaseDirs::new()?;
+    let state_dir = base_dirs.state_dir().unwrap_or_else(|| base_dirs.data_local_dir());
+    Some(state_dir.join("bingpapr").join("applied.json"))
+}
+
+/// Loads the persisted assignment of wallpapers to monitors, keyed by monitor name.
+pub async fn load_applied() -> HashMap<String, PathBuf> {
+    match state_file() {
+        Some(path) => load_applied_from(&path).await,
+        None => HashMap::new(),
+    }
+}
+
+/// Loads the assignment of wallpapers to monitors persisted at `path`, starting over if it is
+/// missing or unreadable.
+pub async fn load_applied_from(path: &Path) -> HashMap<String, PathBuf> {
+    match load_state::<Applied>(path).await {
+        Ok(applied) => applied.0,
+        Err(err) => {
+            warn!("Failed to read state {}: {}", path.display(), err);
+            HashMap::new()
+        }
+    }
+}
+
+/// Persists the assignment of wallpapers to monitors atomically, so a crash never leaves a
+/// corrupt file behind.
+pub async fn persist_applied(applied: &HashMap<String, PathBuf>) -> Result<(), StateError> {
+    match state_file() {
+        Some(path) => persist_applied_to(&path, applied).await,
+        None => Ok(()),
+    }
+}
+
+/// Persists the assignment of wallpapers to monitors at `path`, see [persist_applied].
+pub async fn persist_applied_to(path: &Path, applied: &HashMap<String, PathBuf>) -> Result<(), StateError> {
+    persist_state(path, &Applied(applied.clone())).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn round_trips() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("bingpapr").join("applied.json");
+        let applied = HashMap::from([
+            ("DP-1".to_owned(), PathBuf::from("/pictures/20240101-en-US-Current.jpg")),
+            ("HDMI-A-1".to_owned(), PathBuf::from("/pictures/20240101-ja-JP-Fuji.jpg")),
+        ]);
+
+        persist_applied_to(&path, &applied).await.unwrap();
+
+        assert_eq!(load_applied_from(&path).await, applied);
+    }
+
+    #[tokio::test]
+    async fn missing_or_corrupt_state_is_empty() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("applied.json");
+        assert_eq!(load_applied_from(&path).await, HashMap::new());
+
+        tokio::fs::write(&path, "{\"version\": 1, \"state\": {\"DP-1\": ").await.unwrap();
+        assert_eq!(load_applied_from(&path).await, HashMap::new());
+    }
+}
@@ -0,0 +1,74 @@
+//! Runs the bingpapr binary with `--daemonize`, without a session bus so the daemon keeps retrying
+//! to connect to it, logging each failed attempt.
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+/// Running processes whose environment has `variable`, which is unique to the test.
+fn find_processes(variable: &str) -> Vec<libc::pid_t> {
+    fs::read_dir("/proc").unwrap()
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+        .filter(|pid: &libc::pid_t| {
+            fs::read(format!("/proc/{}/environ", pid))
+                .is_ok_and(|environ| environ.split(|byte| *byte == 0).any(|entry| entry == variable.as_bytes()))
+        })
+        .filter(|pid| is_alive(*pid))
+        .collect()
+}
+
+/// Waits for `condition` to hold, giving up after five seconds.
+fn wait_for<T>(mut condition: impl FnMut() -> Option<T>) -> Option<T> {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while Instant::now() < deadline {
+        if let Some(value) = condition() {
+            return Some(value);
+        }
+        sleep(Duration::from_millis(50));
+    }
+    None
+}
+
+fn read_log(path: &Path) -> Option<String> {
+    fs::read_to_string(path).ok()
+}
+
+/// Whether the process exists and hasn't exited, unlike zombies waiting to be reaped.
+fn is_alive(pid: libc::pid_t) -> bool {
+    // the state follows the parenthesized command name
+    fs::read_to_string(format!("/proc/{}/stat", pid))
+        .is_ok_and(|stat| stat.rsplit_once(") ").is_some_and(|(_, rest)| !rest.starts_with(['Z', 'X'])))
+}
+
+#[test]
+fn keeps_running_in_the_background_and_logs_to_the_log_file() {
+    let dir = TempDir::new().unwrap();
+    let state_home = dir.path().join("state");
+    let log_path = state_home.join("bingpapr").join("bingpapr.log");
+    let marker = format!("XDG_STATE_HOME={}", state_home.display());
+
+    let status = Command::new(env!("CARGO_BIN_EXE_bingpapr"))
+        .arg("--daemonize")
+        .env("HOME", dir.path())
+        .env("XDG_CONFIG_HOME", dir.path().join("config"))
+        .env("XDG_STATE_HOME", &state_home)
+        .env("DBUS_SESSION_BUS_ADDRESS", format!("unix:path={}", dir.path().join("bus").display()))
+        .env("RUST_LOG", "warn")
+        .status()
+        .unwrap();
+    assert!(status.success(), "{}", status);
+
+    // only the daemon writes to the log, once the intermediate processes exited
+    let logged = wait_for(|| read_log(&log_path).filter(|log| log.contains("Failed to connect to the session bus")));
+    let processes = find_processes(&marker);
+    for pid in &processes {
+        // SAFETY: the processes were started by this test
+        unsafe { libc::kill(*pid, libc::SIGKILL) };
+    }
+
+    assert!(logged.is_some(), "{:?}", read_log(&log_path));
+    assert_eq!(processes.len(), 1, "{:?}", processes);
+}
@@ -1,14 +1,62 @@
 use std::time::Duration;
-use std::{env, error, io};
-use std::io::{ErrorKind, Read, Write};
+use std::{env, io};
+use std::io::{Read, Write};
 use std::net::Shutdown;
 use std::os::unix::net::UnixStream;
 use std::path::{Path, PathBuf};
-use log::{debug, error};
+use log::debug;
 use thiserror::Error;
 
 pub struct Hyprpaper {
     pub socket_path: PathBuf,
+    /// Image formats the running hyprpaper build is able to load.
+    pub supported_formats: Vec<ImageFormat>,
+    /// Whether to preload a wallpaper hyprpaper rejected for not being preloaded and apply it
+    /// again.
+    preload_missing: bool,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ImageFormat {
+    Jpeg,
+    Png,
+    WebP,
+}
+
+impl ImageFormat {
+    /// Determines the format of an image from its file extension.
+    pub fn from_path(path: &Path) -> Option<ImageFormat> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "jpg" | "jpeg" => Some(ImageFormat::Jpeg),
+            "png" => Some(ImageFormat::Png),
+            "webp" => Some(ImageFormat::WebP),
+            _ => None,
+        }
+    }
+}
+
+/// How a wallpaper is scaled to a monitor whose aspect ratio differs from the image's.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ScaleMode {
+    /// Fill the monitor, cropping the image where necessary.
+    #[default]
+    Cover,
+    /// Fit the whole image onto the monitor, leaving borders where necessary.
+    Contain,
+    /// Repeat the image at its original size.
+    Tile,
+}
+
+impl ScaleMode {
+    /// Prefix of the wallpaper's path selecting the mode in the `wallpaper` command.
+    fn prefix(self) -> &'static str {
+        match self {
+            ScaleMode::Cover => "",
+            ScaleMode::Contain => "contain:",
+            ScaleMode::Tile => "tile:",
+        }
+    }
 }
 
 pub type HyprpaperResult = Result<String, HyprpaperError>;
@@ -19,8 +67,14 @@ pub enum HyprpaperError {
     IOError(#[from] io::Error),
     #[error("unknown error from hyprpaper ipc")]
     Hyprpaper,
+    #[error("hyprpaper rejected the command: {0}")]
+    Command(String),
     #[error("image path contained invalid utf-8 characters")]
     InvalidPath,
+    #[error("image {0:?} is in a format hyprpaper is unable to load")]
+    UnsupportedFormat(PathBuf),
+    #[error("hyprpaper socket {0:?} does not exist, hyprpaper is likely not running; start it or switch backend")]
+    NotRunning(PathBuf),
 }
 
 fn path_to_string(path: &Path) -> HyprpaperResult {
@@ -32,66 +86,258 @@ fn path_to_string(path: &Path) -> HyprpaperResult {
     }
 }
 
+/// Location of hyprpaper's configuration file, `$XDG_CONFIG_HOME/hypr/hyprpaper.conf`.
+pub fn config_file() -> Option<PathBuf> {
+    let config_dir = match env::var_os("XDG_CONFIG_HOME") {
+        Some(config_dir) if !config_dir.is_empty() => PathBuf::from(config_dir),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(config_dir.join("hypr").join("hyprpaper.conf"))
+}
+
+/// Location of hyprpaper's IPC socket. Hyprland places it in `$XDG_RUNTIME_DIR/hypr` since
+/// version 0.40 and in `/tmp/hypr` before, so the first existing socket is used, preferring the
+/// runtime directory when neither exists yet.
+pub fn socket_file() -> Option<PathBuf> {
+    let relative = match env::var("HYPRLAND_INSTANCE_SIGNATURE") {
+        Err(_) => PathBuf::from(".hyprpaper.sock"),
+        Ok(sig) => Path::new(&sig).join(".hyprpaper.sock"),
+    };
+    let mut candidates = Vec::new();
+    if let Some(runtime_dir) = env::var_os("XDG_RUNTIME_DIR").filter(|dir| !dir.is_empty()) {
+        candidates.push(PathBuf::from(runtime_dir).join("hypr").join(&relative));
+    }
+    candidates.push(Path::new("/tmp/hypr").join(&relative));
+    candidates.iter()
+        .find(|candidate| candidate.exists())
+        .or(candidates.first())
+        .cloned()
+}
+
+/// Parses the monitor identifiers of the `wallpaper = monitor,path` lines in hyprpaper's
+/// configuration, in the order they appear. Wallpapers applying to every monitor are skipped.
+pub fn parse_config_monitors(contents: &str) -> Vec<String> {
+    let mut monitors = Vec::new();
+    for line in contents.lines() {
+        let line = line.split('#').next().unwrap_or_default();
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        if key.trim() != "wallpaper" {
+            continue;
+        }
+        let Some((monitor, _)) = value.split_once(',') else {
+            continue;
+        };
+        let monitor = monitor.trim();
+        if !monitor.is_empty() && !monitors.iter().any(|known| known == monitor) {
+            monitors.push(monitor.to_owned());
+        }
+    }
+    monitors
+}
+
+/// Reads the monitor identifiers from hyprpaper's configuration file, see
+/// [parse_config_monitors]. Returns `None` if the file is missing or unreadable.
+pub fn configured_monitors() -> Option<Vec<String>> {
+    let path = config_file()?;
+    match std::fs::read_to_string(&path) {
+        Ok(contents) => Some(parse_config_monitors(&contents)),
+        Err(err) => {
+            debug!("Could not read hyprpaper configuration {}: {}", path.display(), err);
+            None
+        }
+    }
+}
+
+/// Connects to hyprpaper's socket, retrying with `sleep` waiting out the backoff between attempts
+/// while hyprpaper is restarting. A missing socket fails right away, as hyprpaper is not running.
+fn connect_with_backoff(socket_path: &Path, mut sleep: impl FnMut(Duration)) -> Result<UnixStream, io::Error> {
+    const ATTEMPTS: u32 = 5;
+    let mut attempt = 1;
+    loop {
+        debug!("Connecting to socket: {:?} attempt #{}", socket_path, attempt);
+        let err = match UnixStream::connect(socket_path) {
+            Ok(socket) => return Ok(socket),
+            Err(err) => err,
+        };
+        let backoff = match err.kind() {
+            // the socket exists but nothing is listening, hyprpaper has likely crashed and takes
+            // a while to be restarted
+            io::ErrorKind::ConnectionRefused => {
+                debug!("hyprpaper is not accepting connections, it may be restarting");
+                Duration::from_millis(500) * attempt
+            }
+            // the socket does not exist, hyprpaper is not running
+            io::ErrorKind::NotFound => {
+                debug!("hyprpaper socket does not exist");
+                return Err(err);
+            }
+            _ => {
+                debug!("Error connecting: {:?}", err);
+                Duration::from_millis(200)
+            }
+        };
+        if attempt == ATTEMPTS {
+            return Err(err);
+        }
+        sleep(backoff);
+        attempt += 1;
+    }
+}
+
 impl Hyprpaper {
     pub fn new() -> Option<Hyprpaper> {
-        let path = Path::new("/tmp/hypr");
-        let socket_path = match env::var("HYPRLAND_INSTANCE_SIGNATURE") {
-            Err(_) => path.join(".hyprpaper.sock"),
-            Ok(sig) => path.join(sig).join(".hyprpaper.sock"),
-        };
-        Some(Hyprpaper { socket_path })
+        let socket_path = socket_file()?;
+        debug!("Using hyprpaper socket {}", socket_path.display());
+        Some(Hyprpaper::with_socket(socket_path))
     }
 
-    fn connect_to_socket(&self) -> Result<UnixStream, io::Error> {
-        const ATTEMPTS: u32 = 5;
-        for attempt in 1..=ATTEMPTS {
-            debug!("Connecting to socket: {:?} attempt #{}", self.socket_path, attempt);
-            match UnixStream::connect(&self.socket_path) {
-                Ok(socket) => return Ok(socket),
-                Err(err) => {
-                    debug!("Error connecting: {:?}", err);
-                    if attempt != ATTEMPTS {
-                        std::thread::sleep(Duration::from_millis(200));
-                    }
-                },
+    /// Talks to the hyprpaper instance listening on `socket_path` rather than the one of the
+    /// current Hyprland instance.
+    pub fn with_socket(socket_path: PathBuf) -> Hyprpaper {
+        Hyprpaper {
+            socket_path,
+            supported_formats: vec![ImageFormat::Jpeg, ImageFormat::Png, ImageFormat::WebP],
+            preload_missing: false,
+        }
+    }
+
+    /// When applying a wallpaper fails because hyprpaper hasn't preloaded it, e.g. after
+    /// hyprpaper was restarted, preload it and apply it once more instead of failing.
+    pub fn with_preload_missing(mut self, preload_missing: bool) -> Hyprpaper {
+        self.preload_missing = preload_missing;
+        self
+    }
+
+    /// Checks that the image is in a format hyprpaper is able to load. Images of unknown format
+    /// are left for hyprpaper to decide.
+    pub fn check_format(&self, path: &Path) -> Result<(), HyprpaperError> {
+        match ImageFormat::from_path(path) {
+            Some(format) if !self.supported_formats.contains(&format) => {
+                Err(HyprpaperError::UnsupportedFormat(path.to_path_buf()))
             }
+            _ => Ok(()),
         }
-        Err(io::Error::new(io::ErrorKind::NotFound, "Could not open hyprpaper socket"))
     }
 
+    /// Checks that hyprpaper's socket exists, to detect hyprpaper not running before any
+    /// command is sent.
+    pub fn probe(&self) -> Result<(), HyprpaperError> {
+        if self.socket_path.exists() {
+            Ok(())
+        } else {
+            Err(HyprpaperError::NotRunning(self.socket_path.clone()))
+        }
+    }
+
+    fn connect_to_socket(&self) -> Result<UnixStream, io::Error> {
+        connect_with_backoff(&self.socket_path, std::thread::sleep)
+    }
+
+    /// Sends a command replying with `ok` on success, or the reason it failed otherwise.
     fn send(&self, msg: &str) -> HyprpaperResult {
+        let response = self.request(msg)?;
+        match response.trim_end_matches('\0').trim() {
+            "ok" => Ok("ok".to_owned()),
+            "" => Err(HyprpaperError::Hyprpaper),
+            error => Err(HyprpaperError::Command(error.to_owned())),
+        }
+    }
+
+    /// Sends a command and reads its complete response, for commands replying with more than a
+    /// plain `ok`.
+    fn request(&self, msg: &str) -> HyprpaperResult {
         let mut socket = self.connect_to_socket()?;
 
-        debug!("Sending message: {}", msg);
-        socket.write(msg.as_bytes())?;
+        debug!("Sending request: {}", msg);
+        // every command is terminated the same way so hyprpaper parses all of them alike
+        let mut message = msg.as_bytes().to_vec();
+        message.push(b'\0');
+        socket.write_all(&message)?;
 
-        let mut buf = [0u8; 2];
-        let read = socket.read(&mut buf)?;
-        socket.shutdown(Shutdown::Both)?;
+        let mut response = String::new();
+        socket.read_to_string(&mut response)?;
+        let _ = socket.shutdown(Shutdown::Both);
+        Ok(response)
+    }
 
-        if read == 2 && buf[..2] == *b"ok" {
-            Ok("ok".to_owned())
-        } else {
-            Err(HyprpaperError::Hyprpaper)
-        }
+    /// Lists the paths of the currently preloaded wallpapers.
+    pub fn listloaded(&self) -> Result<Vec<PathBuf>, HyprpaperError> {
+        let response = self.request("listloaded")?;
+        debug!("hyprpaper listloaded output: {}", response);
+        // anything but absolute paths is a message, e.g. that no wallpapers are loaded
+        Ok(response.lines()
+            .map(str::trim)
+            .filter(|line| line.starts_with('/'))
+            .map(PathBuf::from)
+            .collect())
+    }
+
+    /// Whether hyprpaper reports the wallpaper as preloaded.
+    pub fn is_loaded(&self, path: &Path) -> bool {
+        self.listloaded().is_ok_and(|loaded| loaded.iter().any(|loaded| loaded == path))
     }
 
     pub fn preload(&self, path: &Path) -> HyprpaperResult {
         debug!("Preloading wallpaper: {}", path.display());
-        let command = format!("preload {}\0", path_to_string(path)?);
+        self.check_format(path)?;
+        let command = format!("preload {}", path_to_string(path)?);
         let output = self.send(&command)?;
         debug!("hyprpaper preload output: {}", output);
         Ok(output)
     }
 
     pub fn set_wallpaper(&self, monitor: &str, path: &Path) -> HyprpaperResult {
-        debug!("Applying wallpaper '{}' to monitor: {}", path.display(), monitor);
-        let command = format!("wallpaper {},{}", monitor, path_to_string(path)?);
-        let output = self.send(&command)?;
+        self.set_wallpaper_mode(monitor, path, ScaleMode::Cover)
+    }
+
+    /// Applies a wallpaper to the monitor, scaling it according to `mode`.
+    pub fn set_wallpaper_mode(&self, monitor: &str, path: &Path, mode: ScaleMode) -> HyprpaperResult {
+        debug!("Applying wallpaper '{}' to monitor: {} ({:?})", path.display(), monitor, mode);
+        let command = format!("wallpaper {},{}{}", monitor, mode.prefix(), path_to_string(path)?);
+        let output = match self.send(&command) {
+            Err(HyprpaperError::Hyprpaper | HyprpaperError::Command(_)) if self.preload_missing && !self.is_loaded(path) => {
+                debug!("Wallpaper '{}' is not preloaded, preloading it and retrying", path.display());
+                self.preload(path)?;
+                self.send(&command)?
+            }
+            result => result?,
+        };
         debug!("hyprpaper wallpaper output: {}", output);
         Ok(output)
     }
 
+    /// Applies a wallpaper to the monitor matching the given description, which unlike the
+    /// connector name stays the same across reboots.
+    pub fn set_wallpaper_by_description(&self, description: &str, path: &Path) -> HyprpaperResult {
+        self.set_wallpaper(&format!("desc:{}", description), path)
+    }
+
+    /// Unloads every preloaded wallpaper not currently shown on a monitor, including ones preloaded
+    /// by earlier runs. Succeeds if nothing is loaded.
+    pub fn unload_all(&self) -> HyprpaperResult {
+        if self.listloaded()?.is_empty() {
+            debug!("No wallpapers loaded, nothing to unload");
+            return Ok("ok".to_owned());
+        }
+        debug!("Unloading all wallpapers");
+        let output = self.send("unload all")?;
+        debug!("hyprpaper unload output: {}", output);
+        Ok(output)
+    }
+
+    /// Preloads and applies a wallpaper to the monitor in a single round trip, unloading the
+    /// monitor's previous wallpaper if it is no longer in use.
+    pub fn reload(&self, monitor: &str, path: &Path) -> HyprpaperResult {
+        debug!("Reloading wallpaper '{}' on monitor: {}", path.display(), monitor);
+        self.check_format(path)?;
+        let command = format!("reload {},{}", monitor, path_to_string(path)?);
+        let output = self.send(&command)?;
+        debug!("hyprpaper reload output: {}", output);
+        Ok(output)
+    }
+
     pub fn unload(&self, path: &Path) -> HyprpaperResult {
         debug!("Unloading wallpaper: {}", path.display());
         let command = format!("unload {}", path_to_string(path)?);
@@ -100,3 +346,195 @@ impl Hyprpaper {
         Ok(output)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::io::{BufRead, BufReader};
+    use std::os::unix::net::UnixListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use tempfile::TempDir;
+    use super::*;
+
+    /// Stand-in for hyprpaper's socket, answering each connection with the next queued response
+    /// and recording the commands it received.
+    struct MockSocket {
+        _dir: TempDir,
+        path: PathBuf,
+        commands: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl MockSocket {
+        fn start(responses: &[&str]) -> MockSocket {
+            let dir = TempDir::new().unwrap();
+            let path = dir.path().join(".hyprpaper.sock");
+            let listener = UnixListener::bind(&path).unwrap();
+            let commands = Arc::new(Mutex::new(Vec::new()));
+            let mut responses = responses.iter().map(|response| response.to_string()).collect::<VecDeque<_>>();
+            let received = commands.clone();
+            thread::spawn(move || {
+                while let Some(response) = responses.pop_front() {
+                    let Ok((mut stream, _)) = listener.accept() else {
+                        return;
+                    };
+                    let mut command = Vec::new();
+                    BufReader::new(&stream).read_until(b'\0', &mut command).unwrap();
+                    received.lock().unwrap().push(String::from_utf8(command).unwrap());
+                    stream.write_all(response.as_bytes()).unwrap();
+                }
+            });
+            MockSocket { _dir: dir, path, commands }
+        }
+
+        fn hyprpaper(&self) -> Hyprpaper {
+            Hyprpaper {
+                socket_path: self.path.clone(),
+                supported_formats: vec![ImageFormat::Jpeg, ImageFormat::Png, ImageFormat::WebP],
+                preload_missing: false,
+            }
+        }
+
+        fn commands(&self) -> Vec<String> {
+            self.commands.lock().unwrap().clone()
+        }
+    }
+
+    #[test]
+    fn parses_configured_monitors() {
+        for (contents, expected) in [
+            ("", vec![]),
+            ("preload = /pictures/a.jpg\nsplash = false", vec![]),
+            ("wallpaper = DP-1,/pictures/a.jpg", vec!["DP-1"]),
+            ("wallpaper=DP-1,/pictures/a.jpg\nwallpaper =  HDMI-A-1 , /pictures/b.jpg", vec!["DP-1", "HDMI-A-1"]),
+            ("wallpaper = HDMI-A-1,/pictures/b.jpg\nwallpaper = DP-1,/pictures/a.jpg", vec!["HDMI-A-1", "DP-1"]),
+            ("wallpaper = DP-1,/pictures/a.jpg\nwallpaper = DP-1,/pictures/b.jpg", vec!["DP-1"]),
+            ("wallpaper = ,/pictures/a.jpg\nwallpaper = DP-1,/pictures/b.jpg", vec!["DP-1"]),
+            ("wallpaper = desc:Dell Inc. DELL U2720Q,/pictures/a.jpg", vec!["desc:Dell Inc. DELL U2720Q"]),
+            ("wallpaper = DP-1,contain:/pictures/a.jpg", vec!["DP-1"]),
+            ("# wallpaper = DP-1,/pictures/a.jpg\nwallpaper = DP-2,/pictures/b.jpg # DP-3", vec!["DP-2"]),
+            ("wallpaper = DP-1\nwallpapers = DP-2,/pictures/a.jpg\nwallpaper DP-3,/pictures/b.jpg", vec![]),
+            ("\twallpaper = DP-1,/pictures/a.jpg\r\n", vec!["DP-1"]),
+        ] {
+            assert_eq!(parse_config_monitors(contents), expected, "{:?}", contents);
+        }
+    }
+
+    const PICTURE: &str = "/pictures/20230815-en-US Lighthouse.jpg";
+
+    #[test]
+    fn sends_commands() {
+        type Command = fn(&Hyprpaper, &Path) -> HyprpaperResult;
+        let commands: [(Command, &str); 9] = [
+            (|h, p| h.preload(p), "preload /pictures/20230815-en-US Lighthouse.jpg\0"),
+            (|h, p| h.unload(p), "unload /pictures/20230815-en-US Lighthouse.jpg\0"),
+            (|h, p| h.set_wallpaper("DP-1", p), "wallpaper DP-1,/pictures/20230815-en-US Lighthouse.jpg\0"),
+            (|h, p| h.set_wallpaper("", p), "wallpaper ,/pictures/20230815-en-US Lighthouse.jpg\0"),
+            (|h, p| h.set_wallpaper_mode("DP-1", p, ScaleMode::Cover), "wallpaper DP-1,/pictures/20230815-en-US Lighthouse.jpg\0"),
+            (|h, p| h.set_wallpaper_mode("DP-1", p, ScaleMode::Contain), "wallpaper DP-1,contain:/pictures/20230815-en-US Lighthouse.jpg\0"),
+            (|h, p| h.set_wallpaper_mode("DP-1", p, ScaleMode::Tile), "wallpaper DP-1,tile:/pictures/20230815-en-US Lighthouse.jpg\0"),
+            (|h, p| h.set_wallpaper_by_description("Dell Inc. DELL U2720Q 8LXMZ13", p), "wallpaper desc:Dell Inc. DELL U2720Q 8LXMZ13,/pictures/20230815-en-US Lighthouse.jpg\0"),
+            (|h, p| h.reload("HDMI-A-1", p), "reload HDMI-A-1,/pictures/20230815-en-US Lighthouse.jpg\0"),
+        ];
+        for (send, expected) in commands {
+            let socket = MockSocket::start(&["ok"]);
+            assert_eq!(send(&socket.hyprpaper(), Path::new(PICTURE)).unwrap(), "ok");
+            assert_eq!(socket.commands(), [expected]);
+        }
+    }
+
+    #[test]
+    fn reports_rejected_commands() {
+        let socket = MockSocket::start(&["wallpaper failed (not preloaded)\n"]);
+        let error = socket.hyprpaper().set_wallpaper("DP-1", Path::new(PICTURE)).unwrap_err();
+        assert!(matches!(error, HyprpaperError::Command(message) if message == "wallpaper failed (not preloaded)"));
+
+        let socket = MockSocket::start(&[""]);
+        let error = socket.hyprpaper().unload(Path::new(PICTURE)).unwrap_err();
+        assert!(matches!(error, HyprpaperError::Hyprpaper));
+    }
+
+    #[test]
+    fn preloads_missing_wallpapers_and_retries() {
+        let socket = MockSocket::start(&["wallpaper failed (not preloaded)", "no wallpapers loaded", "ok", "ok"]);
+        let hyprpaper = socket.hyprpaper().with_preload_missing(true);
+        assert_eq!(hyprpaper.set_wallpaper("DP-1", Path::new(PICTURE)).unwrap(), "ok");
+        assert_eq!(socket.commands(), [
+            format!("wallpaper DP-1,{}\0", PICTURE),
+            "listloaded\0".to_owned(),
+            format!("preload {}\0", PICTURE),
+            format!("wallpaper DP-1,{}\0", PICTURE),
+        ]);
+    }
+
+    #[test]
+    fn unloads_all_only_when_loaded() {
+        let socket = MockSocket::start(&["no wallpapers loaded"]);
+        assert_eq!(socket.hyprpaper().unload_all().unwrap(), "ok");
+        assert_eq!(socket.commands(), ["listloaded\0"]);
+
+        let socket = MockSocket::start(&[&format!("{}\n/pictures/bliss.jpg\n", PICTURE), "ok"]);
+        assert_eq!(socket.hyprpaper().unload_all().unwrap(), "ok");
+        assert_eq!(socket.commands(), ["listloaded\0", "unload all\0"]);
+    }
+
+    #[test]
+    fn probes_whether_hyprpaper_is_running() {
+        let socket = MockSocket::start(&[]);
+        assert!(socket.hyprpaper().probe().is_ok());
+
+        let dir = TempDir::new().unwrap();
+        let mut hyprpaper = socket.hyprpaper();
+        hyprpaper.socket_path = dir.path().join(".hyprpaper.sock");
+        let error = hyprpaper.probe().unwrap_err();
+        assert!(matches!(&error, HyprpaperError::NotRunning(path) if path == &hyprpaper.socket_path), "{:?}", error);
+        assert!(socket.commands().is_empty());
+    }
+
+    #[test]
+    fn retries_refused_connections_with_growing_backoff() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join(".hyprpaper.sock");
+        // binding and dropping a listener leaves a socket file nothing listens on
+        drop(UnixListener::bind(&path).unwrap());
+
+        let mut backoffs = Vec::new();
+        let mut listener = None;
+        let socket = connect_with_backoff(&path, |backoff| {
+            backoffs.push(backoff);
+            // hyprpaper comes back after the second refusal
+            if backoffs.len() == 2 {
+                std::fs::remove_file(&path).unwrap();
+                listener = Some(UnixListener::bind(&path).unwrap());
+            }
+        });
+        assert!(socket.is_ok(), "{:?}", socket);
+        assert_eq!(backoffs, [Duration::from_millis(500), Duration::from_millis(1000)]);
+
+        drop(listener);
+        let mut backoffs = Vec::new();
+        let error = connect_with_backoff(&path, |backoff| backoffs.push(backoff)).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionRefused);
+        assert_eq!(backoffs.len(), 4);
+    }
+
+    #[test]
+    fn missing_sockets_fail_without_retrying() {
+        let dir = TempDir::new().unwrap();
+        let mut backoffs = Vec::new();
+        let error = connect_with_backoff(&dir.path().join(".hyprpaper.sock"), |backoff| backoffs.push(backoff)).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        assert!(backoffs.is_empty());
+    }
+
+    #[test]
+    fn rejects_unsupported_formats_without_sending() {
+        let socket = MockSocket::start(&[]);
+        let mut hyprpaper = socket.hyprpaper();
+        hyprpaper.supported_formats = vec![ImageFormat::Jpeg, ImageFormat::Png];
+        let path = Path::new("/pictures/lighthouse.webp");
+        assert!(matches!(hyprpaper.preload(path), Err(HyprpaperError::UnsupportedFormat(_))));
+        assert!(matches!(hyprpaper.reload("DP-1", path), Err(HyprpaperError::UnsupportedFormat(_))));
+        assert!(socket.commands().is_empty());
+    }
+}